@@ -0,0 +1,144 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Serialize, Deserialize};
+
+use crate::mission::Position;
+
+const MAX_TEXT_LENGTH: usize = 200;
+const MAX_LINE_POINTS: usize = 256;
+const MAX_COLOR_LENGTH: usize = 32;
+
+/// Shapes are in world coordinates so they line up with units at any zoom level
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum AnnotationShape {
+    Arrow { from: Position, to: Position },
+    Circle { center: Position, radius: f32 },
+    Text { position: Position, text: String },
+    Line { points: Vec<Position> }
+}
+
+impl AnnotationShape {
+    fn positions(&self) -> Vec<&Position> {
+        match self {
+            AnnotationShape::Arrow { from, to } => vec![from, to],
+            AnnotationShape::Circle { center, .. } => vec![center],
+            AnnotationShape::Text { position, .. } => vec![position],
+            AnnotationShape::Line { points } => points.iter().collect()
+        }
+    }
+}
+
+/// An annotation as drawn by a viewer, before the lobby gives it an id and author
+#[derive(Deserialize, Clone, Debug)]
+pub struct AnnotationDraft {
+    #[serde(flatten)]
+    pub shape: AnnotationShape,
+    pub color: String,
+    /// Mission time the annotation appears at
+    pub start_time: f64,
+    /// Mission time the annotation disappears at, visible until the end when missing
+    pub end_time: Option<f64>
+}
+
+impl AnnotationDraft {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.color.is_empty() || self.color.len() > MAX_COLOR_LENGTH {
+            return Err("Invalid colour")
+        }
+        if !self.start_time.is_finite() || self.end_time.is_some_and(|end| !end.is_finite() || end < self.start_time) {
+            return Err("Invalid time range")
+        }
+        if self.shape.positions().iter().any(|p| !p.x.is_finite() || !p.y.is_finite() || !p.z.is_finite()) {
+            return Err("Invalid coordinates")
+        }
+
+        match &self.shape {
+            AnnotationShape::Circle { radius, .. } if !radius.is_finite() || *radius <= 0.0 => Err("Invalid radius"),
+            AnnotationShape::Text { text, .. } if text.trim().is_empty() || text.chars().count() > MAX_TEXT_LENGTH => Err("Invalid text"),
+            AnnotationShape::Line { points } if points.len() < 2 || points.len() > MAX_LINE_POINTS => Err("Invalid number of points"),
+            _ => Ok(())
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Annotation {
+    pub annotation_id: String,
+    pub author_id: String,
+    pub author_name: String,
+    #[serde(flatten)]
+    pub shape: AnnotationShape,
+    pub color: String,
+    pub start_time: f64,
+    pub end_time: Option<f64>
+}
+
+impl Annotation {
+    pub fn new(annotation_id: String, author_id: String, author_name: String, draft: AnnotationDraft) -> Annotation {
+        Annotation {
+            annotation_id,
+            author_id,
+            author_name,
+            shape: draft.shape,
+            color: draft.color,
+            start_time: draft.start_time,
+            end_time: draft.end_time
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draft(shape: serde_json::Value) -> AnnotationDraft {
+        let mut value = serde_json::json!({ "color": "#ff0000", "start_time": 10.0 });
+        value.as_object_mut().unwrap().extend(shape.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn drafts_are_checked_before_they_reach_a_lobby() {
+        let circle = draft(serde_json::json!({ "shape": "circle", "center": { "x": 1.0, "y": 2.0 }, "radius": 5.0 }));
+        assert!(circle.validate().is_ok());
+
+        let mut backwards = circle.clone();
+        backwards.end_time = Some(5.0);
+        assert_eq!(backwards.validate(), Err("Invalid time range"));
+
+        let mut colourless = circle.clone();
+        colourless.color = "x".repeat(MAX_COLOR_LENGTH + 1);
+        assert_eq!(colourless.validate(), Err("Invalid colour"));
+
+        let flat = draft(serde_json::json!({ "shape": "circle", "center": { "x": 1.0, "y": 2.0 }, "radius": 0.0 }));
+        assert_eq!(flat.validate(), Err("Invalid radius"));
+
+        let blank = draft(serde_json::json!({ "shape": "text", "position": { "x": 1.0, "y": 2.0 }, "text": "  " }));
+        assert_eq!(blank.validate(), Err("Invalid text"));
+
+        let dot = draft(serde_json::json!({ "shape": "line", "points": [{ "x": 1.0, "y": 2.0 }] }));
+        assert_eq!(dot.validate(), Err("Invalid number of points"));
+
+        let mut far = draft(serde_json::json!({ "shape": "arrow", "from": { "x": 0.0, "y": 0.0 }, "to": { "x": 1.0, "y": 1.0 } }));
+        if let AnnotationShape::Arrow { to, .. } = &mut far.shape {
+            to.x = f32::INFINITY;
+        }
+        assert_eq!(far.validate(), Err("Invalid coordinates"));
+    }
+}
@@ -0,0 +1,411 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Replays a finished recording into the live ingest endpoint as if a game server was sending
+//! it, optionally losing, reordering and disconnecting along the way.
+//!
+//! ingest_simulator <recording.json> <ingest url> [options]
+//!     --speed <n>             play back n times faster than the recording, 1 by default
+//!     --mission-id <id>       mission id to start, the recording's file name by default
+//!     --token <token>         sent as a bearer token, or put `token=` in the url instead
+//!     --drop <chance>         chance of a frame never being sent
+//!     --reorder <chance>      chance of a frame being sent after the one following it
+//!     --disconnect <seconds>  reconnect and resume after this long, repeatedly
+//!     --seed <n>              seed for the chances above
+#[path = "../mission.rs"]
+#[allow(dead_code)]
+mod mission;
+
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+use tungstenite::{client::IntoClientRequest, Message};
+
+use mission::{FrameNumber, Mission};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Shortest time between two frames, however fast the replay is asked to go
+const MIN_FRAME_TIME: Duration = Duration::from_micros(100);
+
+struct Options {
+    recording: String,
+    url: String,
+    speed: f64,
+    mission_id: Option<String>,
+    token: Option<String>,
+    drop_chance: f64,
+    reorder_chance: f64,
+    disconnect_every: Option<Duration>,
+    seed: u64
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, Error> {
+        let mut positional = Vec::new();
+        let mut options = Options {
+            recording: String::new(),
+            url: String::new(),
+            speed: 1.0,
+            mission_id: None,
+            token: None,
+            drop_chance: 0.0,
+            reorder_chance: 0.0,
+            disconnect_every: None,
+            seed: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_nanos() as u64
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--speed" => options.speed = value()?.parse()?,
+                "--mission-id" => options.mission_id = Some(value()?),
+                "--token" => options.token = Some(value()?),
+                "--drop" => options.drop_chance = value()?.parse()?,
+                "--reorder" => options.reorder_chance = value()?.parse()?,
+                "--disconnect" => options.disconnect_every = Some(Duration::try_from_secs_f64(value()?.parse()?)?),
+                "--seed" => options.seed = value()?.parse()?,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg).into()),
+                _ => positional.push(arg)
+            }
+        }
+
+        match <[String; 2]>::try_from(positional) {
+            Ok([recording, url]) => {
+                options.recording = recording;
+                options.url = url;
+            },
+            Err(_) => return Err("Usage: ingest_simulator <recording.json> <ingest url> [options]".into())
+        }
+        if !(options.speed > 0.0 && options.speed.is_finite()) {
+            return Err("Speed has to be positive".into())
+        }
+        if options.disconnect_every.is_some_and(|every| every.is_zero()) {
+            return Err("Disconnect interval has to be positive".into())
+        }
+        Ok(options)
+    }
+}
+
+/// xorshift, good enough to decide which frames to mess with
+struct Chance(u64);
+
+impl Chance {
+    fn roll(&mut self, chance: f64) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % 1_000_000) as f64 / 1_000_000.0 < chance
+    }
+}
+
+/// Turns a finished recording back into the messages a game server would have sent
+struct Replay {
+    mission: Mission,
+    mission_id: String,
+    declared_entities: HashSet<u32>,
+    declared_markers: HashSet<u32>,
+    declared_knowledge: usize
+}
+
+impl Replay {
+    fn start(&self) -> Value {
+        json!({
+            "type": "start",
+            "mission_id": self.mission_id,
+            "name": self.mission.name,
+            "world_name": self.mission.world_name,
+            "author": self.mission.author,
+            "frame_interval": self.mission.frame_interval
+        })
+    }
+
+    /// Entities, markers and knowledge that appear in `frame`, declared before its states
+    fn declarations(&mut self, frame: FrameNumber) -> Vec<Value> {
+        let mut messages = Vec::new();
+        for entity in self.mission.entities.iter().filter(|entity| entity.start_frame <= frame && entity.end_frame() > frame) {
+            if self.declared_entities.insert(entity.id) {
+                messages.push(json!({
+                    "type": "entity",
+                    "id": entity.id,
+                    "kind": entity.kind,
+                    "name": entity.name,
+                    "side": entity.side,
+                    "group": entity.group,
+                    "is_player": entity.is_player,
+                    "class_name": entity.class_name
+                }));
+            }
+        }
+
+        for marker in self.mission.markers.iter().filter(|marker| marker.start_frame <= frame) {
+            if self.declared_markers.insert(marker.id) {
+                // later positions are sent as moves when their frame comes around
+                let mut marker = marker.clone();
+                marker.positions.retain(|position| position.frame <= frame);
+                marker.end_frame = None;
+                messages.push(json!({ "type": "marker", "marker": marker }));
+            }
+        }
+
+        while let Some(knowledge) = self.mission.knowledge.get(self.declared_knowledge) {
+            if knowledge.start_frame > frame {
+                break;
+            }
+            messages.push(json!({ "type": "knowledge", "knowledge": knowledge }));
+            self.declared_knowledge += 1;
+        }
+        messages
+    }
+
+    fn frame(&self, frame: FrameNumber) -> Value {
+        let entities: Vec<Value> = self.mission.entities.iter()
+            .filter_map(|entity| {
+                let mut state = serde_json::to_value(entity.state_at(frame)?).ok()?;
+                state["id"] = json!(entity.id);
+                Some(state)
+            })
+            .collect();
+        let shots: Vec<Value> = self.mission.entities.iter()
+            .flat_map(|entity| entity.shots.iter()
+                .filter(move |shot| shot.frame == frame)
+                .map(move |shot| json!({ "entity": entity.id, "target": shot.target })))
+            .collect();
+        let markers: Vec<Value> = self.mission.markers.iter()
+            .flat_map(|marker| marker.positions.iter()
+                .filter(move |position| position.frame == frame && frame > marker.start_frame)
+                .map(move |position| json!({
+                    "id": marker.id,
+                    "position": position.position,
+                    "direction": position.direction,
+                    "alpha": position.alpha
+                })))
+            .collect();
+        let removed_markers: Vec<u32> = self.mission.markers.iter()
+            .filter(|marker| marker.end_frame == Some(frame))
+            .map(|marker| marker.id)
+            .collect();
+        let events: Vec<Value> = self.mission.events.iter()
+            .filter(|event| event.frame == frame)
+            .filter_map(|event| serde_json::to_value(&event.kind).ok())
+            .collect();
+
+        json!({
+            "type": "frame",
+            "frame": frame,
+            "entities": entities,
+            "shots": shots,
+            "markers": markers,
+            "removed_markers": removed_markers,
+            "events": events
+        })
+    }
+}
+
+async fn send(connection: &mut Connection, message: &Value) -> Result<(), Error> {
+    connection.send(Message::Text(message.to_string())).await?;
+    Ok(())
+}
+
+/// Connects and starts or resumes the mission, returning the frame the server wants next
+async fn connect(options: &Options, replay: &Replay) -> Result<(Connection, FrameNumber), Error> {
+    let mut request = options.url.as_str().into_client_request()?;
+    if let Some(token) = &options.token {
+        request.headers_mut().insert("Authorization", format!("Bearer {}", token).parse()?);
+    }
+    let (mut connection, _) = tokio_tungstenite::connect_async(request).await?;
+
+    send(&mut connection, &replay.start()).await?;
+    while let Some(message) = connection.next().await {
+        if let Message::Text(text) = message? {
+            let reply: Value = serde_json::from_str(&text)?;
+            match reply["type"].as_str() {
+                Some("started") => {
+                    println!("{}", text);
+                    let next_frame = reply["next_frame"].as_u64().unwrap_or(0) as FrameNumber;
+                    return Ok((connection, next_frame))
+                },
+                _ => return Err(format!("Could not start mission: {}", text).into())
+            }
+        }
+    }
+    Err("Connection closed before the mission started".into())
+}
+
+/// Prints anything the server rejected without waiting for it
+fn print_replies(connection: &mut Connection) {
+    while let Some(Some(Ok(Message::Text(text)))) = futures::FutureExt::now_or_never(connection.next()) {
+        println!("{}", text);
+    }
+}
+
+async fn run(options: Options) -> Result<(), Error> {
+    let mut mission: Mission = serde_json::from_slice(&std::fs::read(&options.recording)?)?;
+    mission.normalise();
+    mission.knowledge.sort_by_key(|knowledge| knowledge.start_frame);
+    let mission_id = options.mission_id.clone().unwrap_or_else(|| {
+        let stem = Path::new(&options.recording).file_stem().and_then(|stem| stem.to_str()).unwrap_or("recording");
+        format!("{}-live", stem)
+    });
+
+    let mut replay = Replay {
+        mission,
+        mission_id,
+        declared_entities: HashSet::new(),
+        declared_markers: HashSet::new(),
+        declared_knowledge: 0
+    };
+    let mut chance = Chance(options.seed.max(1));
+    let frame_time = Duration::try_from_secs_f64(replay.mission.frame_interval as f64 / options.speed)
+        .unwrap_or(MIN_FRAME_TIME)
+        .max(MIN_FRAME_TIME);
+
+    let (mut connection, mut frame) = connect(&options, &replay).await?;
+    let mut connected_at = Instant::now();
+    let mut held_back: Option<Value> = None;
+    let mut ticker = tokio::time::interval(frame_time);
+
+    while frame < replay.mission.frame_count {
+        ticker.tick().await;
+
+        if options.disconnect_every.is_some_and(|every| connected_at.elapsed() >= every) {
+            println!("Disconnecting at frame {}", frame);
+            connection.close(None).await?;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            let (new_connection, next_frame) = connect(&options, &replay).await?;
+            connection = new_connection;
+            connected_at = Instant::now();
+            frame = next_frame;
+            held_back = None;
+            continue;
+        }
+
+        for declaration in replay.declarations(frame) {
+            send(&mut connection, &declaration).await?;
+        }
+
+        let message = replay.frame(frame);
+        if chance.roll(options.drop_chance) {
+            println!("Dropping frame {}", frame);
+        } else if held_back.is_none() && chance.roll(options.reorder_chance) {
+            println!("Holding back frame {}", frame);
+            held_back = Some(message);
+        } else {
+            send(&mut connection, &message).await?;
+            if let Some(held_back) = held_back.take() {
+                send(&mut connection, &held_back).await?;
+            }
+        }
+
+        print_replies(&mut connection);
+        frame += 1;
+    }
+
+    if let Some(held_back) = held_back.take() {
+        send(&mut connection, &held_back).await?;
+    }
+    send(&mut connection, &json!({ "type": "end" })).await?;
+    while let Some(message) = connection.next().await {
+        match message? {
+            Message::Text(text) => println!("{}", text),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options).await {
+        eprintln!("Simulation failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, Error> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn options_need_a_recording_a_url_and_usable_values() {
+        let options = parse("op.json ws://[::1]:3000/ingest --speed 4 --drop 0.1 --seed 7").unwrap();
+        assert_eq!((options.recording.as_str(), options.speed, options.drop_chance, options.seed), ("op.json", 4.0, 0.1, 7));
+
+        assert!(parse("op.json").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --speed 0").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --speed NaN").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --disconnect 0").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --disconnect -1").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --seed").is_err());
+        assert!(parse("op.json ws://[::1]:3000/ingest --loss 0.1").is_err());
+    }
+
+    #[test]
+    fn entities_are_declared_once_before_their_first_state() {
+        let state = json!({ "position": { "x": 1.0, "y": 2.0 }, "direction": 0.0, "life": "alive" });
+        let mission: Mission = serde_json::from_value(json!({
+            "name": "Op",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 3,
+            "entities": [
+                { "id": 1, "kind": "unit", "name": "Early", "side": "blufor", "group": "Alpha", "start_frame": 0, "states": [state, state, state] },
+                { "id": 2, "kind": "unit", "name": "Late", "side": "opfor", "group": "Bravo", "start_frame": 2, "states": [state] }
+            ],
+            "events": [{ "frame": 2, "type": "disconnected", "name": "Early" }]
+        })).unwrap();
+        let mut replay = Replay {
+            mission,
+            mission_id: "op".to_string(),
+            declared_entities: HashSet::new(),
+            declared_markers: HashSet::new(),
+            declared_knowledge: 0
+        };
+
+        assert_eq!(replay.declarations(0).len(), 1);
+        assert!(replay.declarations(1).is_empty());
+        let declared = replay.declarations(2);
+        assert_eq!((declared.len(), &declared[0]["id"]), (1, &json!(2)));
+
+        let frame = replay.frame(2);
+        assert_eq!(frame["entities"].as_array().unwrap().len(), 2);
+        assert_eq!(frame["entities"][1]["id"], 2);
+        assert_eq!(frame["events"][0]["type"], "disconnected");
+        assert_eq!(replay.frame(1)["entities"].as_array().unwrap().len(), 1);
+    }
+}
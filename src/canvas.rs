@@ -0,0 +1,317 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fmt::Write;
+
+use tiny_skia::{Paint, PathBuilder, Pixmap, Rect, Stroke, Transform, FillRule};
+
+use crate::mission::Side;
+use crate::potato_types::Error;
+use crate::utils;
+
+/// Largest side of any image the server renders, in pixels
+pub const MAX_IMAGE_SIZE: u32 = 4096;
+
+/// A point in image space, in pixels from the top left
+pub type Point = (f32, f32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Colour {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8
+}
+
+impl Colour {
+    pub const fn new(red: u8, green: u8, blue: u8, alpha: u8) -> Colour {
+        Colour { red, green, blue, alpha }
+    }
+
+    pub const fn with_alpha(self, alpha: u8) -> Colour {
+        Colour { alpha, ..self }
+    }
+
+    pub fn side(side: Side) -> Colour {
+        match side {
+            Side::Blufor => Colour::new(0, 77, 153, 255),
+            Side::Opfor => Colour::new(128, 0, 0, 255),
+            Side::Independent => Colour::new(0, 128, 0, 255),
+            Side::Civilian => Colour::new(102, 0, 128, 255),
+            Side::Unknown => Colour::new(178, 153, 0, 255)
+        }
+    }
+
+    /// Understands the game's marker colour classes as well as `#rrggbb`
+    pub fn marker(name: &str) -> Colour {
+        let hex = name.strip_prefix('#').unwrap_or(name);
+        if hex.len() == 6 {
+            if let Ok(value) = u32::from_str_radix(hex, 16) {
+                return Colour::new((value >> 16) as u8, (value >> 8) as u8, value as u8, 255)
+            }
+        }
+
+        match name.to_ascii_lowercase().as_str() {
+            "colorred" => Colour::new(230, 0, 0, 255),
+            "colorgreen" => Colour::new(0, 204, 0, 255),
+            "colorblue" => Colour::new(0, 0, 255, 255),
+            "coloryellow" => Colour::new(217, 217, 0, 255),
+            "colororange" => Colour::new(217, 102, 0, 255),
+            "colorwhite" => Colour::new(255, 255, 255, 255),
+            "colorbrown" => Colour::new(128, 77, 26, 255),
+            "colorkhaki" => Colour::new(128, 153, 102, 255),
+            "colorpink" => Colour::new(255, 77, 102, 255),
+            "colorwest" | "colorblufor" => Colour::side(Side::Blufor),
+            "coloreast" | "coloropfor" => Colour::side(Side::Opfor),
+            "colorguer" | "colorindependent" => Colour::side(Side::Independent),
+            "colorciv" | "colorcivilian" => Colour::side(Side::Civilian),
+            "colorunknown" => Colour::side(Side::Unknown),
+            _ => Colour::new(0, 0, 0, 255)
+        }
+    }
+
+    fn to_svg(self) -> String {
+        format!("rgba({},{},{},{:.3})", self.red, self.green, self.blue, self.alpha as f32 / 255.0)
+    }
+
+    fn to_paint(self) -> Paint<'static> {
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(self.red, self.green, self.blue, self.alpha);
+        paint.anti_alias = true;
+        paint
+    }
+}
+
+/// Drawing operations map renderings are built from, so the same picture can come out as
+/// either an image or SVG. Angles are degrees clockwise, like headings in the game
+pub trait Canvas {
+    fn fill(&mut self, colour: Colour);
+    fn polyline(&mut self, points: &[Point], colour: Colour, width: f32);
+    fn circle(&mut self, centre: Point, radius: f32, fill: Colour, outline: Colour);
+    fn rectangle(&mut self, centre: Point, half_size: Point, angle: f32, fill: Colour, outline: Colour);
+    fn ellipse(&mut self, centre: Point, radii: Point, angle: f32, fill: Colour, outline: Colour);
+    /// Text with its top left corner at `position`, readable on any background
+    fn text(&mut self, position: Point, text: &str, colour: Colour);
+}
+
+pub struct SvgCanvas {
+    width: u32,
+    height: u32,
+    body: String
+}
+
+impl SvgCanvas {
+    pub fn new(width: u32, height: u32) -> SvgCanvas {
+        SvgCanvas {
+            width,
+            height,
+            body: String::new()
+        }
+    }
+
+    pub fn finish(self) -> String {
+        format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n{2}</svg>\n",
+            self.width, self.height, self.body)
+    }
+}
+
+impl Canvas for SvgCanvas {
+    fn fill(&mut self, colour: Colour) {
+        let _ = writeln!(self.body, "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>", colour.to_svg());
+    }
+
+    fn polyline(&mut self, points: &[Point], colour: Colour, width: f32) {
+        if points.len() < 2 {
+            return
+        }
+        let points: Vec<String> = points.iter().map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect();
+        let _ = writeln!(self.body, "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\" stroke-linejoin=\"round\"/>",
+            points.join(" "), colour.to_svg(), width);
+    }
+
+    fn circle(&mut self, (x, y): Point, radius: f32, fill: Colour, outline: Colour) {
+        let _ = writeln!(self.body, "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" fill=\"{}\" stroke=\"{}\"/>",
+            x, y, radius, fill.to_svg(), outline.to_svg());
+    }
+
+    fn rectangle(&mut self, (x, y): Point, (half_width, half_height): Point, angle: f32, fill: Colour, outline: Colour) {
+        let _ = writeln!(self.body, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" transform=\"translate({:.1} {:.1}) rotate({:.1})\" fill=\"{}\" stroke=\"{}\"/>",
+            -half_width, -half_height, half_width * 2.0, half_height * 2.0, x, y, angle, fill.to_svg(), outline.to_svg());
+    }
+
+    fn ellipse(&mut self, (x, y): Point, (radius_x, radius_y): Point, angle: f32, fill: Colour, outline: Colour) {
+        let _ = writeln!(self.body, "<ellipse rx=\"{:.1}\" ry=\"{:.1}\" transform=\"translate({:.1} {:.1}) rotate({:.1})\" fill=\"{}\" stroke=\"{}\"/>",
+            radius_x, radius_y, x, y, angle, fill.to_svg(), outline.to_svg());
+    }
+
+    fn text(&mut self, (x, y): Point, text: &str, colour: Colour) {
+        let _ = writeln!(self.body, "<text x=\"{:.1}\" y=\"{:.1}\" dominant-baseline=\"hanging\" font-family=\"sans-serif\" font-size=\"11\" fill=\"{}\" stroke=\"white\" stroke-width=\"3\" paint-order=\"stroke\">{}</text>",
+            x, y, colour.to_svg(), utils::escape_xml(text));
+    }
+}
+
+/// Rasterises on the CPU. Text uses a small built in bitmap font, so it is upper case only
+pub struct RasterCanvas {
+    pixmap: Pixmap
+}
+
+impl RasterCanvas {
+    pub fn new(width: u32, height: u32) -> Result<RasterCanvas, Error> {
+        Ok(RasterCanvas {
+            pixmap: Pixmap::new(width, height).ok_or("Image size is invalid")?
+        })
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.pixmap.encode_png()?)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.pixmap.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.pixmap.height()
+    }
+
+    /// RGBA rows, premultiplied, which only matters for images that aren't opaque
+    pub fn pixels(&self) -> &[u8] {
+        self.pixmap.data()
+    }
+
+    fn shape(&mut self, path: Option<tiny_skia::Path>, transform: Transform, fill: Colour, outline: Colour) {
+        let path = match path {
+            Some(path) => path,
+            None => return
+        };
+        self.pixmap.fill_path(&path, &fill.to_paint(), FillRule::Winding, transform, None);
+        let stroke = Stroke { width: 1.0, ..Stroke::default() };
+        self.pixmap.stroke_path(&path, &outline.to_paint(), &stroke, transform, None);
+    }
+}
+
+impl Canvas for RasterCanvas {
+    fn fill(&mut self, colour: Colour) {
+        self.pixmap.fill(tiny_skia::Color::from_rgba8(colour.red, colour.green, colour.blue, colour.alpha));
+    }
+
+    fn polyline(&mut self, points: &[Point], colour: Colour, width: f32) {
+        let mut builder = PathBuilder::new();
+        let mut points = points.iter();
+        if let Some((x, y)) = points.next() {
+            builder.move_to(*x, *y);
+        }
+        points.for_each(|(x, y)| builder.line_to(*x, *y));
+
+        if let Some(path) = builder.finish() {
+            let stroke = Stroke { width, line_join: tiny_skia::LineJoin::Round, ..Stroke::default() };
+            self.pixmap.stroke_path(&path, &colour.to_paint(), &stroke, Transform::identity(), None);
+        }
+    }
+
+    fn circle(&mut self, (x, y): Point, radius: f32, fill: Colour, outline: Colour) {
+        self.shape(PathBuilder::from_circle(x, y, radius), Transform::identity(), fill, outline);
+    }
+
+    fn rectangle(&mut self, (x, y): Point, (half_width, half_height): Point, angle: f32, fill: Colour, outline: Colour) {
+        let path = Rect::from_ltrb(-half_width, -half_height, half_width, half_height).map(PathBuilder::from_rect);
+        self.shape(path, Transform::from_translate(x, y).pre_rotate(angle), fill, outline);
+    }
+
+    fn ellipse(&mut self, (x, y): Point, (radius_x, radius_y): Point, angle: f32, fill: Colour, outline: Colour) {
+        let path = Rect::from_ltrb(-radius_x, -radius_y, radius_x, radius_y).and_then(PathBuilder::from_oval);
+        self.shape(path, Transform::from_translate(x, y).pre_rotate(angle), fill, outline);
+    }
+
+    fn text(&mut self, (x, y): Point, text: &str, colour: Colour) {
+        let (x, y) = (x.round(), y.round());
+        let length = text.chars().count() as f32;
+        if let Some(background) = Rect::from_xywh(x - 1.0, y - 1.0, length * 6.0 + 1.0, 9.0) {
+            self.pixmap.fill_rect(background, &Colour::new(255, 255, 255, 170).to_paint(), Transform::identity(), None);
+        }
+
+        let paint = colour.to_paint();
+        for (index, character) in text.chars().enumerate() {
+            let left = x + index as f32 * 6.0;
+            for (row, bits) in glyph(character).iter().enumerate() {
+                for column in 0..5 {
+                    if bits & (0x10 >> column) == 0 {
+                        continue
+                    }
+                    if let Some(pixel) = Rect::from_xywh(left + column as f32, y + row as f32, 1.0, 1.0) {
+                        self.pixmap.fill_rect(pixel, &paint, Transform::identity(), None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a 5x7 glyph, leftmost pixel in the highest of the five bits
+fn glyph(character: char) -> [u8; 7] {
+    match character.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '\'' => [0x0C, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04]
+    }
+}
@@ -0,0 +1,97 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::time::Instant;
+
+use crate::config::ChatConfig;
+
+/// Token bucket limiting how fast a single viewer can post chat messages
+pub struct ChatRateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl ChatRateLimiter {
+    pub fn new(config: &ChatConfig) -> ChatRateLimiter {
+        ChatRateLimiter {
+            capacity: config.burst as f64,
+            refill_per_second: config.messages_per_minute as f64 / 60.0,
+            tokens: config.burst as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        let refilled = self.last_refill.elapsed().as_secs_f64() * self.refill_per_second;
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.last_refill = Instant::now();
+
+        if self.tokens < 1.0 {
+            return false
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Strips control characters and checks the message length, returning why it was refused
+pub fn clean_message(text: &str, max_length: usize) -> Result<String, &'static str> {
+    let text: String = text.chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .collect();
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Err("Message is empty")
+    }
+    if text.chars().count() > max_length {
+        return Err("Message is too long")
+    }
+    Ok(text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_bursts_and_refills_over_time() {
+        let mut limiter = ChatRateLimiter::new(&ChatConfig { burst: 3, messages_per_minute: 60, ..ChatConfig::default() });
+        assert!((0..3).all(|_| limiter.try_acquire()));
+        assert!(!limiter.try_acquire());
+
+        // a second later one more message is allowed, but the burst is not back yet
+        limiter.last_refill -= Duration::from_secs(1);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // waiting longer never saves up more than the burst
+        limiter.last_refill -= Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| limiter.try_acquire()).count(), 3);
+    }
+
+    #[test]
+    fn cleans_messages() {
+        assert_eq!(clean_message("  hello\u{7}\n world \t", 20), Ok("hello\n world".to_string()));
+        assert_eq!(clean_message(" \u{1b} ", 20), Err("Message is empty"));
+        assert_eq!(clean_message("ééééé", 5), Ok("ééééé".to_string()));
+        assert_eq!(clean_message("ééééé!", 5), Err("Message is too long"));
+    }
+}
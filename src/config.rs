@@ -0,0 +1,177 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+    collections::HashMap
+};
+use serde::{Serialize, Deserialize};
+
+use log::info;
+
+use crate::potato_types::Error;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// Seconds between pings sent to each viewer
+    pub ping_interval: u64,
+    /// Seconds without hearing anything from a viewer before it is disconnected
+    pub ping_timeout: u64,
+    /// Seconds a disconnected viewer can come back with its session token and resume
+    pub resume_window: u64,
+    pub max_viewers: usize,
+    /// Accept failed upgrade requests and close them with a close code and reason instead of
+    /// answering with a HTTP error, which browsers do not expose to scripts
+    pub close_frame_errors: bool
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> WebSocketConfig {
+        WebSocketConfig {
+            ping_interval: 15,
+            ping_timeout: 45,
+            resume_window: 120,
+            max_viewers: 64,
+            close_frame_errors: false
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval.max(1))
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout.max(1))
+    }
+
+    pub fn resume_window(&self) -> Duration {
+        Duration::from_secs(self.resume_window)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// Longest message in characters
+    pub max_length: usize,
+    /// Messages replayed to viewers joining a lobby
+    pub history_size: usize,
+    /// Messages a viewer can send in quick succession before being rate limited
+    pub burst: u32,
+    pub messages_per_minute: u32
+}
+
+impl Default for ChatConfig {
+    fn default() -> ChatConfig {
+        ChatConfig {
+            max_length: 500,
+            history_size: 50,
+            burst: 5,
+            messages_per_minute: 20
+        }
+    }
+}
+
+/// A game server allowed to push live missions
+#[derive(Deserialize, Debug, Clone)]
+pub struct IngestSource {
+    /// Sent by the source as a bearer token, or the `token` query parameter
+    pub token: String,
+    /// Seconds everything from this source is held back before viewers can see it, so players
+    /// cannot watch their own mission live. Lifted once the mission ends
+    #[serde(default)]
+    pub delay: u64
+}
+
+impl IngestSource {
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IngestConfig {
+    /// Known sources by name
+    pub sources: HashMap<String, IngestSource>,
+    /// Seconds a live mission waits for its source to reconnect before it is ended and saved
+    pub abandon_after: u64
+}
+
+impl Default for IngestConfig {
+    fn default() -> IngestConfig {
+        IngestConfig {
+            sources: HashMap::new(),
+            abandon_after: 600
+        }
+    }
+}
+
+impl IngestConfig {
+    pub fn abandon_after(&self) -> Duration {
+        Duration::from_secs(self.abandon_after)
+    }
+}
+
+/// Where a terrain's world coordinates sit on the globe, for exporting to GIS tools. World
+/// coordinates are metres east and north of the terrain's south west corner
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct GeoReference {
+    /// Latitude and longitude of the terrain's origin, in degrees
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Degrees grid north is turned clockwise from true north
+    #[serde(default)]
+    pub rotation: f64
+}
+
+/// Server settings read from a JSON file. Every field is optional and falls back to its default
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the server listens on, `[::1]:3000` when missing
+    pub listen: Option<SocketAddr>,
+    pub websocket: WebSocketConfig,
+    pub chat: ChatConfig,
+    pub ingest: IngestConfig,
+    /// Geographic placement of terrains by world name, over what the terrain registry says
+    pub geo_references: HashMap<String, GeoReference>
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<ServerConfig, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            info!(target: "potato_plant_replay", "No config at {:?}, using defaults", path);
+            return Ok(ServerConfig::default())
+        }
+
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn geo_reference(&self, world_name: &str) -> Option<GeoReference> {
+        self.geo_references.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(world_name))
+            .map(|(_, reference)| *reference)
+    }
+}
@@ -0,0 +1,173 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    fs,
+    io,
+    path::PathBuf,
+    sync::RwLock,
+    collections::HashMap
+};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::potato_types::Error;
+use crate::mission::EntityId;
+
+const CODE_LENGTH: usize = 8;
+/// Lobbies for deep links are named with this, other lobbies cannot use it
+pub const LOBBY_PREFIX: &str = "link-";
+const CODE_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Camera {
+    pub x: f32,
+    pub y: f32,
+    pub zoom: f32
+}
+
+/// Everything needed to put someone in front of the same moment of a mission
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeepLink {
+    pub mission_id: String,
+    pub mission_time: f64,
+    pub camera: Camera,
+    pub follow_unit: Option<EntityId>
+}
+
+impl DeepLink {
+    /// Lobby everyone opening this link ends up in
+    pub fn lobby_id(code: &str) -> String {
+        format!("{}{}", LOBBY_PREFIX, code)
+    }
+
+    /// Viewer page for the link's lobby. Viewers that connect with the `link` parameter start
+    /// detached at the link's time, with its camera and following its unit
+    pub fn viewer_url(code: &str, lobby_uuid: &Uuid) -> String {
+        format!("/?lobby-id={}&link={}", lobby_uuid, code)
+    }
+}
+
+/// Short codes for deep links, persisted so shared links survive restarts
+pub struct LinkStore {
+    path: PathBuf,
+    links: RwLock<HashMap<String, DeepLink>>
+}
+
+impl LinkStore {
+    /// Starts empty only when there is no file yet. A file that cannot be read stops startup,
+    /// the next link created would otherwise replace every link in it
+    pub fn new(path: impl Into<PathBuf>) -> Result<LinkStore, Error> {
+        let path = path.into();
+        let links = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| format!("Cannot parse links in {:?}: {}", path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("Cannot read links from {:?}: {}", path, e).into())
+        };
+        Ok(LinkStore {
+            path,
+            links: RwLock::new(links)
+        })
+    }
+
+    fn generate_code() -> String {
+        // anything at or above the last whole multiple of the alphabet would favour its start
+        let limit = 256 - 256 % CODE_ALPHABET.len();
+        let mut code = String::with_capacity(CODE_LENGTH);
+        while code.len() < CODE_LENGTH {
+            // bytes 6 and 8 hold the UUID version and variant, the others are random
+            let bytes = *Uuid::new_v4().as_bytes();
+            let random = bytes.iter().enumerate()
+                .filter(|(i, byte)| *i != 6 && *i != 8 && (**byte as usize) < limit)
+                .map(|(_, byte)| CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char);
+            code.extend(random.take(CODE_LENGTH - code.len()));
+        }
+        code
+    }
+
+    pub fn create(&self, link: DeepLink) -> Result<String, Error> {
+        let mut links = self.links.write().unwrap();
+        let mut code = LinkStore::generate_code();
+        while links.contains_key(&code) {
+            code = LinkStore::generate_code();
+        }
+        links.insert(code.clone(), link);
+
+        if let Err(e) = self.save(&links) {
+            links.remove(&code);
+            return Err(e)
+        }
+        Ok(code)
+    }
+
+    /// Writes next to the file and renames over it, so a failed write leaves the old links intact
+    fn save(&self, links: &HashMap<String, DeepLink>) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(links)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, code: &str) -> Option<DeepLink> {
+        self.links.read().unwrap().get(code).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link() -> DeepLink {
+        DeepLink {
+            mission_id: "op".to_string(),
+            mission_time: 42.0,
+            camera: Camera { x: 100.0, y: 200.0, zoom: 2.0 },
+            follow_unit: Some(3)
+        }
+    }
+
+    #[test]
+    fn codes_only_use_the_alphabet() {
+        for _ in 0..100 {
+            let code = LinkStore::generate_code();
+            assert_eq!(code.len(), CODE_LENGTH);
+            assert!(code.bytes().all(|byte| CODE_ALPHABET.contains(&byte)));
+        }
+    }
+
+    #[test]
+    fn links_survive_restarts_and_broken_files_stop_startup() {
+        let root = std::env::temp_dir().join(format!("potato-links-{}", std::process::id()));
+        let path = root.join("links.json");
+
+        let store = LinkStore::new(&path).unwrap();
+        let code = store.create(link()).unwrap();
+        let reloaded = LinkStore::new(&path).unwrap();
+        assert_eq!(reloaded.get(&code).unwrap().mission_time, 42.0);
+        assert!(reloaded.get("missing").is_none());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        fs::write(&path, b"{ not json").unwrap();
+        assert!(LinkStore::new(&path).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
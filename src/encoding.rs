@@ -0,0 +1,113 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Serialize, de::DeserializeOwned};
+use hyper_tungstenite::tungstenite::Message;
+
+use crate::potato_types::Error;
+
+/// How messages on a viewer websocket are encoded. Chosen with the `encoding` query parameter
+/// when connecting; JSON text stays the default since it is readable in browser dev tools
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireEncoding {
+    Json,
+    MessagePack,
+    Cbor
+}
+
+impl WireEncoding {
+    pub fn from_query(value: &str) -> Option<WireEncoding> {
+        match value.to_ascii_lowercase().as_str() {
+            "" | "json" => Some(WireEncoding::Json),
+            "msgpack" | "messagepack" => Some(WireEncoding::MessagePack),
+            "cbor" => Some(WireEncoding::Cbor),
+            _ => None
+        }
+    }
+
+    pub fn encode<T>(&self, message: &T) -> Result<Message, Error>
+            where T: Serialize
+    {
+        match self {
+            WireEncoding::Json => Ok(Message::text(serde_json::to_string(message)?)),
+            // named so internally tagged enums keep their field names
+            WireEncoding::MessagePack => Ok(Message::binary(rmp_serde::to_vec_named(message)?)),
+            WireEncoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(message, &mut bytes)?;
+                Ok(Message::binary(bytes))
+            }
+        }
+    }
+
+    /// Viewers may send text JSON at any time, binary messages use the negotiated encoding
+    pub fn decode<T>(&self, message: &Message) -> Result<Option<T>, Error>
+            where T: DeserializeOwned
+    {
+        match message {
+            Message::Text(text) => Ok(Some(serde_json::from_str(text)?)),
+            Message::Binary(bytes) => match self {
+                WireEncoding::Json => Ok(Some(serde_json::from_slice(bytes)?)),
+                WireEncoding::MessagePack => Ok(Some(rmp_serde::from_slice(bytes)?)),
+                WireEncoding::Cbor => Ok(Some(ciborium::de::from_reader(&bytes[..])?))
+            },
+            _ => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Command {
+        Seek { mission_time: f64 },
+        Pause
+    }
+
+    #[test]
+    fn reads_encoding_names() {
+        assert_eq!(WireEncoding::from_query(""), Some(WireEncoding::Json));
+        assert_eq!(WireEncoding::from_query("MsgPack"), Some(WireEncoding::MessagePack));
+        assert_eq!(WireEncoding::from_query("cbor"), Some(WireEncoding::Cbor));
+        assert_eq!(WireEncoding::from_query("xml"), None);
+    }
+
+    #[test]
+    fn every_encoding_reads_back_what_it_writes() {
+        for encoding in [WireEncoding::Json, WireEncoding::MessagePack, WireEncoding::Cbor] {
+            for command in [Command::Seek { mission_time: 12.5 }, Command::Pause] {
+                let message = encoding.encode(&command).unwrap();
+                assert_eq!(message.is_binary(), encoding != WireEncoding::Json);
+                assert_eq!(encoding.decode::<Command>(&message).unwrap(), Some(command));
+            }
+        }
+    }
+
+    #[test]
+    fn binary_encodings_still_accept_json_text() {
+        let message = Message::text(r#"{"type":"seek","mission_time":3.0}"#);
+        for encoding in [WireEncoding::MessagePack, WireEncoding::Cbor] {
+            assert_eq!(encoding.decode::<Command>(&message).unwrap(), Some(Command::Seek { mission_time: 3.0 }));
+        }
+        assert_eq!(WireEncoding::Cbor.decode::<Command>(&Message::Ping(Vec::new())).unwrap(), None);
+        assert!(WireEncoding::Cbor.decode::<Command>(&Message::binary(vec![0xff, 0x00])).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::config::GeoReference;
+use crate::mission::{Entity, EntityId, EventKind, FrameNumber, LifeState, Mission, Position, Side};
+use crate::terrain::{GridPrecision, GridSystem};
+use crate::potato_types::Error;
+use crate::utils::escape_xml;
+
+/// Metres in a degree of latitude, and of longitude at the equator
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+impl GeoReference {
+    /// Longitude and latitude of a world position. Terrains are small enough that treating the
+    /// earth as flat around them is accurate to a few metres
+    pub fn to_geo(self, position: &Position) -> (f64, f64) {
+        let rotation = self.rotation.to_radians();
+        let (x, y) = (position.x as f64, position.y as f64);
+        let east = x * rotation.cos() + y * rotation.sin();
+        let north = -x * rotation.sin() + y * rotation.cos();
+
+        let latitude = self.latitude + north / METRES_PER_DEGREE;
+        let longitude = self.longitude + east / (METRES_PER_DEGREE * self.latitude.to_radians().cos().max(f64::EPSILON));
+        (longitude, latitude)
+    }
+}
+
+/// Which entities and frames an export covers
+#[derive(Clone, Debug)]
+pub struct ExportFilter {
+    /// Only these entities, or all of them when empty
+    pub units: Vec<EntityId>,
+    pub side: Option<Side>,
+    pub start_frame: FrameNumber,
+    pub end_frame: FrameNumber
+}
+
+/// Where exported positions end up: on the globe, and on the terrain's map grid
+#[derive(Clone, Copy, Debug)]
+pub struct ExportPlacement {
+    pub geo: GeoReference,
+    /// The terrain's grid, when the terrain is known
+    pub grid: Option<GridSystem>,
+    pub precision: GridPrecision,
+    /// Unix time mission time counts from, as recordings don't store the real date and time
+    pub epoch: f64
+}
+
+impl ExportFilter {
+    fn includes(&self, entity: &Entity) -> bool {
+        (self.units.is_empty() || self.units.contains(&entity.id))
+            && self.side.is_none_or(|side| entity.side == side)
+    }
+
+    fn frames(&self, entity: &Entity) -> std::ops::Range<FrameNumber> {
+        self.start_frame.max(entity.start_frame)..entity.end_frame().min(self.end_frame.saturating_add(1))
+    }
+}
+
+/// Writes mission data in formats other tools read
+pub struct Export<'a> {
+    mission: &'a Mission,
+    filter: ExportFilter,
+    placement: ExportPlacement
+}
+
+impl<'a> Export<'a> {
+    pub fn new(mission: &'a Mission, filter: ExportFilter, placement: ExportPlacement) -> Export<'a> {
+        Export {
+            mission,
+            filter,
+            placement
+        }
+    }
+
+    fn entities(&self) -> impl Iterator<Item = &'a Entity> + '_ {
+        self.mission.entities.iter().filter(|entity| self.filter.includes(entity))
+    }
+
+    fn coordinates(&self, position: &Position) -> Value {
+        let (longitude, latitude) = self.placement.geo.to_geo(position);
+        json!([longitude, latitude, position.z])
+    }
+
+    fn timestamp(&self, frame: FrameNumber) -> String {
+        format_timestamp(self.placement.epoch + self.mission.time_of_frame(frame))
+    }
+
+    fn grid(&self, position: &Position) -> Option<String> {
+        self.placement.grid.map(|grid| grid.reference(position, self.placement.precision))
+    }
+
+    /// A LineString per entity, with the time of every point
+    pub fn tracks_geojson(&self) -> Value {
+        let features: Vec<Value> = self.entities()
+            .filter_map(|entity| {
+                let frames: Vec<FrameNumber> = self.filter.frames(entity).collect();
+                if frames.len() < 2 {
+                    return None
+                }
+                let coordinates: Vec<Value> = frames.iter()
+                    .filter_map(|frame| entity.state_at(*frame))
+                    .map(|state| self.coordinates(&state.position))
+                    .collect();
+                let times: Vec<String> = frames.iter().map(|frame| self.timestamp(*frame)).collect();
+
+                Some(json!({
+                    "type": "Feature",
+                    "geometry": { "type": "LineString", "coordinates": coordinates },
+                    "properties": {
+                        "id": entity.id,
+                        "name": entity.name,
+                        "kind": entity.kind,
+                        "side": entity.side,
+                        "group": entity.group,
+                        "start_time": self.mission.time_of_frame(frames[0]),
+                        "end_time": self.mission.time_of_frame(frames[frames.len() - 1]),
+                        "times": times
+                    }
+                }))
+            })
+            .collect();
+
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    /// A Point per event that happened somewhere, placed where its victim was
+    pub fn events_geojson(&self) -> Value {
+        let features: Vec<Value> = self.mission.events.iter()
+            .filter(|event| (self.filter.start_frame..=self.filter.end_frame).contains(&event.frame))
+            .filter_map(|event| {
+                let (victim, attacker) = match &event.kind {
+                    EventKind::Killed { victim, killer, .. } => (*victim, *killer),
+                    EventKind::Hit { victim, shooter, .. } => (*victim, *shooter),
+                    _ => return None
+                };
+                let involved = [Some(victim), attacker].into_iter().flatten()
+                    .filter_map(|id| self.mission.entity(id))
+                    .any(|entity| self.filter.includes(entity));
+                if !involved {
+                    return None
+                }
+
+                let victim = self.mission.entity(victim)?;
+                let state = victim.state_at(event.frame).or(victim.states.last())?;
+                let mut properties = serde_json::to_value(&event.kind).ok()?;
+                properties["frame"] = json!(event.frame);
+                properties["mission_time"] = json!(self.mission.time_of_frame(event.frame));
+                properties["time"] = json!(self.timestamp(event.frame));
+                if let Some(grid) = self.grid(&state.position) {
+                    properties["grid"] = json!(grid);
+                }
+
+                Some(json!({
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": self.coordinates(&state.position) },
+                    "properties": properties
+                }))
+            })
+            .collect();
+
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    /// Tracks as KML `gx:Track`s, which Google Earth and QGIS can play back over time
+    pub fn tracks_kml(&self) -> String {
+        let mut kml = String::new();
+        let _ = writeln!(kml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        let _ = writeln!(kml, "<kml xmlns=\"http://www.opengis.net/kml/2.2\" xmlns:gx=\"http://www.google.com/kml/ext/2.2\">");
+        let _ = writeln!(kml, "<Document>\n<name>{}</name>", escape_xml(&self.mission.name));
+
+        for entity in self.entities() {
+            let states: Vec<(FrameNumber, Position)> = self.filter.frames(entity)
+                .filter_map(|frame| Some((frame, entity.state_at(frame)?.position)))
+                .collect();
+            if states.len() < 2 {
+                continue
+            }
+
+            let _ = writeln!(kml, "<Placemark>\n<name>{}</name>", escape_xml(&entity.name));
+            let _ = writeln!(kml, "<description>{} {}</description>", entity.side.name(), escape_xml(&entity.group));
+            let _ = writeln!(kml, "<gx:Track>\n<altitudeMode>relativeToGround</altitudeMode>");
+            for (frame, _) in &states {
+                let _ = writeln!(kml, "<when>{}</when>", self.timestamp(*frame));
+            }
+            for (_, position) in &states {
+                let (longitude, latitude) = self.placement.geo.to_geo(position);
+                let _ = writeln!(kml, "<gx:coord>{} {} {}</gx:coord>", longitude, latitude, position.z);
+            }
+            let _ = writeln!(kml, "</gx:Track>\n</Placemark>");
+        }
+
+        let _ = writeln!(kml, "</Document>\n</kml>");
+        kml
+    }
+
+    /// Every position of every included entity, one row per entity per frame
+    pub fn positions_csv(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let mut header = vec![
+            "frame", "mission_time", "id", "name", "side", "group", "x", "y", "z", "longitude", "latitude"
+        ];
+        if self.placement.grid.is_some() {
+            header.push("grid");
+        }
+        header.extend(["direction", "alive", "vehicle"]);
+        writer.write_record(&header)?;
+
+        for entity in self.entities() {
+            for frame in self.filter.frames(entity) {
+                let state = match entity.state_at(frame) {
+                    Some(state) => state,
+                    None => continue
+                };
+                let (longitude, latitude) = self.placement.geo.to_geo(&state.position);
+                let mut record = vec![
+                    frame.to_string(),
+                    self.mission.time_of_frame(frame).to_string(),
+                    entity.id.to_string(),
+                    entity.name.clone(),
+                    entity.side.name().to_string(),
+                    entity.group.clone(),
+                    state.position.x.to_string(),
+                    state.position.y.to_string(),
+                    state.position.z.to_string(),
+                    format!("{:.7}", longitude),
+                    format!("{:.7}", latitude)
+                ];
+                record.extend(self.grid(&state.position));
+                record.extend([
+                    state.direction.to_string(),
+                    (state.life != LifeState::Dead).to_string(),
+                    state.vehicle.map(|vehicle| vehicle.to_string()).unwrap_or_default()
+                ]);
+                writer.write_record(&record)?;
+            }
+        }
+
+        Ok(writer.into_inner()?)
+    }
+}
+
+/// RFC 3339 UTC time of a Unix timestamp
+pub fn format_timestamp(unix_seconds: f64) -> String {
+    let millis = (unix_seconds * 1000.0).round() as i64;
+    let (days, millis_of_day) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+
+    // days to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds = millis_of_day / 1000;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60, millis_of_day % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mission() -> Mission {
+        let state = |x: f32, life: &str| json!({
+            "position": { "x": x, "y": 0.0, "z": 1.0 }, "direction": 90.0, "life": life
+        });
+        serde_json::from_value(json!({
+            "name": "Export test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 5,
+            "entities": [
+                {
+                    "id": 1, "kind": "unit", "name": "Smith & Sons", "side": "blufor", "group": "Alpha",
+                    "start_frame": 0, "states": (0..5).map(|frame| state(frame as f32 * 10.0, "alive")).collect::<Vec<_>>()
+                },
+                {
+                    "id": 2, "kind": "unit", "name": "Jones", "side": "opfor", "group": "Bravo",
+                    "start_frame": 0, "states": (0..5).map(|frame| state(300.0, if frame < 3 { "alive" } else { "dead" })).collect::<Vec<_>>()
+                }
+            ],
+            "events": [
+                { "frame": 3, "type": "killed", "victim": 2, "killer": 1, "weapon": "Rifle", "distance": 300.0 }
+            ]
+        })).unwrap()
+    }
+
+    fn filter(side: Option<Side>, start_frame: FrameNumber, end_frame: FrameNumber) -> ExportFilter {
+        ExportFilter { units: Vec::new(), side, start_frame, end_frame }
+    }
+
+    fn placement(grid: Option<GridSystem>) -> ExportPlacement {
+        ExportPlacement { geo: GeoReference::default(), grid, precision: GridPrecision::Eight, epoch: 1709208000.0 }
+    }
+
+    #[test]
+    fn tracks_cover_the_chosen_side_and_frames() {
+        let mission = mission();
+        let tracks = Export::new(&mission, filter(Some(Side::Blufor), 1, 3), placement(None)).tracks_geojson();
+
+        let features = tracks["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+        let properties = &features[0]["properties"];
+        assert_eq!(properties["id"], json!(1));
+        assert_eq!(properties["start_time"], json!(1.0));
+        assert_eq!(properties["end_time"], json!(3.0));
+        assert_eq!(properties["times"], json!(["2024-02-29T12:00:01.000Z", "2024-02-29T12:00:02.000Z", "2024-02-29T12:00:03.000Z"]));
+        assert_eq!(features[0]["geometry"]["coordinates"].as_array().unwrap().len(), 3);
+        assert_eq!(features[0]["geometry"]["coordinates"][0], json!([10.0 / METRES_PER_DEGREE, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn events_carry_a_grid_only_when_the_terrain_is_known() {
+        let mission = mission();
+        let events = Export::new(&mission, filter(None, 0, 4), placement(Some(GridSystem::default()))).events_geojson();
+        let properties = &events["features"][0]["properties"];
+        assert_eq!(properties["victim"], json!(2));
+        assert_eq!(properties["time"], json!("2024-02-29T12:00:03.000Z"));
+        assert_eq!(properties["grid"], json!("0030 0000"));
+
+        let events = Export::new(&mission, filter(None, 0, 4), placement(None)).events_geojson();
+        assert_eq!(events["features"].as_array().unwrap().len(), 1);
+        assert!(events["features"][0]["properties"].get("grid").is_none());
+    }
+
+    #[test]
+    fn events_outside_the_filter_are_left_out() {
+        let mission = mission();
+        let count = |filter| Export::new(&mission, filter, placement(None)).events_geojson()["features"].as_array().unwrap().len();
+
+        assert_eq!(count(filter(None, 0, 2)), 0);
+        assert_eq!(count(filter(None, 4, 4)), 0);
+        // either side of the kill includes it
+        assert_eq!(count(filter(Some(Side::Opfor), 0, 4)), 1);
+        assert_eq!(count(filter(Some(Side::Blufor), 0, 4)), 1);
+        assert_eq!(count(filter(Some(Side::Civilian), 0, 4)), 0);
+    }
+
+    #[test]
+    fn kml_tracks_are_escaped_and_timed() {
+        let mission = mission();
+        let kml = Export::new(&mission, filter(None, 0, 4), placement(None)).tracks_kml();
+
+        assert_eq!(kml.matches("<Placemark>").count(), 2);
+        assert_eq!(kml.matches("<when>").count(), 10);
+        assert_eq!(kml.matches("<gx:coord>").count(), 10);
+        assert!(kml.contains("<name>Smith &amp; Sons</name>"));
+        assert!(kml.contains("<when>2024-02-29T12:00:04.000Z</when>"));
+
+        let kml = Export::new(&mission, filter(Some(Side::Opfor), 3, 4), placement(None)).tracks_kml();
+        assert_eq!(kml.matches("<Placemark>").count(), 1);
+        assert!(kml.contains("<name>Jones</name>"));
+        assert_eq!(kml.matches("<when>").count(), 2);
+    }
+
+    #[test]
+    fn csv_has_a_grid_column_only_when_the_terrain_is_known() {
+        let mission = mission();
+        let csv = Export::new(&mission, filter(Some(Side::Opfor), 2, 3), placement(None)).positions_csv().unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, [
+            "frame,mission_time,id,name,side,group,x,y,z,longitude,latitude,direction,alive,vehicle",
+            "2,2,2,Jones,opfor,Bravo,300,0,1,0.0026949,0.0000000,90,true,",
+            "3,3,2,Jones,opfor,Bravo,300,0,1,0.0026949,0.0000000,90,false,"
+        ]);
+
+        let csv = Export::new(&mission, filter(Some(Side::Opfor), 2, 2), placement(Some(GridSystem::default()))).positions_csv().unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, [
+            "frame,mission_time,id,name,side,group,x,y,z,longitude,latitude,grid,direction,alive,vehicle",
+            "2,2,2,Jones,opfor,Bravo,300,0,1,0.0026949,0.0000000,0030 0000,90,true,"
+        ]);
+    }
+
+    #[test]
+    fn formats_the_epoch_and_fractions() {
+        assert_eq!(format_timestamp(0.0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(1709208000.25), "2024-02-29T12:00:00.250Z");
+    }
+
+    #[test]
+    fn formats_times_before_1970() {
+        assert_eq!(format_timestamp(-1.0), "1969-12-31T23:59:59.000Z");
+        assert_eq!(format_timestamp(-0.001), "1969-12-31T23:59:59.999Z");
+        assert_eq!(format_timestamp(-2203891201.0), "1900-02-28T23:59:59.000Z");
+    }
+
+    #[test]
+    fn follows_leap_years() {
+        assert_eq!(format_timestamp(951782400.0), "2000-02-29T00:00:00.000Z");
+        // 1900 is not a leap year, so February ends on the 28th
+        assert_eq!(format_timestamp(-2203891200.0), "1900-03-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(-11670998400.0), "1600-02-29T00:00:00.000Z");
+    }
+}
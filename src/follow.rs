@@ -0,0 +1,229 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::mission::{EntityId, EntityState, EventKind, FrameNumber, LifeState, Mission, Position};
+use crate::recording_index::WorldState;
+
+/// What a viewer asked to follow
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FollowTarget {
+    Unit { id: EntityId },
+    Group { name: String }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FollowedUnit {
+    pub id: EntityId,
+    pub state: EntityState
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FollowEvent {
+    Died,
+    EnteredVehicle { vehicle: EntityId },
+    LeftVehicle { vehicle: EntityId },
+    Disconnected
+}
+
+/// A viewer's follow subscription, and what it last knew of the followed units so it can tell
+/// the viewer when something happens to them
+pub struct Follow {
+    units: Vec<EntityId>,
+    last_states: HashMap<EntityId, EntityState>
+}
+
+impl Follow {
+    pub fn new(target: &FollowTarget, mission: &Mission) -> Result<Follow, &'static str> {
+        let units: Vec<EntityId> = match target {
+            FollowTarget::Unit { id } => mission.entity(*id).map(|entity| entity.id).into_iter().collect(),
+            FollowTarget::Group { name } => mission.entities.iter()
+                .filter(|entity| &entity.group == name)
+                .map(|entity| entity.id)
+                .collect()
+        };
+        if units.is_empty() {
+            return Err("Nothing to follow")
+        }
+
+        Ok(Follow {
+            units,
+            last_states: HashMap::new()
+        })
+    }
+
+    pub fn unit_ids(&self) -> &[EntityId] {
+        &self.units
+    }
+
+    /// Exact states of the followed units present in `state`
+    pub fn units(&self, state: &WorldState) -> Vec<FollowedUnit> {
+        self.units.iter()
+            .filter_map(|id| Some(FollowedUnit {
+                id: *id,
+                state: state.entities.get(id)?.clone()
+            }))
+            .collect()
+    }
+
+    /// Followed units positioned between recorded frames, for updates more frequent than the
+    /// recording itself
+    pub fn interpolated(&self, mission: &Mission, mission_time: f64) -> Vec<FollowedUnit> {
+        let frame = mission.frame_at(mission_time);
+        let fraction = if mission.frame_interval > 0.0 {
+            ((mission_time / mission.frame_interval as f64) - frame as f64).clamp(0.0, 1.0) as f32
+        } else {
+            0.0
+        };
+
+        self.units.iter()
+            .filter_map(|id| {
+                let entity = mission.entity(*id)?;
+                let mut state = entity.state_at(frame)?.clone();
+                if let Some(next) = entity.state_at(frame + 1) {
+                    state.position = lerp_position(&state.position, &next.position, fraction);
+                    state.direction = lerp_direction(state.direction, next.direction, fraction);
+                }
+                Some(FollowedUnit { id: *id, state })
+            })
+            .collect()
+    }
+
+    /// Compares followed units against what was seen last. `from` is the last frame the viewer
+    /// saw when playback simply moved on, and `None` after a seek, which only resets what is known
+    pub fn events(&mut self, mission: &Mission, state: &WorldState, from: Option<FrameNumber>) -> Vec<(EntityId, FollowEvent)> {
+        let mut events = Vec::new();
+        if let Some(from) = from {
+            for id in &self.units {
+                let (previous, current) = match (self.last_states.get(id), state.entities.get(id)) {
+                    (Some(previous), Some(current)) => (previous, current),
+                    _ => continue
+                };
+                if previous.life != LifeState::Dead && current.life == LifeState::Dead {
+                    events.push((*id, FollowEvent::Died));
+                }
+                match (previous.vehicle, current.vehicle) {
+                    (None, Some(vehicle)) => events.push((*id, FollowEvent::EnteredVehicle { vehicle })),
+                    (Some(vehicle), None) => events.push((*id, FollowEvent::LeftVehicle { vehicle })),
+                    (Some(left), Some(entered)) if left != entered => {
+                        events.push((*id, FollowEvent::LeftVehicle { vehicle: left }));
+                        events.push((*id, FollowEvent::EnteredVehicle { vehicle: entered }));
+                    },
+                    _ => {}
+                }
+            }
+
+            for event in mission.events_between(from, state.frame) {
+                if let EventKind::Disconnected { name } = &event.kind {
+                    // units the viewer could not see before stay quiet
+                    let disconnected = self.units.iter()
+                        .filter(|id| self.last_states.contains_key(id))
+                        .filter_map(|id| mission.entity(*id))
+                        .filter(|entity| entity.is_player && &entity.name == name);
+                    for entity in disconnected {
+                        events.push((entity.id, FollowEvent::Disconnected));
+                    }
+                }
+            }
+        }
+
+        self.last_states = self.units.iter()
+            .filter_map(|id| Some((*id, state.entities.get(id)?.clone())))
+            .collect();
+        events
+    }
+}
+
+fn lerp_position(from: &Position, to: &Position, fraction: f32) -> Position {
+    Position {
+        x: from.x + (to.x - from.x) * fraction,
+        y: from.y + (to.y - from.y) * fraction,
+        z: from.z + (to.z - from.z) * fraction
+    }
+}
+
+/// Turns the short way round, directions are in degrees
+fn lerp_direction(from: f32, to: f32, fraction: f32) -> f32 {
+    let difference = (to - from + 540.0).rem_euclid(360.0) - 180.0;
+    (from + difference * fraction).rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Unit 1 walks 10m east every two second frame while turning through north, and gets into
+    /// vehicle 2 on frame 3
+    fn mission() -> Mission {
+        let directions = [350.0, 10.0, 30.0, 30.0, 30.0];
+        let states: Vec<_> = (0..5).map(|frame| json!({
+            "position": { "x": frame as f32 * 10.0, "y": 0.0 },
+            "direction": directions[frame],
+            "life": "alive",
+            "vehicle": if frame >= 3 { Some(2) } else { None }
+        })).collect();
+        serde_json::from_value(json!({
+            "name": "Follow test",
+            "world_name": "VR",
+            "frame_interval": 2.0,
+            "frame_count": 5,
+            "entities": [
+                { "id": 1, "kind": "unit", "name": "Scout", "side": "blufor", "group": "Alpha", "start_frame": 0, "states": states },
+                { "id": 2, "kind": "vehicle", "name": "Hunter", "side": "blufor", "group": "", "start_frame": 0, "states": [] }
+            ]
+        })).unwrap()
+    }
+
+    #[test]
+    fn positions_and_directions_are_blended_between_frames() {
+        let mission = mission();
+        let follow = Follow::new(&FollowTarget::Unit { id: 1 }, &mission).unwrap();
+
+        let halfway = &follow.interpolated(&mission, 1.0)[0].state;
+        assert_eq!(halfway.position.x, 5.0);
+        // 350 to 10 turns through north rather than all the way round
+        assert_eq!(halfway.direction, 0.0);
+
+        let on_frame = &follow.interpolated(&mission, 4.0)[0].state;
+        assert_eq!((on_frame.position.x, on_frame.direction), (20.0, 30.0));
+
+        // nothing to blend towards after the last frame
+        assert_eq!(follow.interpolated(&mission, 9.0)[0].state.position.x, 40.0);
+    }
+
+    #[test]
+    fn changes_are_reported_only_when_playback_moves_on() {
+        let mission = mission();
+        let mut follow = Follow::new(&FollowTarget::Group { name: "Alpha".to_string() }, &mission).unwrap();
+        assert_eq!(follow.unit_ids(), [1]);
+        assert!(Follow::new(&FollowTarget::Group { name: "Bravo".to_string() }, &mission).is_err());
+
+        let state = |frame| crate::recording_index::RecordingIndex::build(&mission).snapshot(frame);
+        assert!(follow.events(&mission, &state(2), None).is_empty());
+        assert_eq!(follow.events(&mission, &state(3), Some(2)), [(1, FollowEvent::EnteredVehicle { vehicle: 2 })]);
+
+        // seeking back only resets what is known
+        assert!(follow.events(&mission, &state(0), None).is_empty());
+        assert!(follow.events(&mission, &state(1), Some(0)).is_empty());
+    }
+}
@@ -0,0 +1,441 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, Weak}
+};
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::mission::{Entity, EntityKind, EventKind, FrameNumber, LifeState, Mission, Position, Side};
+use crate::recording_index::Recording;
+use crate::potato_types::Error;
+use crate::canvas::MAX_IMAGE_SIZE;
+
+/// Past this many cells a heatmap is refused rather than built
+const MAX_CELLS: usize = 1_000_000;
+/// Heatmaps and images kept before the least recently used ones are dropped
+const MAX_CACHED: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeatmapKind {
+    /// Where living units spent their time
+    Presence,
+    /// Where units died
+    Deaths,
+    /// Where units fired from
+    Shots
+}
+
+impl HeatmapKind {
+    pub fn from_name(name: &str) -> Option<HeatmapKind> {
+        match name {
+            "presence" => Some(HeatmapKind::Presence),
+            "deaths" => Some(HeatmapKind::Deaths),
+            "shots" => Some(HeatmapKind::Shots),
+            _ => None
+        }
+    }
+}
+
+/// Everything that decides what a heatmap contains. Doubles as the cache key
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HeatmapQuery {
+    pub mission_id: String,
+    pub kind: HeatmapKind,
+    pub side: Option<Side>,
+    pub group: Option<String>,
+    pub start_frame: FrameNumber,
+    pub end_frame: FrameNumber,
+    /// Width of a cell in metres
+    pub cell_size: u32
+}
+
+impl HeatmapQuery {
+    fn includes(&self, entity: &Entity) -> bool {
+        entity.kind == EntityKind::Unit
+            && self.side.is_none_or(|side| entity.side == side)
+            && self.group.as_ref().is_none_or(|group| &entity.group == group)
+    }
+
+    fn includes_frame(&self, frame: FrameNumber) -> bool {
+        (self.start_frame..=self.end_frame).contains(&frame)
+    }
+}
+
+/// Counts per grid cell. Cells go row by row starting from the south west corner at `origin`
+#[derive(Serialize, Clone, Debug)]
+pub struct Heatmap {
+    pub origin: Position,
+    pub cell_size: u32,
+    pub columns: u32,
+    pub rows: u32,
+    pub max: u32,
+    pub cells: Vec<u32>
+}
+
+impl Heatmap {
+    pub fn new(mission: &Mission, query: &HeatmapQuery) -> Result<Heatmap, Error> {
+        let points = Heatmap::points(mission, query);
+        let cell_size = query.cell_size as f32;
+
+        let cell_of = |position: &Position| ((position.x / cell_size).floor() as i64, (position.y / cell_size).floor() as i64);
+        let (min_x, min_y, max_x, max_y) = points.iter()
+            .map(cell_of)
+            .fold((i64::MAX, i64::MAX, i64::MIN, i64::MIN), |(min_x, min_y, max_x, max_y), (x, y)| {
+                (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+            });
+        if points.is_empty() {
+            return Ok(Heatmap {
+                origin: Position::default(),
+                cell_size: query.cell_size,
+                columns: 0,
+                rows: 0,
+                max: 0,
+                cells: Vec::new()
+            })
+        }
+
+        // positions far off the map put the corners further apart than an i64 can count
+        let span = |min: i64, max: i64| max.checked_sub(min)?.checked_add(1).and_then(|span| usize::try_from(span).ok());
+        let (columns, rows) = match (span(min_x, max_x), span(min_y, max_y)) {
+            (Some(columns), Some(rows)) if columns.saturating_mul(rows) <= MAX_CELLS => (columns, rows),
+            _ => return Err("Heatmap would have too many cells, use a larger cell size".into())
+        };
+
+        let mut cells = vec![0; columns * rows];
+        for point in &points {
+            let (x, y) = cell_of(point);
+            cells[(y - min_y) as usize * columns + (x - min_x) as usize] += 1;
+        }
+
+        Ok(Heatmap {
+            origin: Position {
+                x: min_x as f32 * cell_size,
+                y: min_y as f32 * cell_size,
+                z: 0.0
+            },
+            cell_size: query.cell_size,
+            columns: columns as u32,
+            rows: rows as u32,
+            max: cells.iter().copied().max().unwrap_or(0),
+            cells
+        })
+    }
+
+    /// Every position that counts towards the heatmap, once per occurrence
+    fn points(mission: &Mission, query: &HeatmapQuery) -> Vec<Position> {
+        match query.kind {
+            HeatmapKind::Presence => mission.entities.iter()
+                .filter(|entity| query.includes(entity))
+                .flat_map(|entity| (entity.start_frame..entity.end_frame())
+                    .filter(|frame| query.includes_frame(*frame))
+                    .filter_map(|frame| entity.state_at(frame)))
+                .filter(|state| state.life != LifeState::Dead)
+                .map(|state| state.position)
+                .collect(),
+            HeatmapKind::Deaths => mission.events.iter()
+                .filter(|event| query.includes_frame(event.frame))
+                .filter_map(|event| match &event.kind {
+                    EventKind::Killed { victim, .. } => {
+                        let victim = mission.entity(*victim).filter(|victim| query.includes(victim))?;
+                        victim.state_at(event.frame).or(victim.states.last()).map(|state| state.position)
+                    },
+                    _ => None
+                })
+                .collect(),
+            HeatmapKind::Shots => mission.entities.iter()
+                .filter(|entity| query.includes(entity))
+                .flat_map(|entity| entity.shots.iter()
+                    .filter(|shot| query.includes_frame(shot.frame))
+                    .filter_map(|shot| entity.state_at(shot.frame))
+                    .map(|state| state.position))
+                .collect()
+        }
+    }
+
+    /// Renders the heatmap as a transparent overlay with `scale` pixels per cell, north up
+    pub fn to_png(&self, scale: u32) -> Result<Vec<u8>, Error> {
+        if self.columns == 0 || self.rows == 0 || scale == 0 {
+            return Err("Heatmap is empty".into())
+        }
+        let (width, height) = match (self.columns.checked_mul(scale), self.rows.checked_mul(scale)) {
+            (Some(width), Some(height)) if width <= MAX_IMAGE_SIZE && height <= MAX_IMAGE_SIZE => (width, height),
+            _ => return Err("Heatmap image would be too large, use a smaller scale".into())
+        };
+
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        for (index, count) in self.cells.iter().enumerate() {
+            if *count == 0 {
+                continue
+            }
+            let colour = Heatmap::colour(*count, self.max);
+            let column = index as u32 % self.columns;
+            let row = self.rows - 1 - index as u32 / self.columns;
+            for y in row * scale..(row + 1) * scale {
+                for x in column * scale..(column + 1) * scale {
+                    let offset = ((y * width + x) * 4) as usize;
+                    pixels[offset..offset + 4].copy_from_slice(&colour);
+                }
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+
+    /// Blue through yellow to red on a log scale, so a few busy cells don't wash out the rest
+    fn colour(count: u32, max: u32) -> [u8; 4] {
+        let heat = ((1 + count) as f32).ln() / ((1 + max) as f32).ln().max(f32::EPSILON);
+        let (red, green, blue) = if heat < 0.5 {
+            let t = heat * 2.0;
+            (t, t, 1.0 - t)
+        } else {
+            let t = (heat - 0.5) * 2.0;
+            (1.0, 1.0 - t, 0.0)
+        };
+        let alpha = 0.35 + 0.55 * heat;
+        [(red * 255.0) as u8, (green * 255.0) as u8, (blue * 255.0) as u8, (alpha * 255.0) as u8]
+    }
+}
+
+struct CacheEntry<V> {
+    value: V,
+    // the recording the value was built from. Holding on to it keeps its address from being
+    // reused, so a replaced recording never matches
+    recording: Weak<Recording>,
+    last_used: u64
+}
+
+/// Keeps the `MAX_CACHED` most recently used values, each tied to the recording it was built
+/// from. Recordings are replaced when a mission is imported again or a live mission is saved
+struct RecordingCache<K, V> {
+    entries: HashMap<K, CacheEntry<V>>,
+    clock: u64
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> RecordingCache<K, V> {
+    fn new() -> RecordingCache<K, V> {
+        RecordingCache {
+            entries: HashMap::new(),
+            clock: 0
+        }
+    }
+
+    fn get(&mut self, key: &K, recording: &Arc<Recording>) -> Option<V> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        if !Weak::ptr_eq(&entry.recording, &Arc::downgrade(recording)) {
+            return None
+        }
+        entry.last_used = self.clock;
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: K, recording: &Arc<Recording>, value: V) {
+        self.clock += 1;
+        if self.entries.len() >= MAX_CACHED && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, CacheEntry {
+            value,
+            recording: Arc::downgrade(recording),
+            last_used: self.clock
+        });
+    }
+}
+
+/// Built heatmaps and their images
+pub struct HeatmapCache {
+    heatmaps: Mutex<RecordingCache<HeatmapQuery, Arc<Heatmap>>>,
+    images: Mutex<RecordingCache<(HeatmapQuery, u32), Bytes>>
+}
+
+impl HeatmapCache {
+    pub fn new() -> HeatmapCache {
+        HeatmapCache {
+            heatmaps: Mutex::new(RecordingCache::new()),
+            images: Mutex::new(RecordingCache::new())
+        }
+    }
+
+    pub fn heatmap(&self, recording: &Arc<Recording>, query: &HeatmapQuery) -> Result<Arc<Heatmap>, Error> {
+        if let Some(heatmap) = self.heatmaps.lock().unwrap().get(query, recording) {
+            return Ok(heatmap)
+        }
+
+        let heatmap = Arc::new(Heatmap::new(&recording.mission, query)?);
+        self.heatmaps.lock().unwrap().insert(query.clone(), recording, heatmap.clone());
+        Ok(heatmap)
+    }
+
+    pub fn image(&self, recording: &Arc<Recording>, query: &HeatmapQuery, scale: u32) -> Result<Bytes, Error> {
+        let key = (query.clone(), scale);
+        if let Some(image) = self.images.lock().unwrap().get(&key, recording) {
+            return Ok(image)
+        }
+
+        let image = Bytes::from(self.heatmap(recording, query)?.to_png(scale)?);
+        self.images.lock().unwrap().insert(key, recording, image.clone());
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording() -> Arc<Recording> {
+        let mission = serde_json::from_value(serde_json::json!({
+            "name": "Cache test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 0,
+            "entities": []
+        })).unwrap();
+        Arc::new(Recording::new(mission))
+    }
+
+    /// Unit 1 (blufor) stands at (50, 50) for frames 0-2 and dies there on frame 2, unit 2
+    /// (opfor) stands at (250, 150) and fires on frame 1
+    fn mission() -> Mission {
+        let state = |x: f32, y: f32, life: &str| serde_json::json!({
+            "position": { "x": x, "y": y }, "direction": 0.0, "life": life
+        });
+        serde_json::from_value(serde_json::json!({
+            "name": "Heatmap test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 4,
+            "entities": [
+                { "id": 1, "kind": "unit", "name": "Blue", "side": "blufor", "group": "Alpha", "start_frame": 0,
+                    "states": [state(50.0, 50.0, "alive"), state(50.0, 50.0, "alive"), state(50.0, 50.0, "dead"), state(50.0, 50.0, "dead")] },
+                { "id": 2, "kind": "unit", "name": "Red", "side": "opfor", "group": "Bravo", "start_frame": 0,
+                    "states": [state(250.0, 150.0, "alive"), state(250.0, 150.0, "alive"), state(250.0, 150.0, "alive"), state(250.0, 150.0, "alive")],
+                    "shots": [{ "frame": 1, "target": { "x": 50.0, "y": 50.0 } }] }
+            ],
+            "events": [{ "frame": 2, "type": "killed", "victim": 1, "killer": 2, "weapon": "Rifle" }]
+        })).unwrap()
+    }
+
+    fn query(kind: HeatmapKind) -> HeatmapQuery {
+        HeatmapQuery {
+            mission_id: "test".to_string(),
+            kind,
+            side: None,
+            group: None,
+            start_frame: 0,
+            end_frame: 4,
+            cell_size: 100
+        }
+    }
+
+    #[test]
+    fn points_are_counted_in_their_cells() {
+        let mission = mission();
+        let presence = Heatmap::new(&mission, &query(HeatmapKind::Presence)).unwrap();
+        assert_eq!((presence.columns, presence.rows, presence.origin.x, presence.origin.y), (3, 2, 0.0, 0.0));
+        // the dead are not present, the living are counted every frame
+        assert_eq!(presence.cells, [2, 0, 0, 0, 0, 4]);
+        assert_eq!(presence.max, 4);
+
+        let early = HeatmapQuery { end_frame: 0, ..query(HeatmapKind::Presence) };
+        assert_eq!(Heatmap::new(&mission, &early).unwrap().cells, [1, 0, 0, 0, 0, 1]);
+        let opfor = HeatmapQuery { side: Some(Side::Opfor), ..query(HeatmapKind::Presence) };
+        assert_eq!(Heatmap::new(&mission, &opfor).unwrap().cells, [4]);
+
+        let deaths = Heatmap::new(&mission, &query(HeatmapKind::Deaths)).unwrap();
+        assert_eq!((deaths.origin.x, deaths.cells.as_slice()), (0.0, [1].as_slice()));
+        let shots = Heatmap::new(&mission, &query(HeatmapKind::Shots)).unwrap();
+        assert_eq!((shots.origin.x, shots.origin.y, shots.cells.as_slice()), (200.0, 100.0, [1].as_slice()));
+
+        let nothing = HeatmapQuery { group: Some("Charlie".to_string()), ..query(HeatmapKind::Presence) };
+        assert!(Heatmap::new(&mission, &nothing).unwrap().cells.is_empty());
+    }
+
+    #[test]
+    fn spread_out_points_are_refused() {
+        let mut mission = mission();
+        mission.entities[1].states[0].position.x = f32::MAX;
+        mission.entities[0].states[0].position.x = f32::MIN;
+        assert!(Heatmap::new(&mission, &query(HeatmapKind::Presence)).is_err());
+    }
+
+    #[test]
+    fn images_are_north_up_with_empty_cells_left_clear() {
+        let heatmap = Heatmap::new(&mission(), &query(HeatmapKind::Presence)).unwrap();
+        let png = heatmap.to_png(2).unwrap();
+
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (6, 4));
+        let alpha = |x: usize, y: usize| pixels[(y * 6 + x) * 4 + 3];
+        // unit 1's cell is the bottom left, unit 2's the top right
+        assert!(alpha(0, 3) > 0 && alpha(1, 2) > 0);
+        assert!(alpha(5, 0) > alpha(0, 3));
+        assert_eq!(alpha(0, 0), 0);
+
+        assert!(heatmap.to_png(u32::MAX).is_err());
+        assert!(heatmap.to_png(MAX_IMAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn replaced_recordings_miss() {
+        let mut cache = RecordingCache::new();
+        let original = recording();
+        cache.insert("mission", &original, 1);
+        assert_eq!(cache.get(&"mission", &original), Some(1));
+
+        // same contents, but stored again so it has to be rebuilt
+        let replaced = recording();
+        drop(original);
+        assert_eq!(cache.get(&"mission", &replaced), None);
+        cache.insert("mission", &replaced, 2);
+        assert_eq!(cache.get(&"mission", &replaced), Some(2));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = RecordingCache::new();
+        let recording = recording();
+        for key in 0..MAX_CACHED {
+            cache.insert(key, &recording, key);
+        }
+        assert_eq!(cache.get(&0, &recording), Some(0));
+
+        cache.insert(MAX_CACHED, &recording, MAX_CACHED);
+        assert_eq!(cache.entries.len(), MAX_CACHED);
+        assert_eq!(cache.get(&0, &recording), Some(0));
+        assert_eq!(cache.get(&1, &recording), None);
+        assert_eq!(cache.get(&MAX_CACHED, &recording), Some(MAX_CACHED));
+    }
+}
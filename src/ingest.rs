@@ -0,0 +1,222 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    time::Duration,
+    sync::{Arc, RwLock}
+};
+use hyper_tungstenite::{tungstenite, HyperWebsocket, WebSocketStream};
+use hyper::upgrade::Upgraded;
+use tungstenite::{Message, protocol::CloseFrame, protocol::frame::coding::CloseCode};
+use futures::{SinkExt, StreamExt};
+use uuid::Uuid;
+
+use log::{info, warn, debug};
+
+use crate::view_session::LobbyHandler;
+use crate::mission_store::MissionStore;
+use crate::config::ServerConfig;
+use crate::potato_types::Error;
+use crate::live::LiveMission;
+use crate::recording_index::Recording;
+use crate::requests::IngestMessage;
+use crate::responses::IngestReply;
+
+/// How often messages held back by a broadcast delay are checked
+const RELEASE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A game server pushing a live mission. The first message has to start or resume a mission,
+/// everything after it is added to that mission's lobby
+pub struct IngestConnection {
+    config: Arc<ServerConfig>,
+    lobbies: Arc<RwLock<LobbyHandler>>,
+    missions: Arc<MissionStore>,
+    source: String,
+    lobby_uuid: Option<Uuid>
+}
+
+impl IngestConnection {
+    pub fn new(config: Arc<ServerConfig>, lobbies: Arc<RwLock<LobbyHandler>>, missions: Arc<MissionStore>, source: String) -> IngestConnection {
+        IngestConnection {
+            config,
+            lobbies,
+            missions,
+            source,
+            lobby_uuid: None
+        }
+    }
+
+    fn decode(message: &Message) -> Result<Option<IngestMessage>, Error> {
+        match message {
+            Message::Text(text) => Ok(Some(serde_json::from_str(text)?)),
+            Message::Binary(bytes) => Ok(Some(serde_json::from_slice(bytes)?)),
+            _ => Ok(None)
+        }
+    }
+
+    async fn reply(websocket: &mut WebSocketStream<Upgraded>, reply: &IngestReply) -> Result<(), Error> {
+        websocket.send(Message::Text(serde_json::to_string(reply)?)).await?;
+        Ok(())
+    }
+
+    /// Creates the live lobby, or hands back the one a previous connection from this source left
+    fn start(&mut self, mission_id: String, lobby_id: Option<String>, name: String, world_name: String, author: String, frame_interval: f32) -> Result<IngestReply, Error> {
+        let mut lobbies = self.lobbies.write().unwrap();
+        if let Some(lobby_uuid) = lobbies.live_lobby(&self.source, &mission_id) {
+            let lobby = lobbies.lobby_mut(&lobby_uuid).unwrap();
+            lobby.live_mut().unwrap().set_connected(true);
+            self.lobby_uuid = Some(lobby_uuid);
+            let next_frame = lobby.live().unwrap().next_frame(lobby.recording());
+            return Ok(IngestReply::Started {
+                mission_id,
+                lobby_id: lobby_uuid.to_string(),
+                next_frame,
+                resumed: true
+            })
+        }
+
+        if !MissionStore::is_valid_name(&mission_id) || self.missions.exists(&mission_id) {
+            return Err(format!("Mission id {:?} is invalid or already recorded", mission_id).into())
+        }
+
+        let mission = LiveMission::start(name, world_name, author, frame_interval)?;
+        let lobby_id = lobby_id.unwrap_or_else(|| mission_id.clone());
+        let delay = self.config.ingest.sources.get(&self.source).map(|source| source.delay()).unwrap_or_default();
+        let live = LiveMission::new(&self.source, &mission_id, delay);
+        let lobby_uuid = lobbies.create_live_lobby(&lobby_id, live, Recording::new(mission))?;
+        info!(target: "ingest", "{} started live mission {} in lobby {} with a delay of {:?}", self.source, mission_id, lobby_uuid, delay);
+        if !delay.is_zero() {
+            tokio::spawn(IngestConnection::release_delayed(self.lobbies.clone(), lobby_uuid));
+        }
+
+        self.lobby_uuid = Some(lobby_uuid);
+        Ok(IngestReply::Started {
+            mission_id,
+            lobby_id: lobby_uuid.to_string(),
+            next_frame: 0,
+            resumed: false
+        })
+    }
+
+    /// Adds a message to the live mission. Returns the mission id once the mission has ended and
+    /// been saved
+    fn receive(&mut self, message: IngestMessage) -> Result<Option<String>, Error> {
+        let lobby_uuid = self.lobby_uuid.ok_or("No mission has been started")?;
+        IngestConnection::ingest(&self.lobbies, &self.missions, &lobby_uuid, message)
+    }
+
+    fn ingest(lobbies: &RwLock<LobbyHandler>, missions: &MissionStore, lobby_uuid: &Uuid, message: IngestMessage) -> Result<Option<String>, Error> {
+        let (mission_id, recording) = {
+            let mut lobbies = lobbies.write().unwrap();
+            let lobby = lobbies.lobby_mut(lobby_uuid).ok_or("Live lobby no longer exists")?;
+            let mission_id = lobby.live().ok_or("Mission has already ended")?.mission_id().to_string();
+            if !lobby.ingest(message)?.ended {
+                return Ok(None)
+            }
+            (mission_id, lobby.recording().clone())
+        };
+
+        missions.add(&mission_id, recording)?;
+        info!(target: "ingest", "Live mission {} ended and was saved", mission_id);
+        Ok(Some(mission_id))
+    }
+
+    /// Keeps releasing delayed messages for as long as the lobby is live, whether or not the
+    /// source is still connected
+    async fn release_delayed(lobbies: Arc<RwLock<LobbyHandler>>, lobby_uuid: Uuid) {
+        let mut interval = tokio::time::interval(RELEASE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut lobbies = lobbies.write().unwrap();
+            match lobbies.lobby_mut(&lobby_uuid) {
+                Some(lobby) if lobby.is_live() => lobby.release_delayed(),
+                _ => break
+            }
+        }
+    }
+
+    /// Ends and saves a live mission whose source disappeared and did not come back in time
+    async fn abandon(config: Arc<ServerConfig>, lobbies: Arc<RwLock<LobbyHandler>>, missions: Arc<MissionStore>, lobby_uuid: Uuid) {
+        let abandon_after = config.ingest.abandon_after();
+        tokio::time::sleep(abandon_after).await;
+
+        let abandoned = lobbies.read().unwrap().lobby(&lobby_uuid)
+            .and_then(|lobby| lobby.live())
+            .is_some_and(|live| !live.is_connected() && live.last_received().elapsed() >= abandon_after);
+        if abandoned {
+            warn!(target: "ingest", "Live source of lobby {} did not come back, ending the mission", lobby_uuid);
+            if let Err(e) = IngestConnection::ingest(&lobbies, &missions, &lobby_uuid, IngestMessage::End) {
+                warn!(target: "ingest", "Cannot save abandoned live mission: {:?}", e);
+            }
+        }
+    }
+
+    pub async fn serve(mut self, websocket: HyperWebsocket) -> Result<(), Error> {
+        let mut websocket = websocket.await?;
+        info!(target: "ingest", "Live source {} connected", self.source);
+
+        let mut ended = false;
+        while let Some(message) = websocket.next().await {
+            let message = match message {
+                Ok(Message::Close(_)) => break,
+                Ok(message) => message,
+                Err(e) => {
+                    debug!(target: "ingest", "Websocket error from {}: {:?}", self.source, e);
+                    break;
+                }
+            };
+
+            let result = match IngestConnection::decode(&message) {
+                Ok(Some(IngestMessage::Start { mission_id, lobby_id, name, world_name, author, frame_interval })) if self.lobby_uuid.is_none() => {
+                    self.start(mission_id, lobby_id, name, world_name, author, frame_interval).map(Some)
+                },
+                Ok(Some(message)) => self.receive(message).map(|ended| ended.map(|mission_id| IngestReply::Ended { mission_id })),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e)
+            };
+
+            match result {
+                Ok(Some(reply)) => {
+                    ended = matches!(reply, IngestReply::Ended { .. });
+                    IngestConnection::reply(&mut websocket, &reply).await?;
+                    if ended {
+                        break;
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    debug!(target: "ingest", "Rejected message from {}: {:?}", self.source, e);
+                    IngestConnection::reply(&mut websocket, &IngestReply::Rejected { message: e.to_string() }).await?;
+                }
+            }
+        }
+
+        info!(target: "ingest", "Live source {} disconnected", self.source);
+        if ended {
+            let _ = websocket.close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "Mission ended".into()
+            })).await;
+        } else if let Some(lobby_uuid) = self.lobby_uuid {
+            if let Some(live) = self.lobbies.write().unwrap().lobby_mut(&lobby_uuid).and_then(|lobby| lobby.live_mut()) {
+                live.set_connected(false);
+            }
+            tokio::spawn(IngestConnection::abandon(self.config.clone(), self.lobbies.clone(), self.missions.clone(), lobby_uuid));
+        }
+        Ok(())
+    }
+}
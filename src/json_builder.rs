@@ -45,6 +45,6 @@ pub fn build_json_response_from_response<T>(response: responses::Response<T>) ->
     Response::builder()
         .header("Content-Type", "application/json")
         .status(status_code)
-        .body(Body::from(body))
+        .body(body)
     .unwrap()
 }
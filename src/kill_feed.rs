@@ -0,0 +1,185 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Serialize, Deserialize};
+
+use crate::mission::{EntityId, Event, EventKind, FrameNumber, Mission, Side};
+use crate::terrain::{GridPrecision, GridSystem};
+use crate::stats::Statistics;
+
+/// A kill as shown in the kill feed, with names and sides resolved
+#[derive(Serialize, Clone, Debug)]
+pub struct Kill {
+    pub frame: FrameNumber,
+    pub mission_time: f64,
+    pub victim: EntityId,
+    pub victim_name: String,
+    pub victim_side: Side,
+    pub killer: Option<EntityId>,
+    pub killer_name: Option<String>,
+    pub killer_side: Option<Side>,
+    pub weapon: String,
+    /// As recorded, or measured between killer and victim when the recording left it out
+    pub distance: Option<f32>,
+    pub friendly_fire: bool,
+    /// Where the victim died, when the terrain's grid is known
+    pub grid: Option<String>
+}
+
+/// Narrows a kill feed down to kills where either side of the kill matches
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct KillFilter {
+    pub side: Option<Side>,
+    pub unit: Option<EntityId>
+}
+
+impl KillFilter {
+    pub fn matches(&self, kill: &Kill) -> bool {
+        self.side.is_none_or(|side| kill.victim_side == side || kill.killer_side == Some(side))
+            && self.unit.is_none_or(|unit| kill.victim == unit || kill.killer == Some(unit))
+    }
+}
+
+impl Kill {
+    pub fn from_event(mission: &Mission, event: &Event, grid: Option<&GridSystem>) -> Option<Kill> {
+        let (victim, killer, weapon, distance) = match &event.kind {
+            EventKind::Killed { victim, killer, weapon, distance } => (*victim, *killer, weapon, *distance),
+            _ => return None
+        };
+        let victim_entity = mission.entity(victim)?;
+        // a kill made from a vehicle is shown as the unit credited with it, as in the stats.
+        // An empty vehicle still names itself
+        let killer_entity = killer.and_then(|killer| {
+            Statistics::credited_unit(mission, killer, event.frame).or_else(|| mission.entity(killer))
+        });
+
+        let victim_position = victim_entity.state_at(event.frame).map(|state| state.position);
+        let distance = distance.or_else(|| {
+            let victim_position = victim_position?;
+            let killer_position = killer_entity?.state_at(event.frame)?.position;
+            Some(victim_position.distance_2d(&killer_position))
+        });
+        let friendly_fire = killer_entity.is_some_and(|killer| {
+            killer.id != victim && killer.side == victim_entity.side && killer.side != Side::Unknown
+        });
+
+        Some(Kill {
+            frame: event.frame,
+            mission_time: mission.time_of_frame(event.frame),
+            victim,
+            victim_name: victim_entity.name.clone(),
+            victim_side: victim_entity.side,
+            killer: killer_entity.map(|killer| killer.id).or(killer),
+            killer_name: killer_entity.map(|killer| killer.name.clone()),
+            killer_side: killer_entity.map(|killer| killer.side),
+            weapon: weapon.clone(),
+            distance,
+            friendly_fire,
+            grid: grid.zip(victim_position).map(|(grid, position)| grid.reference(&position, GridPrecision::default()))
+        })
+    }
+
+    /// Leaves out everything that tells who made the kill or where from
+    pub fn redact_killer(&mut self) {
+        self.killer = None;
+        self.killer_name = None;
+        self.killer_side = None;
+        self.distance = None;
+        self.friendly_fire = false;
+    }
+}
+
+/// Every kill of the mission in order
+pub fn kills(mission: &Mission, grid: Option<&GridSystem>) -> Vec<Kill> {
+    mission.events.iter()
+        .filter_map(|event| Kill::from_event(mission, event, grid))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Unit 1 (blufor) shoots unit 2 (opfor) 30m away on frame 1, then unit 3 (blufor) kills
+    /// unit 1 from vehicle 10 on frame 2. Nobody is left in vehicle 11 when it kills unit 3
+    fn mission() -> Mission {
+        let state = |x: f32, crew: Vec<u32>| json!({ "position": { "x": x, "y": 0.0 }, "direction": 0.0, "life": "alive", "crew": crew });
+        let entity = |id: u32, kind: &str, side: &str, x: f32, crew: Vec<u32>| json!({
+            "id": id, "kind": kind, "name": format!("Entity {}", id), "side": side, "group": "", "start_frame": 0,
+            "states": [state(x, crew.clone()), state(x, crew.clone()), state(x, crew.clone()), state(x, crew)]
+        });
+        serde_json::from_value(json!({
+            "name": "Kill feed test",
+            "world_name": "VR",
+            "frame_interval": 2.0,
+            "frame_count": 4,
+            "entities": [
+                entity(1, "unit", "blufor", 0.0, vec![]),
+                entity(2, "unit", "opfor", 30.0, vec![]),
+                entity(3, "unit", "blufor", 50.0, vec![]),
+                entity(10, "vehicle", "unknown", 50.0, vec![3]),
+                entity(11, "vehicle", "unknown", 90.0, vec![])
+            ],
+            "events": [
+                { "frame": 1, "type": "killed", "victim": 2, "killer": 1, "weapon": "Rifle" },
+                { "frame": 2, "type": "killed", "victim": 1, "killer": 10, "weapon": "HMG", "distance": 48.0 },
+                { "frame": 3, "type": "killed", "victim": 3, "killer": 11, "weapon": "Mine" },
+                { "frame": 3, "type": "hit", "victim": 2, "shooter": 1, "weapon": "Rifle" }
+            ]
+        })).unwrap()
+    }
+
+    #[test]
+    fn kills_are_credited_to_the_unit_that_made_them() {
+        let mission = mission();
+        let kills = kills(&mission, None);
+        assert_eq!(kills.len(), 3);
+
+        assert_eq!((kills[0].killer, kills[0].friendly_fire, kills[0].mission_time), (Some(1), false, 2.0));
+        // measured when the recording left it out
+        assert_eq!(kills[0].distance, Some(30.0));
+        assert_eq!(kills[0].grid, None);
+
+        assert_eq!(kills[1].killer, Some(3));
+        assert_eq!(kills[1].killer_name.as_deref(), Some("Entity 3"));
+        assert_eq!(kills[1].killer_side, Some(Side::Blufor));
+        assert!(kills[1].friendly_fire);
+        assert_eq!(kills[1].distance, Some(48.0));
+
+        assert_eq!(kills[2].killer, Some(11));
+        assert!(!kills[2].friendly_fire);
+    }
+
+    #[test]
+    fn filters_match_either_side_of_a_kill() {
+        let kills = kills(&mission(), None);
+        let matching = |filter: serde_json::Value| {
+            let filter: KillFilter = serde_json::from_value(filter).unwrap();
+            kills.iter().filter(|kill| filter.matches(kill)).count()
+        };
+        assert_eq!(matching(json!({})), 3);
+        assert_eq!(matching(json!({ "side": "opfor" })), 1);
+        assert_eq!(matching(json!({ "unit": 3 })), 2);
+        assert_eq!(matching(json!({ "side": "opfor", "unit": 3 })), 0);
+
+        let mut redacted = kills[1].clone();
+        redacted.redact_killer();
+        assert_eq!((redacted.killer, redacted.distance, redacted.friendly_fire), (None, None, false));
+    }
+}
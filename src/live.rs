@@ -0,0 +1,422 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    time::{Duration, Instant},
+    collections::{BTreeMap, HashSet, VecDeque}
+};
+
+use log::{debug, warn};
+
+use crate::potato_types::Error;
+use crate::mission::{Entity, Event, FrameNumber, MarkerPosition, Mission, ShotFired};
+use crate::recording_index::Recording;
+use crate::requests::{IngestMessage, LiveEntityState, LiveFrame};
+
+/// Frames allowed to arrive out of order. Once this many later frames are waiting, a missing
+/// frame is given up on and its entities carry on as they were
+const REORDER_WINDOW: usize = 10;
+/// How far past the latest frame a source may jump. Every skipped frame is filled in, so
+/// anything further is refused
+const MAX_FRAMES_AHEAD: FrameNumber = REORDER_WINDOW as FrameNumber * 4;
+
+/// What a message from a live source changed, so the lobby can tell its viewers. New entities
+/// and markers reach each viewer once its filter lets them through
+#[derive(Default, Debug)]
+pub struct LiveUpdate {
+    pub frames: u32,
+    pub ended: bool
+}
+
+impl LiveUpdate {
+    fn merge(&mut self, other: LiveUpdate) {
+        self.frames += other.frames;
+        self.ended |= other.ended;
+    }
+}
+
+/// A mission being recorded as it is played. Lives in its lobby alongside the growing recording
+pub struct LiveMission {
+    source: String,
+    mission_id: String,
+    pending: BTreeMap<FrameNumber, LiveFrame>,
+    // messages held back by the broadcast delay, oldest first
+    delay: Duration,
+    delayed: VecDeque<(Instant, IngestMessage)>,
+    highest_frame: Option<FrameNumber>,
+    // earliest frame filled in since the recording was last indexed
+    refilled_from: Option<FrameNumber>,
+    last_received: Instant,
+    connected: bool
+}
+
+impl LiveMission {
+    pub fn new(source: &str, mission_id: &str, delay: Duration) -> LiveMission {
+        LiveMission {
+            source: source.to_string(),
+            mission_id: mission_id.to_string(),
+            pending: BTreeMap::new(),
+            delay,
+            delayed: VecDeque::new(),
+            highest_frame: None,
+            refilled_from: None,
+            last_received: Instant::now(),
+            connected: true
+        }
+    }
+
+    /// The empty recording a live mission starts from
+    pub fn start(name: String, world_name: String, author: String, frame_interval: f32) -> Result<Mission, Error> {
+        if !frame_interval.is_finite() || frame_interval <= 0.0 {
+            return Err("Invalid frame interval".into())
+        }
+        Ok(Mission {
+            name,
+            world_name,
+            author,
+            frame_interval,
+            frame_count: 0,
+            entities: Vec::new(),
+            markers: Vec::new(),
+            events: Vec::new(),
+            knowledge: Vec::new()
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn mission_id(&self) -> &str {
+        &self.mission_id
+    }
+
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        self.last_received = Instant::now();
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Frame a reconnecting source should carry on from, counting frames still held back
+    pub fn next_frame(&self, recording: &Recording) -> FrameNumber {
+        let received = self.highest_frame.map_or(0, |frame| frame.saturating_add(1));
+        received.max(recording.mission.frame_count)
+    }
+
+    /// Takes a message from the source. With a broadcast delay it only reaches the recording once
+    /// the delay has passed, except for the end of the mission which releases everything
+    pub fn receive(&mut self, recording: &mut Recording, message: IngestMessage) -> Result<LiveUpdate, Error> {
+        self.last_received = Instant::now();
+        if let IngestMessage::Frame(frame) = &message {
+            let next_frame = self.next_frame(recording);
+            let too_far = next_frame.checked_add(MAX_FRAMES_AHEAD).is_none_or(|limit| frame.frame > limit);
+            if too_far || frame.frame == FrameNumber::MAX {
+                return Err(format!("Frame {} is too far ahead of frame {}", frame.frame, next_frame).into())
+            }
+            // a second state for an entity would shift every state after it by a frame
+            let mut ids = HashSet::new();
+            if let Some(repeated) = frame.entities.iter().find(|entity| !ids.insert(entity.id)) {
+                return Err(format!("Frame {} has more than one state for entity {}", frame.frame, repeated.id).into())
+            }
+            self.highest_frame = self.highest_frame.max(Some(frame.frame));
+        }
+
+        match message {
+            IngestMessage::End => {
+                let mut update = self.release_all(recording, |_| true);
+                update.merge(self.apply(recording, IngestMessage::End)?);
+                Ok(update)
+            },
+            message if self.delay.is_zero() => self.apply(recording, message),
+            message => {
+                self.delayed.push_back((Instant::now(), message));
+                Ok(LiveUpdate::default())
+            }
+        }
+    }
+
+    /// Adds messages whose delay has passed to the recording
+    pub fn release(&mut self, recording: &mut Recording) -> LiveUpdate {
+        let delay = self.delay;
+        self.release_all(recording, |received| received.elapsed() >= delay)
+    }
+
+    fn release_all(&mut self, recording: &mut Recording, ready: impl Fn(&Instant) -> bool) -> LiveUpdate {
+        let mut update = LiveUpdate::default();
+        while self.delayed.front().is_some_and(|(received, _)| ready(received)) {
+            let (_, message) = self.delayed.pop_front().unwrap();
+            // the source was told it was accepted long ago, all that is left is to log it
+            match self.apply(recording, message) {
+                Ok(applied) => update.merge(applied),
+                Err(e) => warn!(target: "live", "Dropping delayed message for {}: {:?}", self.mission_id, e)
+            }
+        }
+        update
+    }
+
+    fn apply(&mut self, recording: &mut Recording, message: IngestMessage) -> Result<LiveUpdate, Error> {
+        let mission = &mut recording.mission;
+        let mut update = LiveUpdate::default();
+
+        match message {
+            IngestMessage::Start { .. } => return Err("Mission has already started".into()),
+            IngestMessage::Entity(live_entity) => {
+                let index = match mission.entities.binary_search_by_key(&live_entity.id, |entity| entity.id) {
+                    Ok(_) => return Err(format!("Entity {} already exists", live_entity.id).into()),
+                    Err(index) => index
+                };
+                mission.entities.insert(index, Entity {
+                    id: live_entity.id,
+                    kind: live_entity.kind,
+                    name: live_entity.name,
+                    side: live_entity.side,
+                    group: live_entity.group,
+                    is_player: live_entity.is_player,
+                    class_name: live_entity.class_name,
+                    start_frame: mission.frame_count,
+                    states: Vec::new(),
+                    shots: Vec::new()
+                });
+            },
+            IngestMessage::Marker { marker } => {
+                if mission.markers.iter().any(|existing| existing.id == marker.id) {
+                    return Err(format!("Marker {} already exists", marker.id).into())
+                }
+                mission.markers.push(marker);
+            },
+            IngestMessage::Knowledge { knowledge } => mission.knowledge.push(knowledge),
+            IngestMessage::Frame(frame) => {
+                if frame.frame < mission.frame_count {
+                    debug!(target: "live", "Dropping frame {} of {}, it was already given up on", frame.frame, self.mission_id);
+                    return Ok(update)
+                }
+                self.pending.insert(frame.frame, frame);
+                update.frames = self.flush(mission, false);
+            },
+            IngestMessage::End => {
+                update.frames = self.flush(mission, true);
+                update.ended = true;
+            }
+        }
+
+        if update.frames > 0 {
+            // filling in an entity that went missing rewrites frames that may be indexed already
+            match self.refilled_from.take() {
+                Some(frame) => recording.index.reindex_from(&recording.mission, frame),
+                None => recording.index.extend(&recording.mission)
+            }
+        }
+        Ok(update)
+    }
+
+    /// Adds every pending frame that can be added in order. Missing frames are skipped over when
+    /// too many frames are waiting behind them, or when the mission is ending
+    fn flush(&mut self, mission: &mut Mission, ending: bool) -> u32 {
+        let mut added = 0;
+        while let Some(first) = self.pending.keys().next().copied() {
+            let next_frame = mission.frame_count;
+            let frame = if first == next_frame {
+                self.pending.remove(&first).unwrap()
+            } else if ending || self.pending.len() > REORDER_WINDOW {
+                warn!(target: "live", "Frame {} of {} never arrived", next_frame, self.mission_id);
+                LiveMission::repeat_frame(mission, next_frame)
+            } else {
+                break;
+            };
+            let refilled = LiveMission::add_frame(mission, frame);
+            self.refilled_from = self.refilled_from.into_iter().chain(refilled).min();
+            added += 1;
+        }
+        added
+    }
+
+    /// Stands in for a lost frame by keeping everything where it was
+    fn repeat_frame(mission: &Mission, frame: FrameNumber) -> LiveFrame {
+        LiveFrame {
+            frame,
+            entities: mission.entities.iter()
+                .filter_map(|entity| Some(LiveEntityState {
+                    id: entity.id,
+                    state: entity.state_at(frame.checked_sub(1)?)?.clone()
+                }))
+                .collect(),
+            ..LiveFrame::default()
+        }
+    }
+
+    /// Returns the first earlier frame that had to be filled in, if any
+    fn add_frame(mission: &mut Mission, frame: LiveFrame) -> Option<FrameNumber> {
+        let number = frame.frame;
+        let mut refilled_from = None;
+        for LiveEntityState { id, state } in frame.entities {
+            let entity = match mission.entities.binary_search_by_key(&id, |entity| entity.id) {
+                Ok(index) => &mut mission.entities[index],
+                Err(_) => {
+                    debug!(target: "live", "State for unknown entity {}", id);
+                    continue
+                }
+            };
+
+            if entity.states.is_empty() {
+                entity.start_frame = number;
+            }
+            // an entity that went missing for a while stays where it was last seen. Frames are
+            // added in order, so this fills at most the frames since it was last sent
+            if entity.end_frame() < number {
+                refilled_from = refilled_from.into_iter().chain(Some(entity.end_frame())).min();
+            }
+            while entity.end_frame() < number {
+                let last = entity.states.last().unwrap().clone();
+                entity.states.push(last);
+            }
+            entity.states.push(state);
+        }
+
+        for shot in frame.shots {
+            if let Ok(index) = mission.entities.binary_search_by_key(&shot.entity, |entity| entity.id) {
+                mission.entities[index].shots.push(ShotFired {
+                    frame: number,
+                    target: shot.target
+                });
+            }
+        }
+
+        for moved in frame.markers {
+            if let Some(marker) = mission.markers.iter_mut().find(|marker| marker.id == moved.id) {
+                marker.positions.push(MarkerPosition {
+                    frame: number,
+                    position: moved.position,
+                    direction: moved.direction,
+                    alpha: moved.alpha.unwrap_or(1.0)
+                });
+            }
+        }
+        for id in frame.removed_markers {
+            if let Some(marker) = mission.markers.iter_mut().find(|marker| marker.id == id) {
+                marker.end_frame = Some(number);
+            }
+        }
+
+        mission.events.extend(frame.events.into_iter().map(|kind| Event { frame: number, kind }));
+        // receive refuses the last frame number, so there is always a next one
+        mission.frame_count = number.saturating_add(1);
+        refilled_from
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::recording_index::RecordingIndex;
+
+    fn live() -> (LiveMission, Recording) {
+        delayed_live(Duration::ZERO)
+    }
+
+    fn delayed_live(delay: Duration) -> (LiveMission, Recording) {
+        let mission = LiveMission::start("Live test".to_string(), "VR".to_string(), String::new(), 1.0).unwrap();
+        let mut recording = Recording::new(mission);
+        let mut live = LiveMission::new("test", "live-test", delay);
+        for id in [1, 2] {
+            let entity = json!({ "type": "entity", "id": id, "kind": "unit", "name": format!("Unit {}", id), "side": "blufor" });
+            live.receive(&mut recording, serde_json::from_value(entity).unwrap()).unwrap();
+        }
+        (live, recording)
+    }
+
+    fn frame(frame: FrameNumber, ids: &[u32]) -> IngestMessage {
+        let entities: Vec<_> = ids.iter()
+            .map(|id| json!({ "id": id, "position": { "x": frame as f32, "y": *id as f32 }, "direction": 0.0, "life": "alive" }))
+            .collect();
+        serde_json::from_value(json!({ "type": "frame", "frame": frame, "entities": entities })).unwrap()
+    }
+
+    #[test]
+    fn filling_in_missing_entities_keeps_the_index_in_step() {
+        let (mut live, mut recording) = live();
+        live.receive(&mut recording, frame(0, &[1, 2])).unwrap();
+        live.receive(&mut recording, frame(1, &[1])).unwrap();
+        live.receive(&mut recording, frame(2, &[1])).unwrap();
+        assert!(!recording.index.snapshot(2).entities.contains_key(&2));
+        let revision = recording.index.revision();
+
+        // unit 2 comes back out of order, after frames 1 and 2 were indexed without it
+        live.receive(&mut recording, frame(4, &[1, 2])).unwrap();
+        live.receive(&mut recording, frame(3, &[1, 2])).unwrap();
+        assert_eq!(recording.mission.frame_count, 5);
+        assert!(recording.index.revision() > revision);
+
+        let built = RecordingIndex::build(&recording.mission);
+        for number in 0..recording.mission.frame_count {
+            assert_eq!(recording.index.snapshot(number), built.snapshot(number), "frame {}", number);
+        }
+        assert_eq!(recording.index.snapshot(2).entities[&2].position.x, 0.0);
+    }
+
+    #[test]
+    fn frames_naming_an_entity_twice_are_refused() {
+        let (mut live, mut recording) = live();
+        live.receive(&mut recording, frame(0, &[1])).unwrap();
+        assert!(live.receive(&mut recording, frame(1, &[1, 2, 1])).is_err());
+        assert_eq!(recording.mission.frame_count, 1);
+        assert_eq!(recording.mission.entity(1).unwrap().states.len(), 1);
+        assert_eq!(live.next_frame(&recording), 1);
+    }
+
+    #[test]
+    fn delayed_frames_wait_until_their_time_or_the_end() {
+        let (mut live, mut recording) = delayed_live(Duration::from_secs(3600));
+        live.receive(&mut recording, frame(0, &[1, 2])).unwrap();
+        live.receive(&mut recording, frame(1, &[1, 2])).unwrap();
+        assert!(recording.mission.entities.is_empty());
+        assert_eq!(recording.mission.frame_count, 0);
+        // a reconnecting source carries on after the frames held back
+        assert_eq!(live.next_frame(&recording), 2);
+
+        live.release(&mut recording);
+        assert_eq!(recording.mission.frame_count, 0);
+
+        live.receive(&mut recording, IngestMessage::End).unwrap();
+        assert_eq!(recording.mission.entities.len(), 2);
+        assert_eq!(recording.mission.frame_count, 2);
+    }
+
+    #[test]
+    fn delayed_frames_are_released_once_the_delay_passes() {
+        let (mut live, mut recording) = delayed_live(Duration::from_millis(20));
+        live.receive(&mut recording, frame(0, &[1, 2])).unwrap();
+        assert_eq!(recording.mission.frame_count, 0);
+
+        std::thread::sleep(Duration::from_millis(30));
+        live.release(&mut recording);
+        assert_eq!(recording.mission.entities.len(), 2);
+        assert_eq!(recording.mission.frame_count, 1);
+        assert_eq!(recording.index.snapshot(0).entities.len(), 2);
+    }
+}
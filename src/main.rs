@@ -23,9 +23,31 @@ mod serve_static;
 mod requests;
 mod responses;
 mod utils;
+mod mission;
+mod mission_store;
+mod ocap;
+mod recording_index;
+mod viewer;
+mod encoding;
+mod config;
+mod chat;
+mod annotation;
+mod deep_link;
+mod follow;
+mod visibility;
+mod live;
+mod ingest;
+mod kill_feed;
+mod stats;
+mod heatmap;
+mod canvas;
+mod snapshot;
+mod timelapse;
+mod export;
+mod terrain;
 
 use crate::potato_types::Error;
-use pretty_env_logger;
+use crate::config::ServerConfig;
 use std::env;
 use log::info;
 
@@ -34,9 +56,14 @@ async fn main() -> Result<(), Error> {
     env::set_var("RUST_APP_LOG", "trace");
     pretty_env_logger::init_custom_env("RUST_APP_LOG");
 
-    let addr: std::net::SocketAddr = "[::1]:3000".parse()?;
+    let config = ServerConfig::load("config.json")?;
+
+    let addr: std::net::SocketAddr = match config.listen {
+        Some(addr) => addr,
+        None => "[::1]:3000".parse()?
+    };
     info!(target: "potato_plant_replay", "Listening on {:?}", addr);
-    let svc = view_session_service::MakeViewSessionService::new();
+    let svc = view_session_service::MakeViewSessionService::new(config)?;
     let server = hyper::Server::bind(&addr).serve(svc);
 
     server.await?;
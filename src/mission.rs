@@ -0,0 +1,291 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Serialize, Deserialize};
+
+pub type EntityId = u32;
+pub type FrameNumber = u32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Blufor,
+    Opfor,
+    Independent,
+    Civilian,
+    Unknown
+}
+
+impl Side {
+    /// Parses the side names the game uses in scripts and recordings (WEST, EAST, GUER, ...)
+    pub fn from_game_name(name: &str) -> Option<Side> {
+        match name.to_ascii_uppercase().as_str() {
+            "WEST" | "BLUFOR" => Some(Side::Blufor),
+            "EAST" | "OPFOR" => Some(Side::Opfor),
+            "GUER" | "INDEPENDENT" | "RESISTANCE" => Some(Side::Independent),
+            "CIV" | "CIVILIAN" => Some(Side::Civilian),
+            "UNKNOWN" | "EMPTY" | "LOGIC" | "AMBIENT LIFE" => Some(Side::Unknown),
+            _ => None
+        }
+    }
+
+    /// Name as it appears in serialized data
+    pub fn name(&self) -> &'static str {
+        match self {
+            Side::Blufor => "blufor",
+            Side::Opfor => "opfor",
+            Side::Independent => "independent",
+            Side::Civilian => "civilian",
+            Side::Unknown => "unknown"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub z: f32
+}
+
+impl Position {
+    /// Distance on the map, ignoring height
+    pub fn distance_2d(&self, other: &Position) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LifeState {
+    Alive,
+    Unconscious,
+    Dead
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Unit,
+    Vehicle
+}
+
+/// State of an entity during a single frame
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EntityState {
+    pub position: Position,
+    pub direction: f32,
+    pub life: LifeState,
+    /// Vehicle a unit is riding in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<EntityId>,
+    /// Units riding in a vehicle
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crew: Vec<EntityId>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShotFired {
+    pub frame: FrameNumber,
+    pub target: Position
+}
+
+/// A unit or vehicle that existed at some point during the mission. `states` holds one entry
+/// per frame starting at `start_frame`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entity {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    pub name: String,
+    pub side: Side,
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub is_player: bool,
+    #[serde(default)]
+    pub class_name: String,
+    pub start_frame: FrameNumber,
+    pub states: Vec<EntityState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shots: Vec<ShotFired>
+}
+
+impl Entity {
+    pub fn state_at(&self, frame: FrameNumber) -> Option<&EntityState> {
+        if frame < self.start_frame {
+            return None
+        }
+        self.states.get((frame - self.start_frame) as usize)
+    }
+
+    pub fn end_frame(&self) -> FrameNumber {
+        self.start_frame + self.states.len() as FrameNumber
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MarkerPosition {
+    pub frame: FrameNumber,
+    pub position: Position,
+    #[serde(default)]
+    pub direction: f32,
+    #[serde(default = "MarkerPosition::default_alpha")]
+    pub alpha: f32
+}
+
+impl MarkerPosition {
+    fn default_alpha() -> f32 {
+        1.0
+    }
+}
+
+/// A map marker. `positions` is ordered by frame; the marker sits at the latest entry until it
+/// is moved again or `end_frame` is reached
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Marker {
+    pub id: u32,
+    pub text: String,
+    pub icon: String,
+    #[serde(default)]
+    pub shape: String,
+    #[serde(default)]
+    pub brush: String,
+    pub color: String,
+    #[serde(default)]
+    pub size: [f32; 2],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<Side>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<EntityId>,
+    pub start_frame: FrameNumber,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_frame: Option<FrameNumber>,
+    pub positions: Vec<MarkerPosition>
+}
+
+impl Marker {
+    pub fn position_at(&self, frame: FrameNumber) -> Option<&MarkerPosition> {
+        if frame < self.start_frame || self.end_frame.is_some_and(|end| frame >= end) {
+            return None
+        }
+
+        let index = self.positions.partition_point(|position| position.frame <= frame);
+        if index == 0 {
+            return self.positions.first()
+        }
+        self.positions.get(index - 1)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    Killed {
+        victim: EntityId,
+        killer: Option<EntityId>,
+        weapon: String,
+        distance: Option<f32>
+    },
+    Hit {
+        victim: EntityId,
+        shooter: Option<EntityId>,
+        weapon: String,
+        distance: Option<f32>
+    },
+    Connected { name: String },
+    Disconnected { name: String },
+    EndMission { side: Option<Side>, message: String },
+    Message { text: String }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub frame: FrameNumber,
+    #[serde(flatten)]
+    pub kind: EventKind
+}
+
+/// A side knew about an entity from `start_frame` until just before `end_frame`, such as when
+/// it was spotted or reported
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Knowledge {
+    pub side: Side,
+    pub entity: EntityId,
+    pub start_frame: FrameNumber,
+    pub end_frame: FrameNumber
+}
+
+/// A complete mission recording. Entities are sorted by id and events by frame
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mission {
+    pub name: String,
+    pub world_name: String,
+    #[serde(default)]
+    pub author: String,
+    /// Seconds between two recorded frames
+    pub frame_interval: f32,
+    pub frame_count: FrameNumber,
+    pub entities: Vec<Entity>,
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+    #[serde(default)]
+    pub events: Vec<Event>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub knowledge: Vec<Knowledge>
+}
+
+impl Mission {
+    pub fn duration_seconds(&self) -> f64 {
+        self.frame_count as f64 * self.frame_interval as f64
+    }
+
+    pub fn time_of_frame(&self, frame: FrameNumber) -> f64 {
+        frame as f64 * self.frame_interval as f64
+    }
+
+    pub fn frame_at(&self, mission_seconds: f64) -> FrameNumber {
+        if self.frame_interval <= 0.0 || mission_seconds <= 0.0 {
+            return 0
+        }
+        let frame = (mission_seconds / self.frame_interval as f64).floor() as FrameNumber;
+        frame.min(self.frame_count.saturating_sub(1))
+    }
+
+    pub fn entity(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.binary_search_by_key(&id, |entity| entity.id).ok()
+            .map(|index| &self.entities[index])
+    }
+
+    /// Events after frame `from` up to and including frame `to`
+    pub fn events_between(&self, from: FrameNumber, to: FrameNumber) -> &[Event] {
+        let start = self.events.partition_point(|event| event.frame <= from);
+        let end = self.events.partition_point(|event| event.frame <= to);
+        &self.events[start..end.max(start)]
+    }
+
+    /// Sorts entities and events so lookups can rely on ordering
+    pub fn normalise(&mut self) {
+        self.entities.sort_by_key(|entity| entity.id);
+        self.events.sort_by_key(|event| event.frame);
+        let last_frame = self.entities.iter()
+            .map(|entity| entity.end_frame())
+            .max()
+            .unwrap_or(0);
+        self.frame_count = self.frame_count.max(last_frame);
+    }
+}
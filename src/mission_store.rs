@@ -0,0 +1,272 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock, Mutex},
+    collections::HashMap
+};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use log::{info, warn};
+
+use crate::potato_types::Error;
+use crate::mission::Mission;
+use crate::recording_index::Recording;
+use crate::ocap::{self, ImportReport};
+
+const MAX_BOOKMARK_NAME_LENGTH: usize = 64;
+
+/// A named point in a mission, shared by every lobby watching it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Bookmark {
+    pub bookmark_id: String,
+    pub name: String,
+    pub mission_time: f64
+}
+
+/// Loads recordings from disk and keeps them in memory once loaded. Native recordings live at
+/// `<root>/<mission id>.json` with their bookmarks next to them, OCAP recordings live in `<root>/ocap/` until they
+/// are imported. Everything here reads or writes files, so async callers run it on the blocking pool
+pub struct MissionStore {
+    root_path: PathBuf,
+    missions: RwLock<HashMap<String, Arc<Recording>>>,
+    // held while bookmark files are rewritten so concurrent edits do not drop each other
+    bookmark_lock: Mutex<()>
+}
+
+impl MissionStore {
+    pub fn new(root_path: impl Into<PathBuf>) -> MissionStore {
+        MissionStore {
+            root_path: root_path.into(),
+            missions: RwLock::new(HashMap::new()),
+            bookmark_lock: Mutex::new(())
+        }
+    }
+
+    /// Mission ids end up in paths next to other files of the mission, so they cannot hold dots
+    /// that would let one mission's file name pass for another's
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Names of OCAP files, plain file names with extensions
+    pub fn is_valid_file_name(name: &str) -> bool {
+        !name.starts_with('.')
+            && MissionStore::is_valid_name(&name.replace('.', ""))
+    }
+
+    fn native_path(&self, mission_id: &str) -> PathBuf {
+        self.root_path.join(format!("{}.json", mission_id))
+    }
+
+    fn bookmarks_path(&self, mission_id: &str) -> PathBuf {
+        self.root_path.join(format!("{}.bookmarks.json", mission_id))
+    }
+
+    fn ocap_path(&self, file_name: &str) -> PathBuf {
+        self.root_path.join("ocap").join(file_name)
+    }
+
+    fn save(&self, mission_id: &str, mission: &Mission) -> Result<(), Error> {
+        fs::create_dir_all(&self.root_path)?;
+        fs::write(self.native_path(mission_id), serde_json::to_vec(mission)?)?;
+        Ok(())
+    }
+
+    fn cache(&self, mission_id: &str, mission: Mission) -> Arc<Recording> {
+        let recording = Arc::new(Recording::new(mission));
+        self.missions.write().unwrap().insert(mission_id.to_string(), recording.clone());
+        recording
+    }
+
+    /// Whether a recording with this id exists, without loading it
+    pub fn exists(&self, mission_id: &str) -> bool {
+        self.missions.read().unwrap().contains_key(mission_id) || self.native_path(mission_id).exists()
+    }
+
+    /// Saves a recording made elsewhere, such as a finished live mission
+    pub fn add(&self, mission_id: &str, recording: Arc<Recording>) -> Result<(), Error> {
+        if !MissionStore::is_valid_name(mission_id) {
+            return Err(format!("Invalid mission id {:?}", mission_id).into())
+        }
+
+        self.save(mission_id, &recording.mission)?;
+        self.missions.write().unwrap().insert(mission_id.to_string(), recording);
+        Ok(())
+    }
+
+    pub fn load(&self, mission_id: &str) -> Result<Arc<Recording>, Error> {
+        if !MissionStore::is_valid_name(mission_id) {
+            return Err(format!("Invalid mission id {:?}", mission_id).into())
+        }
+
+        if let Some(recording) = self.missions.read().unwrap().get(mission_id) {
+            return Ok(recording.clone())
+        }
+
+        let native_path = self.native_path(mission_id);
+        if !native_path.exists() {
+            return Err(format!("No recording exists for mission {:?}", mission_id).into())
+        }
+        let mut mission: Mission = serde_json::from_slice(&fs::read(native_path)?)?;
+        mission.normalise();
+        Ok(self.cache(mission_id, mission))
+    }
+
+    /// Converts `<root>/ocap/<file_name>` into a native recording stored as `mission_id`
+    pub fn import_ocap(&self, mission_id: &str, file_name: &str) -> Result<ImportReport, Error> {
+        if !MissionStore::is_valid_name(mission_id) || !MissionStore::is_valid_file_name(file_name) {
+            return Err("Invalid mission id or file name".into())
+        }
+
+        let (mission, report) = ocap::import(&fs::read(self.ocap_path(file_name))?)?;
+        info!(target: "MissionStore", "Imported OCAP recording {} as {}: {} entities, {} markers, {} events",
+            file_name, mission_id, report.entities, report.markers, report.events);
+        if !report.warnings.is_empty() || !report.unmapped_events.is_empty() {
+            warn!(target: "MissionStore", "Imported {} with {} warnings, unmapped events: {:?}", file_name, report.warnings.len(), report.unmapped_events);
+        }
+
+        self.save(mission_id, &mission)?;
+        self.cache(mission_id, mission);
+        Ok(report)
+    }
+
+    /// Bookmarks of a mission ordered by mission time
+    pub fn bookmarks(&self, mission_id: &str) -> Result<Vec<Bookmark>, Error> {
+        if !MissionStore::is_valid_name(mission_id) {
+            return Err(format!("Invalid mission id {:?}", mission_id).into())
+        }
+
+        let path = self.bookmarks_path(mission_id);
+        if !path.exists() {
+            return Ok(Vec::new())
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    fn save_bookmarks(&self, mission_id: &str, bookmarks: &mut [Bookmark]) -> Result<(), Error> {
+        bookmarks.sort_by(|a, b| a.mission_time.total_cmp(&b.mission_time));
+        fs::write(self.bookmarks_path(mission_id), serde_json::to_vec(bookmarks)?)?;
+        Ok(())
+    }
+
+    /// Adds a bookmark and returns the mission's updated bookmarks
+    pub fn add_bookmark(&self, mission_id: &str, name: &str, mission_time: f64) -> Result<Vec<Bookmark>, Error> {
+        let recording = self.load(mission_id)?;
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_BOOKMARK_NAME_LENGTH {
+            return Err("Invalid bookmark name".into())
+        }
+        if !(0.0..=recording.mission.duration_seconds()).contains(&mission_time) {
+            return Err("Bookmark is outside of the mission".into())
+        }
+
+        let _guard = self.bookmark_lock.lock().unwrap();
+        let mut bookmarks = self.bookmarks(mission_id)?;
+        bookmarks.push(Bookmark {
+            bookmark_id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            mission_time
+        });
+        self.save_bookmarks(mission_id, &mut bookmarks)?;
+        Ok(bookmarks)
+    }
+
+    /// Removes a bookmark and returns the mission's updated bookmarks
+    pub fn delete_bookmark(&self, mission_id: &str, bookmark_id: &str) -> Result<Vec<Bookmark>, Error> {
+        let _guard = self.bookmark_lock.lock().unwrap();
+        let mut bookmarks = self.bookmarks(mission_id)?;
+        let count = bookmarks.len();
+        bookmarks.retain(|bookmark| bookmark.bookmark_id != bookmark_id);
+        if bookmarks.len() == count {
+            return Err(format!("No bookmark {:?} exists for mission {:?}", bookmark_id, mission_id).into())
+        }
+        self.save_bookmarks(mission_id, &mut bookmarks)?;
+        Ok(bookmarks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mission_ids_cannot_name_other_missions_files() {
+        assert!(MissionStore::is_valid_name("op-1_final"));
+        assert!(!MissionStore::is_valid_name("foo.bookmarks"));
+        assert!(!MissionStore::is_valid_name("../foo"));
+        assert!(!MissionStore::is_valid_name(""));
+
+        // the only id whose recording would land on foo's bookmarks is refused
+        let store = MissionStore::new("recordings");
+        assert_eq!(store.native_path("foo.bookmarks"), store.bookmarks_path("foo"));
+        assert!(store.import_ocap("foo.bookmarks", "foo.json").is_err());
+    }
+
+    #[test]
+    fn ocap_file_names_keep_their_extensions() {
+        assert!(MissionStore::is_valid_file_name("op-1.json.gz"));
+        assert!(!MissionStore::is_valid_file_name(".hidden.json"));
+        assert!(!MissionStore::is_valid_file_name("../op.json"));
+        assert!(!MissionStore::is_valid_file_name("ocap/op.json"));
+    }
+
+    #[test]
+    fn loading_does_not_import_ocap_recordings() {
+        let root = std::env::temp_dir().join(format!("potato-missions-{}", std::process::id()));
+        fs::create_dir_all(root.join("ocap")).unwrap();
+        let recording = serde_json::json!({ "missionName": "Op", "worldName": "VR", "endFrame": 1, "entities": [] });
+        fs::write(root.join("ocap").join("op.json"), serde_json::to_vec(&recording).unwrap()).unwrap();
+
+        let store = MissionStore::new(&root);
+        assert!(store.load("op").is_err());
+        store.import_ocap("op", "op.json").unwrap();
+        assert_eq!(store.load("op").unwrap().mission.name, "Op");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn bookmarks_stay_ordered_and_inside_the_mission() {
+        let root = std::env::temp_dir().join(format!("potato-bookmarks-{}", std::process::id()));
+        fs::create_dir_all(root.join("ocap")).unwrap();
+        let recording = serde_json::json!({ "missionName": "Op", "worldName": "VR", "endFrame": 100, "entities": [] });
+        fs::write(root.join("ocap").join("op.json"), serde_json::to_vec(&recording).unwrap()).unwrap();
+        let store = MissionStore::new(&root);
+        store.import_ocap("op", "op.json").unwrap();
+
+        assert!(store.bookmarks("op").unwrap().is_empty());
+        store.add_bookmark("op", "Extract", 90.0).unwrap();
+        let bookmarks = store.add_bookmark("op", "  Contact ", 20.0).unwrap();
+        let names: Vec<&str> = bookmarks.iter().map(|bookmark| bookmark.name.as_str()).collect();
+        assert_eq!(names, ["Contact", "Extract"]);
+
+        assert!(store.add_bookmark("op", "Late", 101.0).is_err());
+        assert!(store.add_bookmark("op", " ", 10.0).is_err());
+        assert!(store.add_bookmark("op", &"x".repeat(MAX_BOOKMARK_NAME_LENGTH + 1), 10.0).is_err());
+        assert!(store.delete_bookmark("op", "missing").is_err());
+
+        // a fresh store reads what the last one wrote
+        let store = MissionStore::new(&root);
+        let remaining = store.delete_bookmark("op", &bookmarks[0].bookmark_id).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(store.bookmarks("op").unwrap()[0].name, "Extract");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
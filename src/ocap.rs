@@ -0,0 +1,565 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    io::Read,
+    collections::BTreeMap
+};
+use serde::Serialize;
+use serde_json::Value;
+use flate2::read::GzDecoder;
+
+use log::debug;
+
+use crate::potato_types::Error;
+use crate::mission::{
+    Mission, Entity, EntityKind, EntityState, EntityId, LifeState, Position, Side, ShotFired,
+    Marker, MarkerPosition, Event, EventKind, FrameNumber
+};
+
+/// Longest recording accepted, about eleven days at one frame a second
+const MAX_FRAME_COUNT: FrameNumber = 1_000_000;
+
+/// Everything from an OCAP recording that could not be carried over into a `Mission`
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub entities: usize,
+    pub markers: usize,
+    pub events: usize,
+    pub unmapped_events: BTreeMap<String, usize>,
+    pub warnings: Vec<String>
+}
+
+impl ImportReport {
+    fn warn(&mut self, message: String) {
+        debug!(target: "ocap", "{}", message);
+        self.warnings.push(message);
+    }
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    // OCAP writes gzipped JSON, but archives are frequently stored already unpacked
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut decoded)?;
+        return Ok(decoded)
+    }
+    Ok(bytes.to_vec())
+}
+
+fn as_f32(value: &Value) -> Option<f32> {
+    value.as_f64().map(|v| v as f32)
+}
+
+fn as_frame(value: &Value) -> Option<FrameNumber> {
+    value.as_f64().filter(|v| *v >= 0.0).map(|v| v as FrameNumber)
+}
+
+fn as_flag(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().unwrap_or(0.0) != 0.0,
+        _ => false
+    }
+}
+
+fn as_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string()
+    }
+}
+
+fn parse_position(value: &Value) -> Option<Position> {
+    let coordinates = value.as_array()?;
+    Some(Position {
+        x: as_f32(coordinates.first()?)?,
+        y: as_f32(coordinates.get(1)?)?,
+        z: coordinates.get(2).and_then(as_f32).unwrap_or(0.0)
+    })
+}
+
+fn parse_life_state(value: &Value) -> LifeState {
+    match value.as_i64() {
+        Some(0) => LifeState::Dead,
+        Some(2) => LifeState::Unconscious,
+        Some(_) => LifeState::Alive,
+        None => if as_flag(value) { LifeState::Alive } else { LifeState::Dead }
+    }
+}
+
+/// OCAP references killers as `[id, weapon]`, or `["null"]` when there was no killer
+fn parse_causer(value: &Value) -> (Option<EntityId>, String) {
+    match value.as_array() {
+        Some(causer) => (
+            causer.first().and_then(|id| id.as_u64()).map(|id| id as EntityId),
+            causer.get(1).map(as_string).unwrap_or_default()
+        ),
+        None => (None, String::new())
+    }
+}
+
+/// Where each entry of a vehicle's positions starts and ends, for recordings that collapse
+/// stationary vehicles into frame ranges
+struct FrameRange {
+    /// Frame of the entity's first state
+    start_frame: FrameNumber,
+    /// Frames in the recording, ranges are cut off there. Ranges cannot be trusted without it
+    frame_count: Option<FrameNumber>
+}
+
+impl FrameRange {
+    /// How many states an entry stands for, the first being the next state to be read
+    fn repeats(&self, range: &Value, states_read: usize, report: &mut ImportReport, entity_id: EntityId) -> usize {
+        let (start, end) = match range.as_array().and_then(|range| Some((as_frame(range.first()?)?, as_frame(range.get(1)?)?))) {
+            Some(range) => range,
+            None => return 1
+        };
+        let frame_count = match self.frame_count {
+            Some(frame_count) => frame_count,
+            None => {
+                report.warn(format!("Entity {} has a frame range but the recording has no end frame", entity_id));
+                return 1
+            }
+        };
+
+        let next = self.start_frame as u64 + states_read as u64;
+        if end < start {
+            report.warn(format!("Entity {} has a frame range running backwards: {}..{}", entity_id, start, end));
+            return 1
+        }
+        if (start as u64) < next {
+            report.warn(format!("Entity {} has a frame range {}..{} overlapping states already read", entity_id, start, end));
+        }
+        let mut end = end as u64;
+        if end >= frame_count as u64 {
+            report.warn(format!("Entity {} has a frame range {}..{} past the end of the recording", entity_id, start, end));
+            end = (frame_count as u64).saturating_sub(1);
+        }
+        (end.max(next) - next + 1) as usize
+    }
+}
+
+fn parse_entity_states(kind: &EntityKind, positions: &[Value], frames: &FrameRange, report: &mut ImportReport, entity_id: EntityId) -> Vec<EntityState> {
+    let mut states = Vec::with_capacity(positions.len());
+    for raw in positions {
+        let fields = match raw.as_array() {
+            Some(fields) => fields,
+            None => {
+                report.warn(format!("Entity {} has a malformed position entry", entity_id));
+                continue;
+            }
+        };
+
+        let position = match fields.first().and_then(parse_position) {
+            Some(position) => position,
+            None => {
+                report.warn(format!("Entity {} has a position without coordinates", entity_id));
+                states.last().map(|state: &EntityState| state.position).unwrap_or_default()
+            }
+        };
+
+        let mut state = EntityState {
+            position,
+            direction: fields.get(1).and_then(as_f32).unwrap_or(0.0),
+            life: fields.get(2).map(parse_life_state).unwrap_or(LifeState::Alive),
+            vehicle: None,
+            crew: Vec::new()
+        };
+
+        if *kind == EntityKind::Vehicle {
+            state.crew = fields.get(3)
+                .and_then(|crew| crew.as_array())
+                .map(|crew| crew.iter().filter_map(|id| id.as_u64()).map(|id| id as EntityId).collect())
+                .unwrap_or_default();
+
+            // OCAP2 collapses stationary vehicles into a single entry covering a frame range
+            let repeat = match fields.get(4) {
+                Some(range) => frames.repeats(range, states.len(), report, entity_id),
+                None => 1
+            };
+            for _ in 1..repeat {
+                states.push(state.clone());
+            }
+        }
+
+        states.push(state);
+    }
+    states
+}
+
+fn parse_entity(raw: &Value, frame_count: Option<FrameNumber>, report: &mut ImportReport) -> Option<Entity> {
+    let id = raw.get("id")?.as_u64()? as EntityId;
+    let kind = match raw.get("type").and_then(|t| t.as_str()) {
+        Some("unit") => EntityKind::Unit,
+        Some("vehicle") => EntityKind::Vehicle,
+        other => {
+            report.warn(format!("Entity {} has unsupported type {:?}", id, other));
+            return None
+        }
+    };
+
+    let side_name = raw.get("side").map(as_string).unwrap_or_default();
+    let side = Side::from_game_name(&side_name).unwrap_or_else(|| {
+        if kind == EntityKind::Unit {
+            report.warn(format!("Entity {} has unknown side {:?}", id, side_name));
+        }
+        Side::Unknown
+    });
+
+    let start_frame = raw.get("startFrameNum").and_then(as_frame).unwrap_or(0);
+    let positions = raw.get("positions").and_then(|p| p.as_array()).map(|p| &p[..]).unwrap_or(&[]);
+    let states = parse_entity_states(&kind, positions, &FrameRange { start_frame, frame_count }, report, id);
+
+    let shots = raw.get("framesFired")
+        .and_then(|f| f.as_array())
+        .map(|fired| fired.iter()
+            .filter_map(|shot| {
+                let shot = shot.as_array()?;
+                Some(ShotFired {
+                    frame: as_frame(shot.first()?)?,
+                    target: parse_position(shot.get(1)?)?
+                })
+            })
+            .collect())
+        .unwrap_or_default();
+
+    let class_field = if kind == EntityKind::Vehicle { "class" } else { "role" };
+    Some(Entity {
+        id,
+        name: raw.get("name").map(as_string).unwrap_or_default(),
+        side,
+        group: raw.get("group").map(as_string).unwrap_or_default(),
+        is_player: raw.get("isPlayer").map(as_flag).unwrap_or(false),
+        class_name: raw.get(class_field).map(as_string).unwrap_or_default(),
+        start_frame,
+        kind,
+        states,
+        shots
+    })
+}
+
+/// Units only record whether they are in a vehicle, vehicles record who is inside. Resolve the
+/// vehicle of each unit from the vehicle crews
+fn link_crews(entities: &mut [Entity]) {
+    let mut seats: Vec<(EntityId, FrameNumber, EntityId)> = Vec::new();
+    for vehicle in entities.iter().filter(|entity| entity.kind == EntityKind::Vehicle) {
+        for (offset, state) in vehicle.states.iter().enumerate() {
+            let frame = vehicle.start_frame + offset as FrameNumber;
+            seats.extend(state.crew.iter().map(|unit| (*unit, frame, vehicle.id)));
+        }
+    }
+
+    for (unit_id, frame, vehicle_id) in seats {
+        if let Ok(index) = entities.binary_search_by_key(&unit_id, |entity| entity.id) {
+            let unit = &mut entities[index];
+            if frame >= unit.start_frame {
+                if let Some(state) = unit.states.get_mut((frame - unit.start_frame) as usize) {
+                    state.vehicle = Some(vehicle_id);
+                }
+            }
+        }
+    }
+}
+
+fn parse_marker_side(value: &Value) -> Option<Side> {
+    // markers store BIS_fnc_sideID indices, -1 meaning visible to everyone
+    match value.as_i64() {
+        Some(0) => Some(Side::Opfor),
+        Some(1) => Some(Side::Blufor),
+        Some(2) => Some(Side::Independent),
+        Some(3) => Some(Side::Civilian),
+        _ => value.as_str().and_then(Side::from_game_name)
+    }
+}
+
+fn parse_marker(id: u32, raw: &Value, report: &mut ImportReport) -> Option<Marker> {
+    let fields = raw.as_array()?;
+    let positions: Vec<MarkerPosition> = fields.get(7)
+        .and_then(|p| p.as_array())
+        .map(|positions| positions.iter()
+            .filter_map(|entry| {
+                let entry = entry.as_array()?;
+                Some(MarkerPosition {
+                    frame: as_frame(entry.first()?)?,
+                    position: parse_position(entry.get(1)?)?,
+                    direction: entry.get(2).and_then(as_f32).unwrap_or(0.0),
+                    alpha: entry.get(3).and_then(as_f32).unwrap_or(1.0)
+                })
+            })
+            .collect())
+        .unwrap_or_default();
+
+    if positions.is_empty() {
+        report.warn(format!("Marker {} has no usable positions", id));
+        return None
+    }
+
+    let size = fields.get(8)
+        .and_then(|s| s.as_array())
+        .and_then(|s| Some([as_f32(s.first()?)?, as_f32(s.get(1)?)?]))
+        .unwrap_or([1.0, 1.0]);
+
+    Some(Marker {
+        id,
+        icon: fields.first().map(as_string).unwrap_or_default(),
+        text: fields.get(1).map(as_string).unwrap_or_default(),
+        start_frame: fields.get(2).and_then(as_frame).unwrap_or(positions[0].frame),
+        end_frame: fields.get(3).and_then(as_frame),
+        author: fields.get(4).and_then(|a| a.as_u64()).map(|a| a as EntityId),
+        color: fields.get(5).map(as_string).unwrap_or_default(),
+        side: fields.get(6).and_then(parse_marker_side),
+        shape: fields.get(9).map(as_string).unwrap_or_default(),
+        brush: fields.get(10).map(as_string).unwrap_or_default(),
+        size,
+        positions
+    })
+}
+
+fn parse_event(raw: &Value, report: &mut ImportReport) -> Option<Event> {
+    let header = raw.as_array()
+        .and_then(|fields| Some((fields, as_frame(fields.first()?)?, fields.get(1)?.as_str()?)));
+    let (fields, frame, event_type) = match header {
+        Some(header) => header,
+        None => {
+            report.warn(format!("Skipped malformed event {}", raw));
+            return None
+        }
+    };
+
+    let kind = match event_type {
+        "killed" | "hit" => {
+            let victim = match fields.get(2).and_then(|victim| victim.as_u64()).and_then(|victim| EntityId::try_from(victim).ok()) {
+                Some(victim) => victim,
+                None => {
+                    report.warn(format!("Skipped {} event without a usable victim: {}", event_type, raw));
+                    return None
+                }
+            };
+            let (causer, weapon) = fields.get(3).map(parse_causer).unwrap_or((None, String::new()));
+            let distance = fields.get(4).and_then(as_f32).filter(|d| *d >= 0.0);
+            if event_type == "killed" {
+                EventKind::Killed { victim, killer: causer, weapon, distance }
+            } else {
+                EventKind::Hit { victim, shooter: causer, weapon, distance }
+            }
+        },
+        "connected" => EventKind::Connected { name: fields.get(2).map(as_string).unwrap_or_default() },
+        "disconnected" => EventKind::Disconnected { name: fields.get(2).map(as_string).unwrap_or_default() },
+        "endMission" => {
+            let (side, message) = match fields.get(2) {
+                Some(Value::Array(result)) => (
+                    result.first().and_then(|s| s.as_str()).and_then(Side::from_game_name),
+                    result.get(1).map(as_string).unwrap_or_default()
+                ),
+                Some(other) => (None, as_string(other)),
+                None => (None, String::new())
+            };
+            EventKind::EndMission { side, message }
+        },
+        "generalEvent" => EventKind::Message { text: fields.get(2).map(as_string).unwrap_or_default() },
+        _ => {
+            *report.unmapped_events.entry(event_type.to_string()).or_insert(0) += 1;
+            return None
+        }
+    };
+
+    Some(Event { frame, kind })
+}
+
+/// Converts an OCAP or OCAP2 recording (gzipped or plain JSON) into a `Mission`
+pub fn import(bytes: &[u8]) -> Result<(Mission, ImportReport), Error> {
+    let json: Value = serde_json::from_slice(&decompress(bytes)?)?;
+    if !json.is_object() {
+        return Err("OCAP recording is not a JSON object".into())
+    }
+
+    let mut report = ImportReport::default();
+    let frame_count = json.get("endFrame").and_then(as_frame);
+    if frame_count.is_some_and(|frame_count| frame_count > MAX_FRAME_COUNT) {
+        return Err(format!("OCAP recording is longer than {} frames", MAX_FRAME_COUNT).into())
+    }
+
+    let mut entities: Vec<Entity> = json.get("entities")
+        .and_then(|e| e.as_array())
+        .ok_or("OCAP recording has no entities")?
+        .iter()
+        .filter_map(|raw| parse_entity(raw, frame_count, &mut report))
+        .collect();
+    entities.sort_by_key(|entity| entity.id);
+    link_crews(&mut entities);
+
+    let markers: Vec<Marker> = json.get("Markers")
+        .or_else(|| json.get("markers"))
+        .and_then(|m| m.as_array())
+        .map(|markers| markers.iter()
+            .enumerate()
+            .filter_map(|(id, raw)| parse_marker(id as u32, raw, &mut report))
+            .collect())
+        .unwrap_or_default();
+
+    let events: Vec<Event> = json.get("events")
+        .and_then(|e| e.as_array())
+        .map(|events| events.iter()
+            .filter_map(|raw| parse_event(raw, &mut report))
+            .collect())
+        .unwrap_or_default();
+
+    report.entities = entities.len();
+    report.markers = markers.len();
+    report.events = events.len();
+
+    let mut mission = Mission {
+        name: json.get("missionName").map(as_string).unwrap_or_default(),
+        world_name: json.get("worldName").map(as_string).unwrap_or_default(),
+        author: json.get("missionAuthor").map(as_string).unwrap_or_default(),
+        frame_interval: json.get("captureDelay").and_then(as_f32).unwrap_or(1.0),
+        frame_count: frame_count.unwrap_or(0),
+        entities,
+        markers,
+        events,
+        // OCAP does not record what each side knew
+        knowledge: Vec::new()
+    };
+    mission.normalise();
+
+    Ok((mission, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A recording of 10 frames with one unit and the vehicle positions given
+    fn recording(end_frame: Option<u32>, vehicle_positions: Value, events: Value) -> Vec<u8> {
+        let mut recording = json!({
+            "missionName": "Import test",
+            "worldName": "Altis",
+            "captureDelay": 1.0,
+            "entities": [
+                {
+                    "id": 0, "type": "unit", "name": "Rifleman", "side": "WEST", "group": "Alpha 1-1",
+                    "isPlayer": 1, "role": "Rifleman", "startFrameNum": 0,
+                    "positions": [[[100.0, 200.0, 0.0], 90, 1, 0, "Rifleman", 1], [[101.0, 200.0, 0.0], 90, 1, 0, "Rifleman", 1]]
+                },
+                {
+                    "id": 1, "type": "vehicle", "name": "Hunter", "class": "car", "startFrameNum": 2,
+                    "positions": vehicle_positions
+                }
+            ],
+            "events": events
+        });
+        if let Some(end_frame) = end_frame {
+            recording["endFrame"] = json!(end_frame);
+        }
+        serde_json::to_vec(&recording).unwrap()
+    }
+
+    fn vehicle_states(mission: &Mission) -> usize {
+        mission.entity(1).unwrap().states.len()
+    }
+
+    #[test]
+    fn imports_entities_and_events() {
+        let events = json!([[1, "killed", 0, ["null"], 0], [2, "generalEvent", "Hello"], [3, "unknownThing"]]);
+        let (mission, report) = import(&recording(Some(10), json!([[[0.0, 0.0], 0, 1, [0]]]), events)).unwrap();
+        assert_eq!(mission.world_name, "Altis");
+        assert_eq!(mission.entity(0).unwrap().side, Side::Blufor);
+        assert_eq!(mission.entity(0).unwrap().states.len(), 2);
+        assert_eq!(mission.entity(0).unwrap().states[0].vehicle, None);
+        assert_eq!(report.events, 2);
+        assert_eq!(report.unmapped_events.get("unknownThing"), Some(&1));
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn reads_gzipped_recordings() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &recording(Some(10), json!([]), json!([]))).unwrap();
+        let (mission, _) = import(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(mission.name, "Import test");
+    }
+
+    #[test]
+    fn expands_frame_ranges_of_stationary_vehicles() {
+        let positions = json!([[[0.0, 0.0], 0, 1, [0], [2, 4]], [[5.0, 0.0], 0, 1, [], [5, 5]]]);
+        let (mission, report) = import(&recording(Some(10), positions, json!([]))).unwrap();
+        assert_eq!(vehicle_states(&mission), 4);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn cuts_frame_ranges_off_at_the_end_of_the_recording() {
+        let positions = json!([[[0.0, 0.0], 0, 1, [], [2, 4294967295u32]]]);
+        let (mission, report) = import(&recording(Some(10), positions, json!([]))).unwrap();
+        assert_eq!(vehicle_states(&mission), 8);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn ignores_ranges_that_cannot_be_trusted() {
+        let backwards = json!([[[0.0, 0.0], 0, 1, [], [6, 3]]]);
+        let (mission, report) = import(&recording(Some(10), backwards, json!([]))).unwrap();
+        assert_eq!(vehicle_states(&mission), 1);
+        assert_eq!(report.warnings.len(), 1);
+
+        let unbounded = json!([[[0.0, 0.0], 0, 1, [], [2, 4294967295u32]]]);
+        let (mission, report) = import(&recording(None, unbounded, json!([]))).unwrap();
+        assert_eq!(vehicle_states(&mission), 1);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn overlapping_ranges_only_add_the_frames_not_yet_read() {
+        let positions = json!([[[0.0, 0.0], 0, 1, [], [2, 5]], [[1.0, 0.0], 0, 1, [], [3, 7]]]);
+        let (mission, report) = import(&recording(Some(10), positions, json!([]))).unwrap();
+        // frames 2 to 5 from the first entry, then 6 and 7 from the second
+        assert_eq!(vehicle_states(&mission), 6);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn refuses_recordings_too_long_to_hold() {
+        assert!(import(&recording(Some(MAX_FRAME_COUNT + 1), json!([]), json!([]))).is_err());
+    }
+
+    #[test]
+    fn reports_malformed_events() {
+        let events = json!([
+            "not an event",
+            [1],
+            ["one", "killed", 0],
+            [2, "killed", "victim", ["null"]],
+            [3, "hit", -1, [0, "gun"]],
+            [4, "killed", 4294967296u64, ["null"]],
+            [5, "killed", 0, [1, "HMG"], 30]
+        ]);
+        let (mission, report) = import(&recording(Some(10), json!([]), events)).unwrap();
+        assert_eq!(report.events, 1);
+        assert_eq!(mission.events.len(), 1);
+        assert_eq!(report.warnings.len(), 6);
+    }
+
+    #[test]
+    fn rejects_recordings_that_are_not_ocap() {
+        assert!(import(b"[1, 2, 3]").is_err());
+        assert!(import(b"{\"missionName\": \"No entities\"}").is_err());
+        assert!(import(b"not json").is_err());
+    }
+}
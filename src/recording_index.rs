@@ -0,0 +1,423 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Serialize;
+
+use crate::mission::{Mission, EntityId, EntityState, FrameNumber, Position};
+
+/// Frames between two stored keyframes. Reconstructing any frame never applies more deltas than this
+pub const KEYFRAME_INTERVAL: FrameNumber = 60;
+
+/// How far an entity or marker has to move or turn before a delta includes it. Changes in life
+/// state, vehicle or crew are always included
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaThresholds {
+    pub position: f32,
+    pub direction: f32
+}
+
+impl DeltaThresholds {
+    pub const EXACT: DeltaThresholds = DeltaThresholds {
+        position: 0.0,
+        direction: 0.0
+    };
+
+    fn moved(&self, from: &Position, to: &Position) -> bool {
+        let distance_squared = (from.x - to.x).powi(2) + (from.y - to.y).powi(2) + (from.z - to.z).powi(2);
+        distance_squared > self.position * self.position
+    }
+
+    fn turned(&self, from: f32, to: f32) -> bool {
+        let difference = (from - to).rem_euclid(360.0);
+        difference.min(360.0 - difference) > self.direction
+    }
+
+    fn entity_changed(&self, from: &EntityState, to: &EntityState) -> bool {
+        from.life != to.life
+            || from.vehicle != to.vehicle
+            || from.crew != to.crew
+            || self.moved(&from.position, &to.position)
+            || self.turned(from.direction, to.direction)
+    }
+
+    fn marker_changed(&self, from: &MarkerState, to: &MarkerState) -> bool {
+        from.alpha != to.alpha
+            || self.moved(&from.position, &to.position)
+            || self.turned(from.direction, to.direction)
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MarkerState {
+    pub position: Position,
+    pub direction: f32,
+    pub alpha: f32
+}
+
+/// Everything a viewer needs to draw a single frame: every entity and marker that exists in it
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct WorldState {
+    pub frame: FrameNumber,
+    pub entities: BTreeMap<EntityId, EntityState>,
+    pub markers: BTreeMap<u32, MarkerState>
+}
+
+/// Changes that turn the world state of one frame into the state of a later one
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct FrameDelta {
+    pub frame: FrameNumber,
+    pub entities: BTreeMap<EntityId, EntityState>,
+    pub removed_entities: BTreeSet<EntityId>,
+    pub markers: BTreeMap<u32, MarkerState>,
+    pub removed_markers: BTreeSet<u32>
+}
+
+impl FrameDelta {
+    /// Folds a later delta into this one so that applying the result equals applying both in order
+    fn merge(&mut self, later: &FrameDelta) {
+        self.frame = later.frame;
+        for (id, state) in &later.entities {
+            self.removed_entities.remove(id);
+            self.entities.insert(*id, state.clone());
+        }
+        for id in &later.removed_entities {
+            self.entities.remove(id);
+            self.removed_entities.insert(*id);
+        }
+        for (id, state) in &later.markers {
+            self.removed_markers.remove(id);
+            self.markers.insert(*id, state.clone());
+        }
+        for id in &later.removed_markers {
+            self.markers.remove(id);
+            self.removed_markers.insert(*id);
+        }
+    }
+}
+
+impl WorldState {
+    pub fn from_mission(mission: &Mission, frame: FrameNumber) -> WorldState {
+        WorldState {
+            frame,
+            entities: mission.entities.iter()
+                .filter_map(|entity| Some((entity.id, entity.state_at(frame)?.clone())))
+                .collect(),
+            markers: mission.markers.iter()
+                .filter_map(|marker| {
+                    let position = marker.position_at(frame)?;
+                    Some((marker.id, MarkerState {
+                        position: position.position,
+                        direction: position.direction,
+                        alpha: position.alpha
+                    }))
+                })
+                .collect()
+        }
+    }
+
+    /// Changes from this state to `next`, leaving out anything that moved less than `thresholds`
+    pub fn diff(&self, next: &WorldState, thresholds: &DeltaThresholds) -> FrameDelta {
+        FrameDelta {
+            frame: next.frame,
+            entities: next.entities.iter()
+                .filter(|(id, state)| self.entities.get(id).is_none_or(|previous| thresholds.entity_changed(previous, state)))
+                .map(|(id, state)| (*id, state.clone()))
+                .collect(),
+            removed_entities: self.entities.keys()
+                .filter(|id| !next.entities.contains_key(id))
+                .copied()
+                .collect(),
+            markers: next.markers.iter()
+                .filter(|(id, state)| self.markers.get(id).is_none_or(|previous| thresholds.marker_changed(previous, state)))
+                .map(|(id, state)| (*id, state.clone()))
+                .collect(),
+            removed_markers: self.markers.keys()
+                .filter(|id| !next.markers.contains_key(id))
+                .copied()
+                .collect()
+        }
+    }
+
+    pub fn apply(&mut self, delta: &FrameDelta) {
+        self.frame = delta.frame;
+        for id in &delta.removed_entities {
+            self.entities.remove(id);
+        }
+        for (id, state) in &delta.entities {
+            self.entities.insert(*id, state.clone());
+        }
+        for id in &delta.removed_markers {
+            self.markers.remove(id);
+        }
+        for (id, state) in &delta.markers {
+            self.markers.insert(*id, state.clone());
+        }
+    }
+}
+
+/// Keyframes every `KEYFRAME_INTERVAL` frames plus the delta into every frame, so any frame can
+/// be rebuilt from the closest keyframe without replaying the mission from the start
+#[derive(Clone)]
+pub struct RecordingIndex {
+    keyframes: Vec<WorldState>,
+    deltas: Vec<FrameDelta>,
+    // state of the last indexed frame, so a growing recording can be indexed as it arrives
+    latest: WorldState,
+    // bumped whenever frames already indexed change, so anything built on them can start over
+    revision: u64
+}
+
+impl RecordingIndex {
+    pub fn build(mission: &Mission) -> RecordingIndex {
+        let mut index = RecordingIndex {
+            keyframes: Vec::new(),
+            deltas: Vec::with_capacity(mission.frame_count as usize),
+            latest: WorldState::default(),
+            revision: 0
+        };
+        index.extend(mission);
+        index
+    }
+
+    /// Indexes frames of `mission` added since the index was last built or extended
+    pub fn extend(&mut self, mission: &Mission) {
+        for frame in self.deltas.len() as FrameNumber..mission.frame_count {
+            let state = WorldState::from_mission(mission, frame);
+            self.deltas.push(self.latest.diff(&state, &DeltaThresholds::EXACT));
+            if frame % KEYFRAME_INTERVAL == 0 {
+                self.keyframes.push(state.clone());
+            }
+            self.latest = state;
+        }
+    }
+
+    /// Indexes `mission` again from `frame` on, for a growing recording that changed frames it
+    /// already had
+    pub fn reindex_from(&mut self, mission: &Mission, frame: FrameNumber) {
+        if (frame as usize) < self.deltas.len() {
+            self.latest = match frame.checked_sub(1) {
+                Some(previous) => self.snapshot(previous),
+                None => WorldState::default()
+            };
+            self.deltas.truncate(frame as usize);
+            self.keyframes.truncate(frame.div_ceil(KEYFRAME_INTERVAL) as usize);
+            self.revision += 1;
+        }
+        self.extend(mission);
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn last_frame(&self) -> FrameNumber {
+        (self.deltas.len() as FrameNumber).saturating_sub(1)
+    }
+
+    pub fn snapshot(&self, frame: FrameNumber) -> WorldState {
+        let frame = frame.min(self.last_frame());
+        let keyframe_index = (frame / KEYFRAME_INTERVAL) as usize;
+        let mut state = match self.keyframes.get(keyframe_index) {
+            Some(keyframe) => keyframe.clone(),
+            None => return WorldState::default()
+        };
+
+        for delta in &self.deltas[state.frame as usize + 1..=frame as usize] {
+            state.apply(delta);
+        }
+        state
+    }
+
+    /// Combined changes from frame `from` to frame `to`
+    pub fn delta_between(&self, from: FrameNumber, to: FrameNumber) -> FrameDelta {
+        let to = to.min(self.last_frame());
+        let mut combined = FrameDelta {
+            frame: from,
+            ..FrameDelta::default()
+        };
+        for delta in self.deltas.iter().take(to as usize + 1).skip(from as usize + 1) {
+            combined.merge(delta);
+        }
+        combined
+    }
+}
+
+/// A loaded mission together with its seek index
+#[derive(Clone)]
+pub struct Recording {
+    pub mission: Mission,
+    pub index: RecordingIndex
+}
+
+impl Recording {
+    pub fn new(mission: Mission) -> Recording {
+        let index = RecordingIndex::build(&mission);
+        Recording {
+            mission,
+            index
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mission::{Entity, EntityKind, LifeState, Marker, MarkerPosition, Side};
+
+    fn state(x: f32, life: LifeState) -> EntityState {
+        EntityState {
+            position: Position { x, y: 100.0, z: 0.0 },
+            direction: x % 360.0,
+            life,
+            vehicle: None,
+            crew: Vec::new()
+        }
+    }
+
+    fn entity(id: EntityId, start_frame: FrameNumber, states: Vec<EntityState>) -> Entity {
+        Entity {
+            id,
+            kind: EntityKind::Unit,
+            name: format!("Unit {}", id),
+            side: Side::Blufor,
+            group: String::new(),
+            is_player: false,
+            class_name: String::new(),
+            start_frame,
+            states,
+            shots: Vec::new()
+        }
+    }
+
+    /// Spans several keyframes, with entities and a marker appearing, changing and vanishing
+    /// between them
+    fn mission() -> Mission {
+        let walker = (0..150)
+            .map(|frame| state(frame as f32 * 2.0, if frame < 120 { LifeState::Alive } else { LifeState::Dead }))
+            .collect();
+        let standing = (70..130).map(|_| state(500.0, LifeState::Alive)).collect();
+        let marker = Marker {
+            id: 7,
+            text: String::new(),
+            icon: "mil_dot".to_string(),
+            shape: String::new(),
+            brush: String::new(),
+            color: "ColorRed".to_string(),
+            size: [1.0, 1.0],
+            side: None,
+            author: None,
+            start_frame: 10,
+            end_frame: Some(100),
+            positions: [10, 50, 90].iter()
+                .map(|frame| MarkerPosition {
+                    frame: *frame,
+                    position: Position { x: *frame as f32, y: 0.0, z: 0.0 },
+                    direction: 0.0,
+                    alpha: 1.0
+                })
+                .collect()
+        };
+
+        Mission {
+            name: "Index test".to_string(),
+            world_name: "VR".to_string(),
+            author: String::new(),
+            frame_interval: 1.0,
+            frame_count: 150,
+            entities: vec![entity(1, 0, walker), entity(2, 70, standing)],
+            markers: vec![marker],
+            events: Vec::new(),
+            knowledge: Vec::new()
+        }
+    }
+
+    #[test]
+    fn snapshots_match_mission_state() {
+        let mission = mission();
+        let index = RecordingIndex::build(&mission);
+        for frame in 0..mission.frame_count {
+            assert_eq!(index.snapshot(frame), WorldState::from_mission(&mission, frame), "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn snapshot_past_the_end_is_the_last_frame() {
+        let mission = mission();
+        let index = RecordingIndex::build(&mission);
+        assert_eq!(index.last_frame(), 149);
+        assert_eq!(index.snapshot(1000), WorldState::from_mission(&mission, 149));
+    }
+
+    #[test]
+    fn combined_deltas_reach_the_later_frame() {
+        let mission = mission();
+        let index = RecordingIndex::build(&mission);
+        for (from, to) in [(0, 1), (0, 149), (5, 75), (65, 135), (95, 105), (119, 121)] {
+            let mut state = index.snapshot(from);
+            state.apply(&index.delta_between(from, to));
+            assert_eq!(state, index.snapshot(to), "{} to {}", from, to);
+        }
+    }
+
+    #[test]
+    fn extending_matches_building_at_once() {
+        let full = mission();
+        let mut partial = full.clone();
+        partial.frame_count = 70;
+
+        let mut index = RecordingIndex::build(&partial);
+        assert_eq!(index.last_frame(), 69);
+        index.extend(&full);
+        let built = RecordingIndex::build(&full);
+        for frame in 0..full.frame_count {
+            assert_eq!(index.snapshot(frame), built.snapshot(frame), "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn reindexing_matches_building_at_once() {
+        let full = mission();
+        let built = RecordingIndex::build(&full);
+        for from in [0, KEYFRAME_INTERVAL, KEYFRAME_INTERVAL + 5] {
+            let mut index = RecordingIndex::build(&full);
+            index.reindex_from(&full, from);
+            assert_eq!(index.revision(), 1);
+            for frame in 0..full.frame_count {
+                assert_eq!(index.snapshot(frame), built.snapshot(frame), "frame {} from {}", frame, from);
+            }
+        }
+
+        // nothing indexed yet from there on, so nothing changed
+        let mut index = RecordingIndex::build(&full);
+        index.reindex_from(&full, full.frame_count);
+        assert_eq!(index.revision(), 0);
+    }
+
+    #[test]
+    fn thresholds_skip_small_moves() {
+        let mission = mission();
+        let first = WorldState::from_mission(&mission, 0);
+        let second = WorldState::from_mission(&mission, 1);
+        let thresholds = DeltaThresholds {
+            position: 5.0,
+            direction: 5.0
+        };
+        assert!(first.diff(&second, &thresholds).entities.is_empty());
+        assert!(first.diff(&second, &DeltaThresholds::EXACT).entities.contains_key(&1));
+    }
+}
@@ -15,11 +15,224 @@
     You should have received a copy of the GNU General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+use std::fmt;
+
 use serde::Deserialize;
 
-#[derive(Deserialize, Debug)]
+use crate::annotation::AnnotationDraft;
+use crate::deep_link::Camera;
+use crate::follow::FollowTarget;
+use crate::visibility::VisibilityFilter;
+use crate::kill_feed::KillFilter;
+use crate::mission::{EntityId, EntityKind, EntityState, EventKind, FrameNumber, Knowledge, Marker, Position, Side};
+
+#[derive(Deserialize)]
 pub struct CreateLobby {
     pub lobby_id: String,
-    pub mission_id: String
+    pub mission_id: String,
+    /// Viewers have to pass this as the `password` query parameter when connecting
+    #[serde(default)]
+    pub password: Option<String>
+}
+
+// requests are logged, so only say whether a password was given
+impl fmt::Debug for CreateLobby {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreateLobby")
+            .field("lobby_id", &self.lobby_id)
+            .field("mission_id", &self.mission_id)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportOcap {
+    pub mission_id: String,
+    pub file_name: String
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddBookmark {
+    pub mission_id: String,
+    pub name: String,
+    pub mission_time: f64
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteBookmark {
+    pub mission_id: String,
+    pub bookmark_id: String
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateLink {
+    pub mission_id: String,
+    pub mission_time: f64,
+    pub camera: Camera,
+    #[serde(default)]
+    pub follow_unit: Option<EntityId>
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelapseFormat {
+    #[default]
+    Gif,
+    Apng
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateTimelapse {
+    pub mission_id: String,
+    pub start_time: f64,
+    pub end_time: f64,
+    /// Mission seconds between two frames of the timelapse
+    pub interval: f64,
+    /// `[min_x, min_y, max_x, max_y]` in metres, otherwise everything that moves during the timelapse
+    #[serde(default)]
+    pub bounds: Option<[f32; 4]>,
+    #[serde(default = "CreateTimelapse::default_width")]
+    pub width: u32,
+    #[serde(default)]
+    pub format: TimelapseFormat,
+    #[serde(default)]
+    pub names: bool,
+    #[serde(default)]
+    pub trail_seconds: f64,
+    /// How long each frame is shown for
+    #[serde(default = "CreateTimelapse::default_frame_delay")]
+    pub frame_delay_ms: u16
+}
+
+impl CreateTimelapse {
+    fn default_width() -> u32 {
+        640
+    }
+
+    fn default_frame_delay() -> u16 {
+        100
+    }
+}
+
+/// Commands a viewer sends over its websocket
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ViewerCommand {
+    /// Playback commands move the viewer's own clock instead of the lobby's while detached
+    Play,
+    Pause,
+    Seek { mission_time: f64 },
+    SetSpeed { speed: f64 },
+    SeekToBookmark { bookmark_id: String },
+    /// Continue from the lobby's current time on a clock of the viewer's own
+    Detach,
+    /// Go back to following the lobby's clock
+    Resync,
+    /// Lower how often frames are sent, for viewers on weak connections
+    SetTickRate { ticks_per_second: f64 },
+    Rename { name: String },
+    /// Tags frames with the target's state and sends extra updates for it in between
+    Follow { target: FollowTarget },
+    Unfollow,
+    /// Replaces the viewer's filter, an empty filter shows everything
+    SetFilter { filter: VisibilityFilter },
+    /// Kills are sent as playback reaches them, only those matching this filter
+    SetKillFilter { filter: KillFilter },
+    /// Without a mission time the message refers to the time the viewer is watching
+    Chat { text: String, mission_time: Option<f64> },
+    AddAnnotation { annotation: AnnotationDraft },
+    /// Only the author of an annotation may edit or delete it
+    EditAnnotation { annotation_id: String, annotation: AnnotationDraft },
+    DeleteAnnotation { annotation_id: String }
+}
+
+impl ViewerCommand {
+    /// Binary encodings can carry NaN and infinities, which no clock or tick rate can use
+    pub fn is_valid(&self) -> bool {
+        match self {
+            ViewerCommand::Seek { mission_time } => mission_time.is_finite(),
+            ViewerCommand::SetSpeed { speed } => speed.is_finite(),
+            ViewerCommand::SetTickRate { ticks_per_second } => ticks_per_second.is_finite(),
+            ViewerCommand::Chat { mission_time, .. } => mission_time.is_none_or(f64::is_finite),
+            _ => true
+        }
+    }
+}
+
+/// An entity a live source is about to send states for
+#[derive(Deserialize, Debug)]
+pub struct LiveEntity {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    pub name: String,
+    pub side: Side,
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub is_player: bool,
+    #[serde(default)]
+    pub class_name: String
 }
 
+#[derive(Deserialize, Debug)]
+pub struct LiveEntityState {
+    pub id: EntityId,
+    #[serde(flatten)]
+    pub state: EntityState
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LiveMarkerMove {
+    pub id: u32,
+    pub position: Position,
+    #[serde(default)]
+    pub direction: f32,
+    /// Fully opaque when missing
+    #[serde(default)]
+    pub alpha: Option<f32>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LiveShot {
+    pub entity: EntityId,
+    pub target: Position
+}
+
+/// Everything that happened during one frame of a live mission. An entity that is no longer
+/// sent ends with its last state; one that shows up again after missing frames is held where it
+/// was last seen in between
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LiveFrame {
+    pub frame: FrameNumber,
+    pub entities: Vec<LiveEntityState>,
+    pub markers: Vec<LiveMarkerMove>,
+    pub removed_markers: Vec<u32>,
+    pub shots: Vec<LiveShot>,
+    pub events: Vec<EventKind>
+}
+
+/// Messages a game server pushes to the ingest endpoint
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IngestMessage {
+    /// Starts a live mission, or picks it back up after the source reconnected
+    Start {
+        mission_id: String,
+        /// Lobby viewers join to watch, the mission id when missing
+        #[serde(default)]
+        lobby_id: Option<String>,
+        name: String,
+        world_name: String,
+        #[serde(default)]
+        author: String,
+        frame_interval: f32
+    },
+    Entity(LiveEntity),
+    Marker { marker: Marker },
+    Knowledge { knowledge: Knowledge },
+    Frame(LiveFrame),
+    /// The mission is over and gets saved as a normal recording
+    End
+}
@@ -18,6 +18,17 @@
 use serde::Serialize;
 use hyper::StatusCode;
 
+use crate::ocap::ImportReport;
+use crate::mission::{Mission, Entity, Marker, EntityId, EntityKind, FrameNumber, Side};
+use crate::recording_index::{WorldState, FrameDelta};
+use crate::annotation::Annotation;
+use crate::follow::{FollowedUnit, FollowEvent};
+use crate::visibility::VisibilityFilter;
+use crate::kill_feed::{Kill, KillFilter};
+use crate::mission_store::Bookmark;
+use crate::terrain::Terrain;
+use crate::deep_link::Camera;
+
 pub enum Response<T> {
     Info((StatusCode, Option<T>)),
     Success((StatusCode, Option<T>)),
@@ -50,19 +61,288 @@ pub struct LobbyCreated {
 }
 impl CanRespond for LobbyCreated {}
 
+/// Why a websocket connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRejection {
+    BadRequest,
+    UnknownLobby,
+    LobbyFull,
+    Unauthorized,
+    ProtocolMismatch
+}
+
+impl ConnectionRejection {
+    /// Application close codes live in the 4000-4999 range reserved for private use
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ConnectionRejection::BadRequest => 4000,
+            ConnectionRejection::Unauthorized => 4001,
+            ConnectionRejection::ProtocolMismatch => 4002,
+            ConnectionRejection::UnknownLobby => 4004,
+            ConnectionRejection::LobbyFull => 4009
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ConnectionRejection::Unauthorized => StatusCode::UNAUTHORIZED,
+            ConnectionRejection::LobbyFull => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_REQUEST
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct WebSocketFailedConnection {
     pub valid: bool,
-    pub message: String
+    pub message: String,
+    pub close_code: u16,
+    #[serde(skip)]
+    pub status_code: StatusCode
 }
 
 impl WebSocketFailedConnection {
-    pub fn new(msg: &str) -> WebSocketFailedConnection {
+    pub fn new(reason: ConnectionRejection, msg: &str) -> WebSocketFailedConnection {
         WebSocketFailedConnection {
             valid: false,
-            message: msg.to_string()
+            message: msg.to_string(),
+            close_code: reason.close_code(),
+            status_code: reason.status_code()
         }
     }
 }
 impl CanRespond for WebSocketFailedConnection {}
 
+#[derive(Serialize, Debug)]
+pub struct MissionImported {
+    pub valid: bool,
+    pub mission_id: String,
+    pub message: String,
+    pub report: Option<ImportReport>
+}
+impl CanRespond for MissionImported {}
+
+#[derive(Serialize, Debug)]
+pub struct BookmarksUpdated {
+    pub valid: bool,
+    pub mission_id: String,
+    pub message: String,
+    pub bookmarks: Vec<Bookmark>
+}
+impl CanRespond for BookmarksUpdated {}
+
+#[derive(Serialize, Debug)]
+pub struct LinkCreated {
+    pub valid: bool,
+    pub message: String,
+    pub code: String,
+    pub url: String
+}
+impl CanRespond for LinkCreated {}
+
+#[derive(Serialize, Debug)]
+pub struct TimelapseCreated {
+    pub valid: bool,
+    pub message: String,
+    pub job_id: String,
+    pub status_url: String
+}
+impl CanRespond for TimelapseCreated {}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Failed
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimelapseStatus {
+    pub job_id: String,
+    pub mission_id: String,
+    pub state: JobState,
+    pub frames_done: u32,
+    pub frames_total: u32,
+    /// Fraction of frames rendered, from 0 to 1
+    pub progress: f32,
+    pub message: String,
+    /// Where the result can be fetched once the job has finished
+    pub download_url: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+pub struct EntityInfo {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    pub name: String,
+    pub side: Side,
+    pub group: String,
+    pub is_player: bool,
+    pub class_name: String
+}
+
+impl EntityInfo {
+    pub fn new(entity: &Entity) -> EntityInfo {
+        EntityInfo {
+            id: entity.id,
+            kind: entity.kind.clone(),
+            name: entity.name.clone(),
+            side: entity.side,
+            group: entity.group.clone(),
+            is_player: entity.is_player,
+            class_name: entity.class_name.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct MarkerInfo {
+    pub id: u32,
+    pub text: String,
+    pub icon: String,
+    pub shape: String,
+    pub brush: String,
+    pub color: String,
+    pub size: [f32; 2],
+    pub side: Option<Side>
+}
+
+impl MarkerInfo {
+    pub fn new(marker: &Marker) -> MarkerInfo {
+        MarkerInfo {
+            id: marker.id,
+            text: marker.text.clone(),
+            icon: marker.icon.clone(),
+            shape: marker.shape.clone(),
+            brush: marker.brush.clone(),
+            color: marker.color.clone(),
+            size: marker.size,
+            side: marker.side
+        }
+    }
+}
+
+/// Static description of a mission sent once when a viewer connects. Frames only carry the
+/// changing state of entities and markers
+#[derive(Serialize, Debug)]
+pub struct MissionInfo {
+    pub name: String,
+    pub world_name: String,
+    pub author: String,
+    pub duration: f64,
+    pub frame_interval: f32,
+    pub entities: Vec<EntityInfo>,
+    pub markers: Vec<MarkerInfo>,
+    /// Left out when the server doesn't know the terrain
+    pub terrain: Option<Terrain>
+}
+
+impl MissionInfo {
+    pub fn new(mission: &Mission, terrain: Option<&Terrain>) -> MissionInfo {
+        MissionInfo {
+            name: mission.name.clone(),
+            world_name: mission.world_name.clone(),
+            author: mission.author.clone(),
+            duration: mission.duration_seconds(),
+            frame_interval: mission.frame_interval,
+            entities: mission.entities.iter().map(EntityInfo::new).collect(),
+            markers: mission.markers.iter().map(MarkerInfo::new).collect(),
+            terrain: terrain.cloned()
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RosterEntry {
+    pub viewer_id: String,
+    pub name: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub viewer_id: String,
+    pub name: String,
+    pub text: String,
+    /// Point in the mission the message is about
+    pub mission_time: f64
+}
+
+/// Messages streamed to a viewer over its websocket
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ViewerMessage {
+    /// First message on every connection. The token lets the viewer resume after a dropped
+    /// connection by reconnecting with `?resume=<token>`
+    Welcome { protocol_version: u32, viewer_id: String, session_token: String, resumed: bool },
+    MissionInfo(MissionInfo),
+    /// Entities and markers the viewer had not been told about and can now see, because they
+    /// appeared in a live mission or the viewer's filter changed
+    EntitiesAdded { entities: Vec<EntityInfo> },
+    MarkersAdded { markers: Vec<MarkerInfo> },
+    /// How much of a live mission viewers can see and how far it trails the game in seconds,
+    /// `live` is false once it ended
+    LiveProgress { duration: f64, delay: f64, live: bool },
+    Roster { viewers: Vec<RosterEntry> },
+    ViewerJoined { viewer: RosterEntry },
+    ViewerLeft { viewer_id: String },
+    ViewerRenamed { viewer_id: String, name: String },
+    Chat(ChatMessage),
+    ChatHistory { messages: Vec<ChatMessage> },
+    ChatRejected { reason: String },
+    Annotations { annotations: Vec<Annotation> },
+    AnnotationAdded { annotation: Annotation },
+    AnnotationEdited { annotation: Annotation },
+    AnnotationDeleted { annotation_id: String },
+    AnnotationRejected { reason: String },
+    Bookmarks { bookmarks: Vec<Bookmark> },
+    /// Where the deep link the viewer opened points the map
+    Camera { camera: Camera },
+    /// `detached` is set while the viewer plays back on its own clock
+    Playback { playing: bool, speed: f64, mission_time: f64, detached: bool },
+    Snapshot {
+        mission_time: f64,
+        state: WorldState,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        followed: Vec<FollowedUnit>
+    },
+    Delta {
+        mission_time: f64,
+        delta: FrameDelta,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        followed: Vec<FollowedUnit>
+    },
+    Filter { filter: VisibilityFilter },
+    KillFilter { filter: KillFilter },
+    Kill(Kill),
+    Following { units: Vec<EntityId> },
+    FollowRejected { reason: String },
+    FollowUpdate { mission_time: f64, units: Vec<FollowedUnit> },
+    FollowEvent {
+        mission_time: f64,
+        unit: EntityId,
+        #[serde(flatten)]
+        event: FollowEvent
+    }
+}
+
+impl ViewerMessage {
+    /// Frames a later snapshot makes up for. These are the only messages dropped for a viewer
+    /// that falls behind
+    pub fn is_replaceable(&self) -> bool {
+        matches!(self,
+            ViewerMessage::Snapshot { .. } | ViewerMessage::Delta { .. }
+            | ViewerMessage::Playback { .. } | ViewerMessage::FollowUpdate { .. })
+    }
+}
+
+/// Replies to a live source on the ingest endpoint
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IngestReply {
+    /// `next_frame` tells a reconnecting source where to carry on from
+    Started { mission_id: String, lobby_id: String, next_frame: FrameNumber, resumed: bool },
+    Rejected { message: String },
+    Ended { mission_id: String }
+}
@@ -41,7 +41,7 @@ pub enum StaticFileStorage {
 }
 
 pub enum StaticFile {
-    HTML(StaticFileStorage),
+    Html(StaticFileStorage),
     JavaScript(StaticFileStorage)
 }
 
@@ -74,12 +74,10 @@ impl StaticFile {
     }
 
     fn serve(&self, root_path: &Path) -> Result<Response<Body>, std::io::Error> {
-        let response = match self {
-            StaticFile::HTML(storage_type) => StaticFile::serve_html(storage_type, root_path),
+        match self {
+            StaticFile::Html(storage_type) => StaticFile::serve_html(storage_type, root_path),
             StaticFile::JavaScript(storage_type) => StaticFile::serve_js(storage_type, root_path)
-        };
-
-        response
+        }
     }
 }
 
@@ -94,7 +92,7 @@ impl StaticServer {
         StaticServer {
             root_path: root_file_offset.into(),
             static_files: HashMap::new(),
-            static_404: StaticFile::HTML(StaticFileStorage::Memory(STATIC_404_PAGE.as_bytes().to_vec()))
+            static_404: StaticFile::Html(StaticFileStorage::Memory(STATIC_404_PAGE.as_bytes().to_vec()))
         }
     }
 
@@ -119,7 +117,7 @@ impl StaticServer {
         let true_path = path.into();
         
         let file = self.static_files.get(&true_path);
-        if let None = file {
+        if file.is_none() {
             warn!(target: "StaticServer", "Attempting to GET file that has not been registered: {:?}", true_path.into_os_string());
             return self.serve_404()
         }
@@ -0,0 +1,374 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::canvas::{Canvas, Colour, Point, RasterCanvas, SvgCanvas, MAX_IMAGE_SIZE};
+use crate::mission::{Entity, EntityKind, EntityState, FrameNumber, LifeState, Mission, Position};
+use crate::potato_types::Error;
+
+pub const MAX_TRAIL_SECONDS: f64 = 600.0;
+/// Snapshots drawn at once, requests past this are turned away until one finishes
+pub const MAX_CONCURRENT_RENDERS: usize = 4;
+/// Smallest area a snapshot shows, in metres, so a lone unit isn't drawn across the whole image
+const MIN_EXTENT: f32 = 200.0;
+/// Largest area a snapshot shows, in metres, comfortably more than the largest terrains
+pub const MAX_EXTENT: f32 = 100_000.0;
+/// How far from the origin an area may lie, in metres. Further out, f32 positions are too
+/// coarse to step across
+const MAX_COORDINATE: f32 = 1_000_000.0;
+/// Grid lines drawn in each direction at most
+const MAX_GRID_LINES: i64 = 200;
+
+const BACKGROUND: Colour = Colour::new(236, 232, 218, 255);
+const GRID: Colour = Colour::new(0, 0, 0, 40);
+const DEAD: Colour = Colour::new(110, 110, 110, 200);
+const OUTLINE: Colour = Colour::new(0, 0, 0, 255);
+const TEXT: Colour = Colour::new(20, 20, 20, 255);
+
+/// Part of the world to draw, in metres
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32
+}
+
+impl Bounds {
+    /// Reads `min_x,min_y,max_x,max_y`
+    pub fn parse(text: &str) -> Option<Bounds> {
+        let values: Vec<f32> = text.split(',').map(|value| value.parse().ok()).collect::<Option<_>>()?;
+        match values[..] {
+            [min_x, min_y, max_x, max_y] => Some(Bounds { min_x, min_y, max_x, max_y }).filter(Bounds::is_valid),
+            _ => None
+        }
+    }
+
+    /// Finite, not empty, no larger than `MAX_EXTENT` and close enough to the map to draw
+    pub fn is_valid(&self) -> bool {
+        [self.min_x, self.min_y, self.max_x, self.max_y].iter().all(|value| value.abs() <= MAX_COORDINATE)
+            && self.min_x < self.max_x
+            && self.min_y < self.max_y
+            && self.width() <= MAX_EXTENT
+            && self.height() <= MAX_EXTENT
+    }
+
+    /// Smallest area holding every position with a margin around it
+    pub fn around(positions: impl Iterator<Item = Position>) -> Option<Bounds> {
+        let bounds = positions.fold(None, |bounds: Option<Bounds>, position| Some(match bounds {
+            Some(bounds) => Bounds {
+                min_x: bounds.min_x.min(position.x),
+                min_y: bounds.min_y.min(position.y),
+                max_x: bounds.max_x.max(position.x),
+                max_y: bounds.max_y.max(position.y)
+            },
+            None => Bounds { min_x: position.x, min_y: position.y, max_x: position.x, max_y: position.y }
+        }))?;
+
+        let margin_x = ((bounds.width() * 1.2).max(MIN_EXTENT) - bounds.width()) / 2.0;
+        let margin_y = ((bounds.height() * 1.2).max(MIN_EXTENT) - bounds.height()) / 2.0;
+        Some(Bounds {
+            min_x: bounds.min_x - margin_x,
+            min_y: bounds.min_y - margin_y,
+            max_x: bounds.max_x + margin_x,
+            max_y: bounds.max_y + margin_y
+        })
+    }
+
+    pub fn width(&self) -> f32 {
+        self.max_x - self.min_x
+    }
+
+    pub fn height(&self) -> f32 {
+        self.max_y - self.min_y
+    }
+
+    /// Image height that keeps the area's proportions at the given width
+    pub fn image_height(&self, width: u32) -> u32 {
+        ((width as f32 * self.height() / self.width()).round() as u32).max(1)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotOptions {
+    pub mission_time: f64,
+    pub names: bool,
+    /// How far back movement trails reach, 0 for none
+    pub trail_seconds: f64,
+    /// Area to draw, otherwise everything on the map at the time
+    pub bounds: Option<Bounds>,
+    pub width: u32
+}
+
+/// The situation on the map at one moment, drawn from recording data
+pub struct Snapshot<'a> {
+    mission: &'a Mission,
+    frame: FrameNumber,
+    options: SnapshotOptions,
+    bounds: Bounds,
+    height: u32
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn new(mission: &'a Mission, options: SnapshotOptions) -> Result<Snapshot<'a>, Error> {
+        let frame = mission.frame_at(options.mission_time);
+        let bounds = options.bounds
+            .or_else(|| Bounds::around(Snapshot::positions(mission, frame)))
+            .unwrap_or(Bounds { min_x: 0.0, min_y: 0.0, max_x: 1000.0, max_y: 1000.0 });
+        // recordings can hold stray positions far off the map, which would make the area too large
+        if !bounds.is_valid() {
+            return Err("Area is too large to draw".into())
+        }
+
+        let height = bounds.image_height(options.width);
+        if !(1..=MAX_IMAGE_SIZE).contains(&options.width) || height > MAX_IMAGE_SIZE {
+            return Err("Image would be too large".into())
+        }
+
+        Ok(Snapshot {
+            mission,
+            frame,
+            options,
+            bounds,
+            height
+        })
+    }
+
+    /// Area covering everything on the map over several frames
+    pub fn area(mission: &Mission, frames: impl Iterator<Item = FrameNumber>) -> Option<Bounds> {
+        Bounds::around(frames.flat_map(|frame| Snapshot::positions(mission, frame)))
+    }
+
+    /// Where everything drawn at this frame is
+    fn positions(mission: &Mission, frame: FrameNumber) -> impl Iterator<Item = Position> + '_ {
+        let entities = mission.entities.iter()
+            .filter_map(move |entity| entity.state_at(frame))
+            .map(|state| state.position);
+        let markers = mission.markers.iter()
+            .filter_map(move |marker| marker.position_at(frame))
+            .map(|position| position.position);
+        entities.chain(markers)
+    }
+
+    pub fn rasterise(&self) -> Result<RasterCanvas, Error> {
+        let mut canvas = RasterCanvas::new(self.options.width, self.height)?;
+        self.draw(&mut canvas);
+        Ok(canvas)
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        self.rasterise()?.to_png()
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut canvas = SvgCanvas::new(self.options.width, self.height);
+        self.draw(&mut canvas);
+        canvas.finish()
+    }
+
+    fn project(&self, position: &Position) -> Point {
+        let scale = self.scale();
+        (
+            (position.x - self.bounds.min_x) * scale,
+            self.height as f32 - (position.y - self.bounds.min_y) * scale
+        )
+    }
+
+    /// Pixels per metre
+    fn scale(&self) -> f32 {
+        self.options.width as f32 / self.bounds.width()
+    }
+
+    pub fn draw(&self, canvas: &mut impl Canvas) {
+        canvas.fill(BACKGROUND);
+        self.draw_grid(canvas);
+        self.draw_markers(canvas, false);
+
+        let drawn: Vec<(&Entity, &EntityState)> = self.mission.entities.iter()
+            .filter_map(|entity| Some((entity, entity.state_at(self.frame)?)))
+            .filter(|(_, state)| state.vehicle.is_none())
+            .collect();
+
+        if self.options.trail_seconds > 0.0 {
+            for (entity, _) in &drawn {
+                self.draw_trail(canvas, entity);
+            }
+        }
+        // vehicles first so units standing next to them stay visible
+        for (entity, state) in drawn.iter().filter(|(entity, _)| entity.kind == EntityKind::Vehicle) {
+            self.draw_vehicle(canvas, entity, state);
+        }
+        for (entity, state) in drawn.iter().filter(|(entity, _)| entity.kind == EntityKind::Unit) {
+            self.draw_unit(canvas, entity, state);
+        }
+
+        self.draw_markers(canvas, true);
+        canvas.text((6.0, 6.0), &format_time(self.mission.time_of_frame(self.frame)), TEXT);
+    }
+
+    fn draw_grid(&self, canvas: &mut impl Canvas) {
+        // the finest of 100m, 1km or 10km that leaves lines at least 40 pixels apart
+        let spacing = [100.0, 1000.0, 10000.0].into_iter()
+            .find(|spacing| spacing * self.scale() >= 40.0)
+            .unwrap_or(100000.0);
+
+        // counted in whole lines rather than stepping a float, which could fail to advance
+        let lines = |min: f32, max: f32| {
+            let first = (min / spacing).ceil() as i64;
+            (first..first + MAX_GRID_LINES)
+                .map(move |line| line as f32 * spacing)
+                .take_while(move |at| *at < max)
+        };
+        for x in lines(self.bounds.min_x, self.bounds.max_x) {
+            let (left, _) = self.project(&Position { x, y: 0.0, z: 0.0 });
+            canvas.polyline(&[(left, 0.0), (left, self.height as f32)], GRID, 1.0);
+        }
+        for y in lines(self.bounds.min_y, self.bounds.max_y) {
+            let (_, top) = self.project(&Position { x: 0.0, y, z: 0.0 });
+            canvas.polyline(&[(0.0, top), (self.options.width as f32, top)], GRID, 1.0);
+        }
+    }
+
+    fn draw_trail(&self, canvas: &mut impl Canvas, entity: &Entity) {
+        let start = self.mission.frame_at(self.mission.time_of_frame(self.frame) - self.options.trail_seconds);
+        let points: Vec<Point> = (start.max(entity.start_frame)..=self.frame)
+            .filter_map(|frame| entity.state_at(frame))
+            .map(|state| self.project(&state.position))
+            .collect();
+        canvas.polyline(&points, self.colour(entity, entity.state_at(self.frame)).with_alpha(130), 2.0);
+    }
+
+    fn draw_vehicle(&self, canvas: &mut impl Canvas, entity: &Entity, state: &EntityState) {
+        let centre = self.project(&state.position);
+        canvas.rectangle(centre, (5.0, 8.0), state.direction, self.colour(entity, Some(state)), OUTLINE);
+
+        if self.options.names {
+            let label = match state.crew.len() {
+                0 => entity.name.clone(),
+                crew => format!("{} ({})", entity.name, crew)
+            };
+            canvas.text((centre.0 + 10.0, centre.1 - 4.0), &label, TEXT);
+        }
+    }
+
+    fn draw_unit(&self, canvas: &mut impl Canvas, entity: &Entity, state: &EntityState) {
+        let centre = self.project(&state.position);
+        let colour = self.colour(entity, Some(state));
+        if state.life != LifeState::Dead {
+            let heading = state.direction.to_radians();
+            let tip = (centre.0 + heading.sin() * 9.0, centre.1 - heading.cos() * 9.0);
+            canvas.polyline(&[centre, tip], colour, 2.0);
+        }
+        canvas.circle(centre, 4.0, colour, OUTLINE);
+
+        if self.options.names {
+            canvas.text((centre.0 + 7.0, centre.1 - 4.0), &entity.name, TEXT);
+        }
+    }
+
+    /// Area markers go under everything else, icons and their labels on top
+    fn draw_markers(&self, canvas: &mut impl Canvas, icons: bool) {
+        let scale = self.scale();
+        for marker in &self.mission.markers {
+            let position = match marker.position_at(self.frame) {
+                Some(position) if position.alpha > 0.0 => position,
+                _ => continue
+            };
+            let centre = self.project(&position.position);
+            let colour = Colour::marker(&marker.color);
+            let alpha = (position.alpha.clamp(0.0, 1.0) * 255.0) as u8;
+
+            let shape = marker.shape.to_ascii_uppercase();
+            match (shape.as_str(), icons) {
+                ("RECTANGLE", false) => {
+                    let half_size = (marker.size[0] * scale, marker.size[1] * scale);
+                    canvas.rectangle(centre, half_size, position.direction, colour.with_alpha(alpha / 3), colour.with_alpha(alpha));
+                },
+                ("ELLIPSE", false) => {
+                    let radii = (marker.size[0] * scale, marker.size[1] * scale);
+                    canvas.ellipse(centre, radii, position.direction, colour.with_alpha(alpha / 3), colour.with_alpha(alpha));
+                },
+                ("RECTANGLE" | "ELLIPSE" | "POLYLINE", _) => {},
+                (_, true) => {
+                    canvas.rectangle(centre, (4.0, 4.0), 45.0, colour.with_alpha(alpha), OUTLINE.with_alpha(alpha));
+                    if !marker.text.is_empty() {
+                        canvas.text((centre.0 + 8.0, centre.1 - 4.0), &marker.text, colour.with_alpha(alpha));
+                    }
+                },
+                (_, false) => {}
+            }
+        }
+    }
+
+    /// Side colour, taken from the crew for vehicles
+    fn colour(&self, entity: &Entity, state: Option<&EntityState>) -> Colour {
+        let state = match state {
+            Some(state) => state,
+            None => return Colour::side(entity.side)
+        };
+        if state.life == LifeState::Dead {
+            return DEAD
+        }
+
+        let side = state.crew.first()
+            .and_then(|crew| self.mission.entity(*crew))
+            .map(|crew| crew.side)
+            .unwrap_or(entity.side);
+        Colour::side(side)
+    }
+}
+
+/// `hh:mm:ss` mission time
+pub fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_must_be_finite_and_drawable() {
+        assert!(Bounds::parse("0,0,1000,2000").is_some());
+        assert!(Bounds::parse("0,0,inf,1000").is_none());
+        assert!(Bounds::parse("NaN,0,1000,1000").is_none());
+        assert!(Bounds::parse("0,0,1000000,1000").is_none());
+        assert!(Bounds::parse("1e30,1e30,1.00001e30,1.00001e30").is_none());
+        assert!(Bounds::parse("1000,0,0,1000").is_none());
+    }
+
+    #[test]
+    fn names_are_written_as_valid_xml() {
+        let mission: Mission = serde_json::from_value(serde_json::json!({
+            "name": "Snapshot test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 1,
+            "entities": [{
+                "id": 1, "kind": "unit", "name": "Bad\u{1}Name <&>", "side": "blufor", "group": "Alpha", "start_frame": 0,
+                "states": [{ "position": { "x": 500.0, "y": 500.0 }, "direction": 0.0, "life": "alive" }]
+            }]
+        })).unwrap();
+        let options = SnapshotOptions { mission_time: 0.0, names: true, trail_seconds: 0.0, bounds: None, width: 256 };
+        let svg = Snapshot::new(&mission, options).unwrap().to_svg();
+        assert!(svg.contains("BadName &lt;&amp;&gt;"));
+        assert!(!svg.contains('\u{1}'));
+
+        let too_wide = SnapshotOptions { mission_time: 0.0, names: false, trail_seconds: 0.0, bounds: None, width: MAX_IMAGE_SIZE + 1 };
+        assert!(Snapshot::new(&mission, too_wide).is_err());
+    }
+}
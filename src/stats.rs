@@ -0,0 +1,289 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::mission::{Entity, EntityId, EntityKind, EntityState, EventKind, FrameNumber, LifeState, Mission, Side};
+use crate::potato_types::Error;
+
+/// Counters shared by unit and group statistics. Distances are in metres and times in seconds
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Tally {
+    pub kills: u32,
+    pub deaths: u32,
+    /// Kills of units on the same side
+    pub friendly_fire: u32,
+    pub shots_fired: u32,
+    pub hits_taken: u32,
+    pub distance_on_foot: f32,
+    pub distance_in_vehicle: f32,
+    pub time_alive: f64,
+    pub vehicle_time: f64
+}
+
+impl Tally {
+    const CSV_HEADERS: [&'static str; 9] = [
+        "kills", "deaths", "friendly_fire", "shots_fired", "hits_taken",
+        "distance_on_foot", "distance_in_vehicle", "time_alive", "vehicle_time"
+    ];
+
+    fn add(&mut self, other: &Tally) {
+        self.kills += other.kills;
+        self.deaths += other.deaths;
+        self.friendly_fire += other.friendly_fire;
+        self.shots_fired += other.shots_fired;
+        self.hits_taken += other.hits_taken;
+        self.distance_on_foot += other.distance_on_foot;
+        self.distance_in_vehicle += other.distance_in_vehicle;
+        self.time_alive += other.time_alive;
+        self.vehicle_time += other.vehicle_time;
+    }
+
+    fn csv_fields(&self) -> [String; 9] {
+        [
+            self.kills.to_string(),
+            self.deaths.to_string(),
+            self.friendly_fire.to_string(),
+            self.shots_fired.to_string(),
+            self.hits_taken.to_string(),
+            format!("{:.1}", self.distance_on_foot),
+            format!("{:.1}", self.distance_in_vehicle),
+            format!("{:.1}", self.time_alive),
+            format!("{:.1}", self.vehicle_time)
+        ]
+    }
+
+    /// Movement and time spent alive or mounted, taken from the unit's recorded states
+    fn from_states(entity: &Entity, frame_interval: f64) -> Tally {
+        let mut tally = Tally {
+            shots_fired: entity.shots.len() as u32,
+            ..Tally::default()
+        };
+
+        let mut previous: Option<&EntityState> = None;
+        for state in &entity.states {
+            if state.life != LifeState::Dead {
+                tally.time_alive += frame_interval;
+                if state.vehicle.is_some() {
+                    tally.vehicle_time += frame_interval;
+                }
+                if let Some(previous) = previous.filter(|previous| previous.life != LifeState::Dead) {
+                    let distance = previous.position.distance_2d(&state.position);
+                    if state.vehicle.is_some() {
+                        tally.distance_in_vehicle += distance;
+                    } else {
+                        tally.distance_on_foot += distance;
+                    }
+                }
+            }
+            previous = Some(state);
+        }
+
+        tally
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UnitStats {
+    pub id: EntityId,
+    pub name: String,
+    pub side: Side,
+    pub group: String,
+    pub is_player: bool,
+    #[serde(flatten)]
+    pub tally: Tally
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupStats {
+    pub side: Side,
+    pub group: String,
+    pub members: Vec<EntityId>,
+    #[serde(flatten)]
+    pub tally: Tally
+}
+
+/// After action statistics for every unit in a recording, and for the groups they belong to
+#[derive(Serialize, Clone, Debug)]
+pub struct Statistics {
+    pub units: Vec<UnitStats>,
+    pub groups: Vec<GroupStats>
+}
+
+impl Statistics {
+    /// Vehicles keep no tally of their own, so their kills go to the first unit riding in them
+    /// at the time, which is the driver or commander in most recordings
+    pub fn credited_unit(mission: &Mission, killer: EntityId, frame: FrameNumber) -> Option<&Entity> {
+        let entity = mission.entity(killer)?;
+        match entity.kind {
+            EntityKind::Unit => Some(entity),
+            EntityKind::Vehicle => {
+                // a vehicle destroyed in the same frame has no state left for it
+                let state = entity.state_at(frame).or_else(|| entity.state_at(frame.checked_sub(1)?))?;
+                mission.entity(*state.crew.first()?)
+            }
+        }
+    }
+
+    pub fn new(mission: &Mission) -> Statistics {
+        let frame_interval = mission.frame_interval as f64;
+        let mut tallies: BTreeMap<EntityId, Tally> = mission.entities.iter()
+            .filter(|entity| entity.kind == EntityKind::Unit)
+            .map(|entity| (entity.id, Tally::from_states(entity, frame_interval)))
+            .collect();
+
+        for event in &mission.events {
+            match &event.kind {
+                EventKind::Killed { victim, killer, .. } => {
+                    if let Some(tally) = tallies.get_mut(victim) {
+                        tally.deaths += 1;
+                    }
+                    let killer = killer.and_then(|killer| Statistics::credited_unit(mission, killer, event.frame));
+                    let (victim, killer) = match (mission.entity(*victim), killer) {
+                        (Some(victim), Some(killer)) if killer.id != victim.id => (victim, killer),
+                        _ => continue
+                    };
+                    if let Some(tally) = tallies.get_mut(&killer.id) {
+                        if killer.side == victim.side && killer.side != Side::Unknown {
+                            tally.friendly_fire += 1;
+                        } else {
+                            tally.kills += 1;
+                        }
+                    }
+                },
+                EventKind::Hit { victim, .. } => {
+                    if let Some(tally) = tallies.get_mut(victim) {
+                        tally.hits_taken += 1;
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let units: Vec<UnitStats> = tallies.into_iter()
+            .filter_map(|(id, tally)| {
+                let entity = mission.entity(id)?;
+                Some(UnitStats {
+                    id,
+                    name: entity.name.clone(),
+                    side: entity.side,
+                    group: entity.group.clone(),
+                    is_player: entity.is_player,
+                    tally
+                })
+            })
+            .collect();
+
+        let mut groups: BTreeMap<(Side, &str), GroupStats> = BTreeMap::new();
+        for unit in units.iter().filter(|unit| !unit.group.is_empty()) {
+            let group = groups.entry((unit.side, unit.group.as_str())).or_insert_with(|| GroupStats {
+                side: unit.side,
+                group: unit.group.clone(),
+                members: Vec::new(),
+                tally: Tally::default()
+            });
+            group.members.push(unit.id);
+            group.tally.add(&unit.tally);
+        }
+        let groups = groups.into_values().collect();
+
+        Statistics {
+            units,
+            groups
+        }
+    }
+
+    pub fn units_csv(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(["id", "name", "side", "group", "is_player"].iter().chain(Tally::CSV_HEADERS.iter()))?;
+        for unit in &self.units {
+            let mut record = vec![
+                unit.id.to_string(),
+                unit.name.clone(),
+                unit.side.name().to_string(),
+                unit.group.clone(),
+                unit.is_player.to_string()
+            ];
+            record.extend(unit.tally.csv_fields());
+            writer.write_record(&record)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    pub fn groups_csv(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(["side", "group", "members"].iter().chain(Tally::CSV_HEADERS.iter()))?;
+        for group in &self.groups {
+            let mut record = vec![
+                group.side.name().to_string(),
+                group.group.clone(),
+                group.members.len().to_string()
+            ];
+            record.extend(group.tally.csv_fields());
+            writer.write_record(&record)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Unit 1 rides in vehicle 10 and the vehicle is recorded as killing unit 2, then unit 3
+    /// in the same frame the vehicle is destroyed
+    fn mission() -> Mission {
+        let state = |crew: Vec<u32>| json!({ "position": { "x": 0.0, "y": 0.0, "z": 0.0 }, "direction": 0.0, "life": "alive", "crew": crew });
+        let unit = |id: u32, side: &str, frames: usize| json!({
+            "id": id, "kind": "unit", "name": format!("Unit {}", id), "side": side, "group": "Alpha",
+            "start_frame": 0, "states": vec![state(Vec::new()); frames]
+        });
+        serde_json::from_value(json!({
+            "name": "Stats test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 5,
+            "entities": [
+                unit(1, "blufor", 5),
+                unit(2, "opfor", 5),
+                unit(3, "blufor", 5),
+                {
+                    "id": 10, "kind": "vehicle", "name": "Hunter", "side": "unknown",
+                    "start_frame": 0, "states": vec![state(vec![1]); 4]
+                }
+            ],
+            "events": [
+                { "frame": 2, "type": "killed", "victim": 2, "killer": 10, "weapon": "HMG", "distance": 50.0 },
+                { "frame": 4, "type": "killed", "victim": 3, "killer": 10, "weapon": "HMG", "distance": 5.0 }
+            ]
+        })).unwrap()
+    }
+
+    #[test]
+    fn vehicle_kills_go_to_the_crew() {
+        let statistics = Statistics::new(&mission());
+        let unit = |id| &statistics.units.iter().find(|unit| unit.id == id).unwrap().tally;
+        assert_eq!(unit(1).kills, 1);
+        assert_eq!(unit(1).friendly_fire, 1);
+        assert_eq!(unit(2).deaths, 1);
+        assert_eq!(unit(3).deaths, 1);
+        assert!(statistics.units.iter().all(|unit| unit.id != 10));
+    }
+}
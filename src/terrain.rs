@@ -0,0 +1,208 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    collections::HashMap
+};
+
+use serde::{Serialize, Deserialize};
+use log::{info, warn};
+
+use crate::config::GeoReference;
+use crate::mission::Position;
+
+/// How map grid numbers relate to world coordinates. Most terrains count from the south west
+/// corner, older ones count northings down from the top of the map
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct GridSystem {
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// Northings grow going south, counted from `offset_y`
+    pub inverted: bool
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GridPrecision {
+    /// 100 metres
+    Six,
+    /// 10 metres
+    #[default]
+    Eight,
+    /// 1 metre
+    Ten
+}
+
+impl GridPrecision {
+    pub fn from_digits(digits: u32) -> Option<GridPrecision> {
+        match digits {
+            6 => Some(GridPrecision::Six),
+            8 => Some(GridPrecision::Eight),
+            10 => Some(GridPrecision::Ten),
+            _ => None
+        }
+    }
+
+    fn digits_per_axis(&self) -> u32 {
+        match self {
+            GridPrecision::Six => 3,
+            GridPrecision::Eight => 4,
+            GridPrecision::Ten => 5
+        }
+    }
+}
+
+impl GridSystem {
+    /// Grid reference such as `0461 1873`, easting first
+    pub fn reference(&self, position: &Position, precision: GridPrecision) -> String {
+        let digits = precision.digits_per_axis();
+        let metres_per_step = 10f32.powi(5 - digits as i32);
+        let wrap = 10i64.pow(digits);
+
+        let easting = position.x - self.offset_x;
+        let northing = match self.inverted {
+            true => self.offset_y - position.y,
+            false => position.y - self.offset_y
+        };
+        let step = |metres: f32| ((metres / metres_per_step).floor() as i64).rem_euclid(wrap);
+        format!("{:0width$} {:0width$}", step(easting), step(northing), width = digits as usize)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ElevationRange {
+    pub min: f32,
+    pub max: f32
+}
+
+/// What the viewer and exports need to know about a map
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Terrain {
+    /// Name the game uses, matched without regard to case
+    pub world_name: String,
+    #[serde(default)]
+    pub display_name: String,
+    /// Width and height in metres; terrains are square
+    pub size: f32,
+    #[serde(default)]
+    pub grid: GridSystem,
+    /// Tiles the viewer draws the map with
+    #[serde(default)]
+    pub tile_set: String,
+    /// Lowest and highest ground in metres
+    pub elevation: ElevationRange,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_reference: Option<GeoReference>
+}
+
+/// Terrains known to the server, read from a directory of JSON files with one terrain each
+pub struct TerrainRegistry {
+    terrains: HashMap<String, Arc<Terrain>>
+}
+
+impl TerrainRegistry {
+    pub fn load(directory: impl AsRef<Path>) -> TerrainRegistry {
+        let directory = directory.as_ref();
+        let mut terrains = HashMap::new();
+
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(target: "terrain", "Cannot read terrains from {:?}: {}", directory, e);
+                return TerrainRegistry { terrains }
+            }
+        };
+
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue
+            }
+            let terrain: Terrain = match fs::read(&path).map_err(|e| e.to_string())
+                .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string())) {
+                Ok(terrain) => terrain,
+                Err(e) => {
+                    warn!(target: "terrain", "Skipping terrain {:?}: {}", path, e);
+                    continue
+                }
+            };
+            terrains.insert(terrain.world_name.to_ascii_lowercase(), Arc::new(terrain));
+        }
+
+        info!(target: "terrain", "Loaded {} terrains from {:?}", terrains.len(), directory);
+        TerrainRegistry { terrains }
+    }
+
+    pub fn get(&self, world_name: &str) -> Option<Arc<Terrain>> {
+        self.terrains.get(&world_name.to_ascii_lowercase()).cloned()
+    }
+
+    /// Grid of a terrain, none when the terrain is unknown
+    pub fn grid(&self, world_name: &str) -> Option<GridSystem> {
+        self.get(world_name).map(|terrain| terrain.grid)
+    }
+
+    pub fn all(&self) -> Vec<&Terrain> {
+        let mut terrains: Vec<&Terrain> = self.terrains.values().map(|terrain| terrain.as_ref()).collect();
+        terrains.sort_by(|a, b| a.world_name.cmp(&b.world_name));
+        terrains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, y: f32) -> Position {
+        Position { x, y, z: 0.0 }
+    }
+
+    #[test]
+    fn references_at_each_precision() {
+        let grid = GridSystem::default();
+        let position = at(4612.3, 18734.9);
+        assert_eq!(grid.reference(&position, GridPrecision::Six), "046 187");
+        assert_eq!(grid.reference(&position, GridPrecision::Eight), "0461 1873");
+        assert_eq!(grid.reference(&position, GridPrecision::Ten), "04612 18734");
+    }
+
+    #[test]
+    fn references_wrap_past_the_largest_number() {
+        let grid = GridSystem::default();
+        assert_eq!(grid.reference(&at(123_456.0, 0.0), GridPrecision::Six), "234 000");
+        assert_eq!(grid.reference(&at(105_000.0, 0.0), GridPrecision::Eight), "0500 0000");
+        assert_eq!(grid.reference(&at(100_001.0, 0.0), GridPrecision::Ten), "00001 00000");
+        // off the south west edge counts back from the top
+        assert_eq!(grid.reference(&at(-50.0, -1.0), GridPrecision::Eight), "9995 9999");
+    }
+
+    #[test]
+    fn inverted_grids_count_northings_down() {
+        let grid = GridSystem { offset_x: 0.0, offset_y: 15360.0, inverted: true };
+        assert_eq!(grid.reference(&at(200.0, 15000.0), GridPrecision::Eight), "0020 0036");
+        assert_eq!(grid.reference(&at(200.0, 15360.0), GridPrecision::Eight), "0020 0000");
+        assert_eq!(grid.reference(&at(200.0, 0.0), GridPrecision::Six), "002 153");
+    }
+
+    #[test]
+    fn offsets_move_the_origin() {
+        let grid = GridSystem { offset_x: 1000.0, offset_y: 2000.0, inverted: false };
+        assert_eq!(grid.reference(&at(1250.0, 2990.0), GridPrecision::Eight), "0025 0099");
+    }
+}
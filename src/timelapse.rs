@@ -0,0 +1,383 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use bytes::Bytes;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+use log::{info, warn};
+
+use crate::mission::Mission;
+use crate::recording_index::Recording;
+use crate::requests::{CreateTimelapse, TimelapseFormat};
+use crate::responses::{JobState, TimelapseStatus};
+use crate::snapshot::{Bounds, Snapshot, SnapshotOptions, MAX_TRAIL_SECONDS};
+use crate::potato_types::Error;
+
+const MAX_FRAMES: usize = 600;
+const MAX_WIDTH: u32 = 1280;
+/// Jobs rendering at the same time, the rest wait their turn
+const CONCURRENT_JOBS: usize = 2;
+/// How long a finished timelapse can be downloaded for
+const RESULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Jobs queued or rendering at once, further requests are turned away
+const MAX_PENDING_JOBS: usize = 16;
+/// Jobs kept in memory at once, counting finished ones whose results have not expired
+const MAX_STORED_JOBS: usize = 64;
+
+/// Why a timelapse was not queued
+#[derive(Debug)]
+pub enum TimelapseRejection {
+    Invalid(Error),
+    Busy
+}
+
+impl TimelapseRejection {
+    pub fn status_code(&self) -> hyper::StatusCode {
+        match self {
+            TimelapseRejection::Invalid(_) => hyper::StatusCode::BAD_REQUEST,
+            TimelapseRejection::Busy => hyper::StatusCode::TOO_MANY_REQUESTS
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            TimelapseRejection::Invalid(e) => e.to_string(),
+            TimelapseRejection::Busy => "Too many timelapses, try again later".to_string()
+        }
+    }
+}
+
+impl TimelapseFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TimelapseFormat::Gif => "image/gif",
+            TimelapseFormat::Apng => "image/apng"
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TimelapseFormat::Gif => "gif",
+            TimelapseFormat::Apng => "png"
+        }
+    }
+}
+
+/// What to render, worked out and checked before a job is queued
+struct Plan {
+    times: Vec<f64>,
+    options: SnapshotOptions,
+    format: TimelapseFormat,
+    frame_delay_ms: u16
+}
+
+impl Plan {
+    fn new(mission: &Mission, params: &CreateTimelapse) -> Result<Plan, Error> {
+        if !(params.interval > 0.0 && params.interval.is_finite()) {
+            return Err("Interval must be positive".into())
+        }
+        if !(0.0 <= params.start_time && params.start_time <= params.end_time && params.end_time <= mission.duration_seconds()) {
+            return Err("Timelapse is outside of the mission".into())
+        }
+        // counted as a float first, a tiny interval would overflow the cast
+        let frames = ((params.end_time - params.start_time) / params.interval).floor() + 1.0;
+        if frames > MAX_FRAMES as f64 {
+            return Err(format!("Timelapse would have more than the {} frames allowed", MAX_FRAMES).into())
+        }
+        let frames = frames as usize;
+        if !(1..=MAX_WIDTH).contains(&params.width) {
+            return Err(format!("Width must be between 1 and {}", MAX_WIDTH).into())
+        }
+        let times: Vec<f64> = (0..frames).map(|frame| params.start_time + frame as f64 * params.interval).collect();
+
+        // every frame shows the same area so the picture doesn't jump around
+        let bounds = match params.bounds {
+            Some([min_x, min_y, max_x, max_y]) => Some(Bounds { min_x, min_y, max_x, max_y })
+                .filter(Bounds::is_valid)
+                .ok_or("Bounds are invalid")?,
+            None => {
+                let start = mission.frame_at(params.start_time);
+                let end = mission.frame_at(params.end_time);
+                Snapshot::area(mission, start..=end).unwrap_or(Bounds { min_x: 0.0, min_y: 0.0, max_x: 1000.0, max_y: 1000.0 })
+            }
+        };
+
+        let options = SnapshotOptions {
+            mission_time: params.start_time,
+            names: params.names,
+            trail_seconds: params.trail_seconds.clamp(0.0, MAX_TRAIL_SECONDS),
+            bounds: Some(bounds),
+            width: params.width
+        };
+        // fails early if the image would be too tall
+        Snapshot::new(mission, options.clone())?;
+
+        Ok(Plan {
+            times,
+            options,
+            format: params.format,
+            frame_delay_ms: params.frame_delay_ms
+        })
+    }
+
+    fn snapshot<'a>(&self, mission: &'a Mission, mission_time: f64) -> Result<Snapshot<'a>, Error> {
+        Snapshot::new(mission, SnapshotOptions { mission_time, ..self.options.clone() })
+    }
+
+    /// Renders every frame, reporting after each one
+    fn render(&self, mission: &Mission, progress: impl Fn(u32)) -> Result<Vec<u8>, Error> {
+        match self.format {
+            TimelapseFormat::Gif => self.render_gif(mission, progress),
+            TimelapseFormat::Apng => self.render_apng(mission, progress)
+        }
+    }
+
+    fn render_gif(&self, mission: &Mission, progress: impl Fn(u32)) -> Result<Vec<u8>, Error> {
+        let mut encoder: Option<gif::Encoder<Vec<u8>>> = None;
+        for (index, time) in self.times.iter().enumerate() {
+            let canvas = self.snapshot(mission, *time)?.rasterise()?;
+            let (width, height) = (canvas.width() as u16, canvas.height() as u16);
+
+            let encoder = match &mut encoder {
+                Some(encoder) => encoder,
+                None => {
+                    let mut new_encoder = gif::Encoder::new(Vec::new(), width, height, &[])?;
+                    new_encoder.set_repeat(gif::Repeat::Infinite)?;
+                    encoder.insert(new_encoder)
+                }
+            };
+
+            let mut pixels = canvas.pixels().to_vec();
+            let mut frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, 10);
+            frame.delay = (self.frame_delay_ms / 10).max(1);
+            encoder.write_frame(&frame)?;
+            progress(index as u32 + 1);
+        }
+
+        let encoder = encoder.ok_or("Timelapse has no frames")?;
+        Ok(encoder.into_inner()?)
+    }
+
+    fn render_apng(&self, mission: &Mission, progress: impl Fn(u32)) -> Result<Vec<u8>, Error> {
+        let first = self.snapshot(mission, self.options.mission_time)?.rasterise()?;
+
+        let mut apng = Vec::new();
+        let mut encoder = png::Encoder::new(&mut apng, first.width(), first.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(self.times.len() as u32, 0)?;
+        encoder.set_frame_delay(self.frame_delay_ms, 1000)?;
+        let mut writer = encoder.write_header()?;
+
+        for (index, time) in self.times.iter().enumerate() {
+            let canvas = match index {
+                0 => None,
+                _ => Some(self.snapshot(mission, *time)?.rasterise()?)
+            };
+            writer.write_image_data(canvas.as_ref().unwrap_or(&first).pixels())?;
+            progress(index as u32 + 1);
+        }
+        writer.finish()?;
+        Ok(apng)
+    }
+}
+
+struct Job {
+    mission_id: String,
+    format: TimelapseFormat,
+    state: JobState,
+    frames_done: u32,
+    frames_total: u32,
+    message: String,
+    result: Option<Bytes>,
+    finished_at: Option<Instant>
+}
+
+/// Timelapses rendering in the background, and their results until they expire
+#[derive(Clone)]
+pub struct TimelapseJobs {
+    jobs: Arc<Mutex<HashMap<Uuid, Job>>>,
+    permits: Arc<Semaphore>
+}
+
+impl TimelapseJobs {
+    pub fn new() -> TimelapseJobs {
+        TimelapseJobs {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(CONCURRENT_JOBS))
+        }
+    }
+
+    /// Queues a timelapse of the recording, or says why it can't be made
+    pub fn start(&self, recording: Arc<Recording>, params: &CreateTimelapse) -> Result<Uuid, TimelapseRejection> {
+        let plan = Plan::new(&recording.mission, params).map_err(TimelapseRejection::Invalid)?;
+        let job_id = Uuid::new_v4();
+
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            TimelapseJobs::purge_expired(&mut jobs, Instant::now());
+            let pending = jobs.values().filter(|job| job.finished_at.is_none()).count();
+            if pending >= MAX_PENDING_JOBS || jobs.len() >= MAX_STORED_JOBS {
+                return Err(TimelapseRejection::Busy)
+            }
+            jobs.insert(job_id, Job {
+                mission_id: params.mission_id.clone(),
+                format: plan.format,
+                state: JobState::Queued,
+                frames_done: 0,
+                frames_total: plan.times.len() as u32,
+                message: String::new(),
+                result: None,
+                finished_at: None
+            });
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let _permit = match this.permits.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return
+            };
+            this.update(&job_id, |job| job.state = JobState::Running);
+            info!(target: "timelapse", "Rendering timelapse {} with {} frames", job_id, plan.times.len());
+
+            let renderer = this.clone();
+            let rendered = tokio::task::spawn_blocking(move || {
+                plan.render(&recording.mission, |frames_done| renderer.update(&job_id, |job| job.frames_done = frames_done))
+            }).await;
+
+            this.update(&job_id, |job| {
+                job.finished_at = Some(Instant::now());
+                match rendered {
+                    Ok(Ok(result)) => {
+                        job.state = JobState::Finished;
+                        job.result = Some(Bytes::from(result));
+                    },
+                    Ok(Err(e)) => {
+                        warn!(target: "timelapse", "Timelapse {} failed: {:?}", job_id, e);
+                        job.state = JobState::Failed;
+                        job.message = e.to_string();
+                    },
+                    Err(e) => {
+                        warn!(target: "timelapse", "Timelapse {} failed: {:?}", job_id, e);
+                        job.state = JobState::Failed;
+                        job.message = "Rendering stopped unexpectedly".to_string();
+                    }
+                }
+            });
+        });
+
+        Ok(job_id)
+    }
+
+    /// Drops finished jobs whose results are past their lifetime. Runs whenever jobs are looked
+    /// at, so nothing expired is ever handed out
+    fn purge_expired(jobs: &mut HashMap<Uuid, Job>, now: Instant) {
+        jobs.retain(|_, job| job.finished_at.is_none_or(|finished| now.saturating_duration_since(finished) < RESULT_LIFETIME));
+    }
+
+    fn update(&self, job_id: &Uuid, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            update(job);
+        }
+    }
+
+    pub fn status(&self, job_id: &Uuid) -> Option<TimelapseStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        TimelapseJobs::purge_expired(&mut jobs, Instant::now());
+        let job = jobs.get(job_id)?;
+        Some(TimelapseStatus {
+            job_id: job_id.to_string(),
+            mission_id: job.mission_id.clone(),
+            state: job.state,
+            frames_done: job.frames_done,
+            frames_total: job.frames_total,
+            progress: job.frames_done as f32 / job.frames_total.max(1) as f32,
+            message: job.message.clone(),
+            download_url: job.result.as_ref().map(|_| format!("/timelapse/{}/download", job_id))
+        })
+    }
+
+    /// The rendered timelapse once the job has finished
+    pub fn result(&self, job_id: &Uuid) -> Option<(TimelapseFormat, Bytes)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        TimelapseJobs::purge_expired(&mut jobs, Instant::now());
+        let job = jobs.get(job_id)?;
+        Some((job.format, job.result.clone()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn finished_job() -> Job {
+        Job {
+            mission_id: "test".to_string(),
+            format: TimelapseFormat::Gif,
+            state: JobState::Finished,
+            frames_done: 1,
+            frames_total: 1,
+            message: String::new(),
+            result: Some(Bytes::from_static(b"GIF89a")),
+            finished_at: Some(Instant::now())
+        }
+    }
+
+    #[test]
+    fn results_expire_after_their_lifetime() {
+        let timelapses = TimelapseJobs::new();
+        let (finished, running) = (Uuid::new_v4(), Uuid::new_v4());
+        timelapses.jobs.lock().unwrap().insert(finished, finished_job());
+        timelapses.jobs.lock().unwrap().insert(running, Job { finished_at: None, result: None, state: JobState::Running, ..finished_job() });
+        assert_eq!(timelapses.result(&finished).unwrap().1, Bytes::from_static(b"GIF89a"));
+        assert!(timelapses.result(&running).is_none());
+
+        let later = Instant::now() + RESULT_LIFETIME;
+        TimelapseJobs::purge_expired(&mut timelapses.jobs.lock().unwrap(), later);
+        assert!(timelapses.result(&finished).is_none());
+        assert!(timelapses.status(&finished).is_none());
+        assert_eq!(timelapses.status(&running).unwrap().state, JobState::Running);
+    }
+
+    #[test]
+    fn plans_stay_inside_the_mission_and_the_frame_limit() {
+        let mission: Mission = serde_json::from_value(json!({
+            "name": "Timelapse test", "world_name": "VR", "frame_interval": 1.0, "frame_count": 100, "entities": []
+        })).unwrap();
+        let params = |extra: serde_json::Value| -> CreateTimelapse {
+            let mut value = json!({ "mission_id": "test", "start_time": 0.0, "end_time": 50.0, "interval": 10.0, "bounds": [0.0, 0.0, 100.0, 100.0] });
+            value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            serde_json::from_value(value).unwrap()
+        };
+
+        assert_eq!(Plan::new(&mission, &params(json!({}))).unwrap().times, [0.0, 10.0, 20.0, 30.0, 40.0, 50.0]);
+        assert!(Plan::new(&mission, &params(json!({ "end_time": 200.0 }))).is_err());
+        assert!(Plan::new(&mission, &params(json!({ "start_time": 60.0 }))).is_err());
+        assert!(Plan::new(&mission, &params(json!({ "interval": 0.0 }))).is_err());
+        assert!(Plan::new(&mission, &params(json!({ "interval": 1e-300 }))).is_err());
+        assert!(Plan::new(&mission, &params(json!({ "width": MAX_WIDTH + 1 }))).is_err());
+        assert!(Plan::new(&mission, &params(json!({ "bounds": [100.0, 0.0, 0.0, 100.0] }))).is_err());
+    }
+}
@@ -16,7 +16,7 @@
     along with this program.  if not, see <https://www.gnu.org/licenses/>.
 */
 use std::collections::HashMap;
-use hyper::{Uri, http::uri::PathAndQuery};
+use hyper::Uri;
 
 pub fn query_to_hash_map(uri: &Uri) -> HashMap<&str, &str> {
     if uri.query().is_none() {
@@ -39,3 +39,86 @@ pub fn query_to_hash_map(uri: &Uri) -> HashMap<&str, &str> {
     return_map
 }
 
+/// Decodes `%XX` escapes and `+` in a query value
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' if index + 2 < bytes.len() => {
+                // from_str_radix would also take a sign, so check the digits first
+                let hex = &bytes[index + 1..index + 3];
+                let escaped = hex.iter().all(u8::is_ascii_hexdigit)
+                    .then(|| u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap());
+                match escaped {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        index += 2;
+                    },
+                    None => decoded.push(b'%')
+                }
+            },
+            byte => decoded.push(byte)
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes text for XML, dropping the control characters XML 1.0 has no way to write at all
+pub fn escape_xml(text: &str) -> String {
+    text.chars()
+        .filter(|c| matches!(c, '\t' | '\n' | '\r') || (*c >= ' ' && !matches!(c, '\u{FFFE}' | '\u{FFFF}')))
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes_and_spaces() {
+        assert_eq!(percent_decode("Alpha+1-1"), "Alpha 1-1");
+        assert_eq!(percent_decode("a%20b%2Fc%2fd"), "a b/c/d");
+        assert_eq!(percent_decode("%41"), "A");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+        assert_eq!(percent_decode("%+1"), "% 1");
+        assert_eq!(percent_decode("%-1"), "%-1");
+        assert_eq!(percent_decode("%%41"), "%A");
+        assert_eq!(percent_decode("%é"), "%é");
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        assert_eq!(percent_decode("%FFok"), "\u{fffd}ok");
+    }
+
+    #[test]
+    fn splits_query_values() {
+        let uri: Uri = "/connect?lobby-id=abc&name=&flag".parse().unwrap();
+        let queries = query_to_hash_map(&uri);
+        assert_eq!(queries.get("lobby-id"), Some(&"abc"));
+        assert_eq!(queries.get("name"), Some(&""));
+        assert_eq!(queries.get("flag"), Some(&""));
+        assert!(query_to_hash_map(&"/connect".parse().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn xml_escapes_drop_unwritable_characters() {
+        assert_eq!(escape_xml("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+        assert_eq!(escape_xml("Alpha\u{0}\u{1b}[1\tBravo\u{FFFF}"), "Alpha[1\tBravo");
+    }
+}
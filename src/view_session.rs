@@ -18,11 +18,21 @@
 use std::{
     str::FromStr,
     time::{Duration, Instant},
-    collections::HashMap
+    sync::Arc,
+    collections::{HashMap, VecDeque}
 };
 use uuid::Uuid;
 
-use log::{info, warn, error, debug};
+use crate::potato_types::Error;
+use crate::recording_index::Recording;
+use crate::viewer::{ViewerPreferences, ViewerHandle};
+use crate::responses::{ViewerMessage, RosterEntry, ChatMessage};
+use crate::live::{LiveMission, LiveUpdate};
+use crate::requests::IngestMessage;
+use crate::annotation::{Annotation, AnnotationDraft};
+use crate::mission_store::Bookmark;
+
+const MAX_ANNOTATIONS: usize = 500;
 
 /* TODO
  * Generate unique websocket group
@@ -35,30 +45,313 @@ use log::{info, warn, error, debug};
 // A single mission being viewed. Has a UUID and a list of viewers of which we stream to
 /// Updates in it's own thread, websockets will read into mission data to figure out next event to
 /// send
-struct ViewSession {
-    current_time: Instant,
+#[derive(Clone)]
+pub struct ViewSession {
+    // mission time is tracked relative to the last moment the clock was changed
+    anchor_instant: Instant,
+    anchor_time: f64,
+    duration: f64,
+    playing: bool,
+    speed: f64,
+    seek_count: u64,
 }
 
 impl ViewSession {
-    fn new() -> ViewSession {
+    fn new(duration: f64) -> ViewSession {
         ViewSession {
-            current_time: Instant::now(),
+            anchor_instant: Instant::now(),
+            anchor_time: 0.0,
+            duration,
+            playing: false,
+            speed: 1.0,
+            seek_count: 0,
+        }
+    }
+
+    /// Current mission time in seconds
+    pub fn current_time(&self) -> f64 {
+        if !self.playing {
+            return self.anchor_time
         }
+        let elapsed = self.anchor_instant.elapsed().as_secs_f64() * self.speed;
+        (self.anchor_time + elapsed).min(self.duration)
+    }
+
+    fn reanchor(&mut self) {
+        self.anchor_time = self.current_time();
+        self.anchor_instant = Instant::now();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Incremented on every seek so viewers know their last frame no longer follows on
+    pub fn seek_count(&self) -> u64 {
+        self.seek_count
+    }
+
+    pub fn play(&mut self) {
+        self.reanchor();
+        self.playing = true;
     }
+
+    pub fn pause(&mut self) {
+        self.reanchor();
+        self.playing = false;
+    }
+
+    pub fn seek(&mut self, mission_time: f64) {
+        self.anchor_time = mission_time.clamp(0.0, self.duration);
+        self.anchor_instant = Instant::now();
+        self.seek_count += 1;
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.reanchor();
+        self.speed = speed.clamp(0.1, 64.0);
+    }
+
+    /// Live missions grow as they are received. A clock held at the end carries on from there
+    pub fn set_duration(&mut self, duration: f64) {
+        self.duration = duration;
+    }
+}
+
+/// A viewer that disconnected recently and may come back with its session token
+struct SuspendedViewer {
+    viewer_id: Uuid,
+    preferences: ViewerPreferences,
+    expires: Instant,
 }
 
-struct Lobby {
+pub struct Lobby {
     view_session: ViewSession,
     unique_id: Uuid,
+    mission_id: String,
+    recording: Arc<Recording>,
+    bookmarks: Vec<Bookmark>,
+    password: Option<String>,
+    // keyed by viewer id, session tokens are secret and never shared with other viewers
+    viewers: HashMap<Uuid, ViewerHandle>,
+    suspended_viewers: HashMap<Uuid, SuspendedViewer>,
+    chat_history: VecDeque<ChatMessage>,
+    annotations: Vec<Annotation>,
+    live: Option<LiveMission>,
 }
 
 impl Lobby {
-    fn new() -> Lobby {
+    fn new(mission_id: String, recording: Arc<Recording>, bookmarks: Vec<Bookmark>, password: Option<String>) -> Lobby {
         Lobby {
-            view_session: ViewSession::new(),
+            view_session: ViewSession::new(recording.mission.duration_seconds()),
             unique_id: Uuid::new_v4(),
+            mission_id,
+            recording,
+            bookmarks,
+            password,
+            viewers: HashMap::new(),
+            suspended_viewers: HashMap::new(),
+            chat_history: VecDeque::new(),
+            annotations: Vec::new(),
+            live: None,
         }
     }
+
+    pub fn is_live(&self) -> bool {
+        self.live.is_some()
+    }
+
+    pub fn live(&self) -> Option<&LiveMission> {
+        self.live.as_ref()
+    }
+
+    pub fn live_mut(&mut self) -> Option<&mut LiveMission> {
+        self.live.as_mut()
+    }
+
+    /// Adds data from the live source to the recording and tells viewers what changed. The
+    /// lobby stops being live once the source ends the mission
+    pub fn ingest(&mut self, message: IngestMessage) -> Result<LiveUpdate, Error> {
+        let live = self.live.as_mut().ok_or("Lobby is not live")?;
+        let update = live.receive(Arc::make_mut(&mut self.recording), message)?;
+        self.live_updated(&update);
+        Ok(update)
+    }
+
+    /// Lets through whatever the live broadcast delay was holding back
+    pub fn release_delayed(&mut self) {
+        if let Some(live) = self.live.as_mut() {
+            let update = live.release(Arc::make_mut(&mut self.recording));
+            self.live_updated(&update);
+        }
+    }
+
+    fn live_updated(&mut self, update: &LiveUpdate) {
+        let mission = &self.recording.mission;
+        if update.ended {
+            self.live = None;
+        }
+        if update.frames > 0 || update.ended {
+            let duration = mission.duration_seconds();
+            self.view_session.set_duration(duration);
+            self.broadcast(&ViewerMessage::LiveProgress {
+                duration,
+                delay: self.live.as_ref().map_or(0.0, |live| live.delay().as_secs_f64()),
+                live: !update.ended
+            });
+        }
+    }
+
+    pub fn accepts_password(&self, password: Option<&str>) -> bool {
+        match &self.password {
+            Some(lobby_password) => password == Some(lobby_password.as_str()),
+            None => true
+        }
+    }
+
+    pub fn viewer_count(&self) -> usize {
+        self.viewers.len()
+    }
+
+    pub fn add_viewer(&mut self, viewer_id: Uuid, handle: ViewerHandle) {
+        self.viewers.insert(viewer_id, handle);
+    }
+
+    pub fn rename_viewer(&mut self, viewer_id: &Uuid, name: &str) {
+        if let Some(viewer) = self.viewers.get_mut(viewer_id) {
+            viewer.name = name.to_string();
+        }
+    }
+
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        let mut roster: Vec<RosterEntry> = self.viewers.iter()
+            .map(|(viewer_id, viewer)| RosterEntry {
+                viewer_id: viewer_id.to_string(),
+                name: viewer.name.clone(),
+            })
+            .collect();
+        roster.sort_by(|a, b| a.name.cmp(&b.name));
+        roster
+    }
+
+    /// Sends a message to every connected viewer
+    pub fn broadcast(&self, message: &ViewerMessage) {
+        for viewer in self.viewers.values() {
+            viewer.send(message);
+        }
+    }
+
+    pub fn send_to(&self, viewer_id: &Uuid, message: &ViewerMessage) {
+        if let Some(viewer) = self.viewers.get(viewer_id) {
+            viewer.send(message);
+        }
+    }
+
+    /// Relays a chat message to everyone and keeps the last `history_size` for late joiners
+    pub fn post_chat(&mut self, message: ChatMessage, history_size: usize) {
+        self.broadcast(&ViewerMessage::Chat(message.clone()));
+        self.chat_history.push_back(message);
+        while self.chat_history.len() > history_size {
+            self.chat_history.pop_front();
+        }
+    }
+
+    pub fn chat_history(&self) -> Vec<ChatMessage> {
+        self.chat_history.iter().cloned().collect()
+    }
+
+    pub fn annotations(&self) -> Vec<Annotation> {
+        self.annotations.clone()
+    }
+
+    pub fn add_annotation(&mut self, author_id: &Uuid, draft: AnnotationDraft) -> Result<(), &'static str> {
+        draft.validate()?;
+        if self.annotations.len() >= MAX_ANNOTATIONS {
+            return Err("Lobby has too many annotations")
+        }
+        let author_name = match self.viewers.get(author_id) {
+            Some(viewer) => viewer.name.clone(),
+            None => return Err("Unknown viewer")
+        };
+
+        let annotation = Annotation::new(Uuid::new_v4().to_string(), author_id.to_string(), author_name, draft);
+        self.broadcast(&ViewerMessage::AnnotationAdded { annotation: annotation.clone() });
+        self.annotations.push(annotation);
+        Ok(())
+    }
+
+    fn authored_annotation(&self, author_id: &Uuid, annotation_id: &str) -> Result<usize, &'static str> {
+        let index = self.annotations.iter()
+            .position(|annotation| annotation.annotation_id == annotation_id)
+            .ok_or("Unknown annotation")?;
+        if self.annotations[index].author_id != author_id.to_string() {
+            return Err("Only the author can change an annotation")
+        }
+        Ok(index)
+    }
+
+    pub fn edit_annotation(&mut self, author_id: &Uuid, annotation_id: &str, draft: AnnotationDraft) -> Result<(), &'static str> {
+        draft.validate()?;
+        let index = self.authored_annotation(author_id, annotation_id)?;
+
+        let previous = &self.annotations[index];
+        let annotation = Annotation::new(previous.annotation_id.clone(), previous.author_id.clone(), previous.author_name.clone(), draft);
+        self.broadcast(&ViewerMessage::AnnotationEdited { annotation: annotation.clone() });
+        self.annotations[index] = annotation;
+        Ok(())
+    }
+
+    pub fn delete_annotation(&mut self, author_id: &Uuid, annotation_id: &str) -> Result<(), &'static str> {
+        let index = self.authored_annotation(author_id, annotation_id)?;
+        let annotation = self.annotations.remove(index);
+        self.broadcast(&ViewerMessage::AnnotationDeleted { annotation_id: annotation.annotation_id });
+        Ok(())
+    }
+
+    pub fn suspend_viewer(&mut self, viewer_id: Uuid, session_token: Uuid, preferences: ViewerPreferences, resume_window: Duration) {
+        self.viewers.remove(&viewer_id);
+        let now = Instant::now();
+        self.suspended_viewers.retain(|_, suspended| suspended.expires > now);
+        self.suspended_viewers.insert(session_token, SuspendedViewer {
+            viewer_id,
+            preferences,
+            expires: now + resume_window,
+        });
+    }
+
+    /// The connected viewer holding a session, which a viewer reconnecting with its token can
+    /// take over before the old connection is noticed to be gone
+    pub fn attached_session(&self, session_token: &Uuid) -> Option<&ViewerHandle> {
+        self.viewers.values().find(|viewer| viewer.holds_session(session_token))
+    }
+
+    pub fn resume_viewer(&mut self, session_token: &Uuid) -> Option<(Uuid, ViewerPreferences)> {
+        let suspended = self.suspended_viewers.remove(session_token)?;
+        if suspended.expires <= Instant::now() {
+            return None
+        }
+        Some((suspended.viewer_id, suspended.preferences))
+    }
+
+    pub fn recording(&self) -> &Arc<Recording> {
+        &self.recording
+    }
+
+    pub fn bookmarks(&self) -> &[Bookmark] {
+        &self.bookmarks
+    }
+
+    pub fn view_session(&self) -> &ViewSession {
+        &self.view_session
+    }
+
+    pub fn view_session_mut(&mut self) -> &mut ViewSession {
+        &mut self.view_session
+    }
 }
 
 pub struct LobbyHandler {
@@ -75,7 +368,7 @@ impl LobbyHandler {
     }
 
     fn is_uuid_an_active_lobby(&self, lobby_uuid: &Uuid) -> bool {
-        return self.lobbies.contains_key(&lobby_uuid)
+        self.lobbies.contains_key(lobby_uuid)
     }
 
     pub fn get_lobby_uuid(&self, lobby_id: &str) -> Option<Uuid> {
@@ -89,7 +382,7 @@ impl LobbyHandler {
             },
             Err(_) => {
                 if self.custom_name_map.contains_key(lobby_id) {
-                    let uuid = self.custom_name_map.get(lobby_id).unwrap().clone();
+                    let uuid = *self.custom_name_map.get(lobby_id).unwrap();
                     if self.is_uuid_an_active_lobby(&uuid) {
                         return Some(uuid)
                     }
@@ -99,13 +392,59 @@ impl LobbyHandler {
         }
     }
 
-    pub fn create_or_get_lobby_uuid(&mut self, lobby_id: &str) -> Uuid {
+    /// Lobby a viewer session belongs to, whether it is suspended or still connected
+    pub fn lobby_for_session(&self, session_token: &Uuid) -> Option<Uuid> {
+        self.lobbies.values()
+            .find(|lobby| lobby.suspended_viewers.contains_key(session_token) || lobby.attached_session(session_token).is_some())
+            .map(|lobby| lobby.unique_id)
+    }
+
+    pub fn lobby(&self, lobby_uuid: &Uuid) -> Option<&Lobby> {
+        self.lobbies.get(lobby_uuid)
+    }
+
+    pub fn lobby_mut(&mut self, lobby_uuid: &Uuid) -> Option<&mut Lobby> {
+        self.lobbies.get_mut(lobby_uuid)
+    }
+
+    /// Hands the new bookmarks of a mission to every lobby watching it
+    pub fn update_bookmarks(&mut self, mission_id: &str, bookmarks: &[Bookmark]) {
+        for lobby in self.lobbies.values_mut().filter(|lobby| lobby.mission_id == mission_id) {
+            lobby.bookmarks = bookmarks.to_vec();
+            lobby.broadcast(&ViewerMessage::Bookmarks { bookmarks: lobby.bookmarks.clone() });
+        }
+    }
+
+    /// Lobby of a live mission that is still being received from `source`
+    pub fn live_lobby(&self, source: &str, mission_id: &str) -> Option<Uuid> {
+        self.lobbies.values()
+            .find(|lobby| lobby.live().is_some_and(|live| live.source() == source && live.mission_id() == mission_id))
+            .map(|lobby| lobby.unique_id)
+    }
+
+    /// Live lobbies start playing straight away so viewers are kept at the newest frame
+    pub fn create_live_lobby(&mut self, lobby_id: &str, live: LiveMission, recording: Recording) -> Result<Uuid, Error> {
+        if self.get_lobby_uuid(lobby_id).is_some() {
+            return Err(format!("Lobby {:?} already exists", lobby_id).into())
+        }
+
+        let mut new_lobby = Lobby::new(live.mission_id().to_string(), Arc::new(recording), Vec::new(), None);
+        new_lobby.live = Some(live);
+        new_lobby.view_session.play();
+        let lobby_uuid = new_lobby.unique_id;
+        self.custom_name_map.insert(lobby_id.to_string(), lobby_uuid);
+        self.lobbies.insert(lobby_uuid, new_lobby);
+
+        Ok(lobby_uuid)
+    }
+
+    pub fn create_or_get_lobby_uuid(&mut self, lobby_id: &str, mission_id: &str, recording: Arc<Recording>, bookmarks: Vec<Bookmark>, password: Option<String>) -> Uuid {
         if let Some(uuid) = self.get_lobby_uuid(lobby_id) {
             return uuid
         }
 
-        let new_lobby = Lobby::new();
-        let lobby_uuid = new_lobby.unique_id.clone();
+        let new_lobby = Lobby::new(mission_id.to_string(), recording, bookmarks, password);
+        let lobby_uuid = new_lobby.unique_id;
         self.custom_name_map.insert(lobby_id.to_string(), lobby_uuid);
         self.lobbies.insert(lobby_uuid, new_lobby);
 
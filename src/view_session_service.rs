@@ -16,7 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 use std::{
-    time::{Duration, Instant},
+    str::FromStr,
     sync::{Arc, RwLock},
     future::Future,
     pin::Pin,
@@ -24,71 +24,181 @@ use std::{
 };
 use hyper::service::Service;
 use hyper::{Body, Request, Response, Method, body};
-use hyper_tungstenite::{tungstenite, HyperWebsocket};
-use tungstenite::Message;
-use futures::SinkExt;
+use uuid::Uuid;
+use tokio::sync::Semaphore;
 
 use log::{info, warn, debug};
 
 use crate::view_session::LobbyHandler;
+use crate::mission_store::{MissionStore, Bookmark};
+use crate::deep_link::{self, LinkStore, DeepLink};
+use crate::kill_feed::{self, KillFilter};
+use crate::stats::Statistics;
+use crate::heatmap::{HeatmapCache, HeatmapKind, HeatmapQuery};
+use crate::snapshot::{self, Bounds, Snapshot, SnapshotOptions};
+use crate::timelapse::{TimelapseJobs, TimelapseRejection};
+use crate::export::{Export, ExportFilter, ExportPlacement};
+use crate::terrain::{GridPrecision, TerrainRegistry};
+use crate::mission::{Mission, Side};
+use crate::viewer::{self, ViewerConnection, JoinRequest};
+use crate::ingest::IngestConnection;
+use crate::encoding::WireEncoding;
+use crate::config::ServerConfig;
 use crate::potato_types::Error;
 use crate::serve_static::{StaticServer, StaticFile, StaticFileStorage};
 use crate::requests;
 use crate::responses;
-use crate::responses::CanRespond;
+use crate::responses::{CanRespond, ConnectionRejection};
 use crate::utils;
 use crate::json_builder;
 
+#[derive(Clone)]
 pub struct ViewSessionService {
+    config: Arc<ServerConfig>,
     lobbies: Arc<RwLock<LobbyHandler>>,
+    missions: Arc<MissionStore>,
+    links: Arc<LinkStore>,
+    heatmaps: Arc<HeatmapCache>,
+    timelapses: TimelapseJobs,
+    renders: Arc<Semaphore>,
+    terrains: Arc<TerrainRegistry>,
     static_server: Arc<StaticServer>
 }
 
 impl ViewSessionService {
-    fn new(lobbies: Arc<RwLock<LobbyHandler>>, static_server: Arc<StaticServer>) -> ViewSessionService {
-        ViewSessionService {
-            lobbies: lobbies.clone(),
-            static_server
+    /// Works out which lobby a websocket upgrade request wants to join and how
+    fn validate_viewer(&self, request: &Request<Body>) -> Result<JoinRequest, responses::WebSocketFailedConnection> {
+        if request.uri().query().is_none() {
+            debug!("No query information");
+            return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::BadRequest, "No query parameters"))
+        }
+        let queries = utils::query_to_hash_map(request.uri());
+        let lobby_str = queries.get("lobby-id");
+        let resume_token = queries.get("resume").and_then(|token| Uuid::from_str(token).ok());
+        if lobby_str.is_none() && resume_token.is_none() {
+            debug!("Bad query parameters");
+            return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::BadRequest, "Bad query parameters"))
         }
-    }
 
-    async fn handle_request(&mut self, mut request: Request<Body>) -> Result<Response<Body>, Error> {
-        if hyper_tungstenite::is_upgrade_request(&request) {
-            if request.uri().query().is_none() {
-                debug!("No query information");
-                return Ok(
-                    json_builder::build_json_response_from_response(
-                        responses::WebSocketFailedConnection::new("No query parameters").build_response(hyper::StatusCode::BAD_REQUEST)    
-                    )
-                )
-            }
-            let queries = utils::query_to_hash_map(request.uri());
-            let lobby_str = queries.get("lobby-id");
-            if let None = lobby_str {
-                debug!("Bad query parameters");
-                return Ok(
-                    json_builder::build_json_response_from_response(
-                        responses::WebSocketFailedConnection::new("Bad query parameters").build_response(hyper::StatusCode::BAD_REQUEST)    
-                    )
-                )
+        if let Some(protocol) = queries.get("protocol") {
+            if protocol.parse::<u32>().ok() != Some(viewer::PROTOCOL_VERSION) {
+                debug!("Viewer requested protocol version {:?}", protocol);
+                return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::ProtocolMismatch, "Unsupported protocol version"))
             }
+        }
+
+        let encoding = WireEncoding::from_query(queries.get("encoding").unwrap_or(&""));
+        if encoding.is_none() {
+            debug!("Unknown encoding requested");
+            return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::BadRequest, "Unknown encoding"))
+        }
 
-            let lobby_uuid = self.lobbies.read().unwrap().get_lobby_uuid(lobby_str.unwrap());
-            if lobby_uuid.is_none() {
+        let lobbies = self.lobbies.read().unwrap();
+        // a resuming viewer only needs its session token to find its way back
+        let lobby_uuid = match lobby_str {
+            Some(lobby_str) => lobbies.get_lobby_uuid(lobby_str),
+            None => lobbies.lobby_for_session(&resume_token.unwrap())
+        };
+        let lobby = match lobby_uuid.and_then(|uuid| lobbies.lobby(&uuid)) {
+            Some(lobby) => lobby,
+            None => {
                 debug!("No lobby exists with provided ID");
-                return Ok(
-                    json_builder::build_json_response_from_response(
-                        responses::WebSocketFailedConnection::new("No lobby exists with provided ID").build_response(hyper::StatusCode::BAD_REQUEST)    
-                    )
-                )
+                return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::UnknownLobby, "No lobby exists with provided ID"))
             }
+        };
+
+        // a viewer with a session in this lobby already got past the password once
+        let resuming = resume_token.is_some_and(|token| lobbies.lobby_for_session(&token) == lobby_uuid);
+        if !resuming && !lobby.accepts_password(queries.get("password").copied()) {
+            debug!("Wrong lobby password");
+            return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::Unauthorized, "Wrong lobby password"))
+        }
+
+        // taking over a session that is still connected frees its place
+        let taking_over = resume_token.is_some_and(|token| lobby.attached_session(&token).is_some());
+        if !taking_over && lobby.viewer_count() >= self.config.websocket.max_viewers {
+            debug!("Lobby is full");
+            return Err(responses::WebSocketFailedConnection::new(ConnectionRejection::LobbyFull, "Lobby is full"))
+        }
+
+        let name = queries.get("name").and_then(|name| viewer::clean_name(&utils::percent_decode(name)));
+        // only a link that belongs to this lobby positions the viewer
+        let link = queries.get("link")
+            .filter(|code| lobbies.get_lobby_uuid(&DeepLink::lobby_id(code)) == lobby_uuid)
+            .and_then(|code| self.links.get(code));
+
+        Ok(JoinRequest {
+            lobby_uuid: lobby_uuid.unwrap(),
+            encoding: encoding.unwrap(),
+            resume_token,
+            name,
+            link
+        })
+    }
+
+    /// Finds which configured live source is connecting, by its name and token
+    fn validate_ingest(&self, request: &Request<Body>) -> Result<String, responses::WebSocketFailedConnection> {
+        let queries = utils::query_to_hash_map(request.uri());
+        let bearer = request.headers().get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = bearer.or(queries.get("token").copied());
+
+        let source = queries.get("source").copied().unwrap_or("");
+        match self.config.ingest.sources.get(source) {
+            Some(config) if token == Some(config.token.as_str()) => Ok(source.to_string()),
+            _ => {
+                warn!("Refused live source {:?}", source);
+                Err(responses::WebSocketFailedConnection::new(ConnectionRejection::Unauthorized, "Unknown source or wrong token"))
+            }
+        }
+    }
+
+    async fn handle_request(&mut self, mut request: Request<Body>) -> Result<Response<Body>, Error> {
+        if hyper_tungstenite::is_upgrade_request(&request) && request.uri().path() == "/ingest" {
+            let source = match self.validate_ingest(&request) {
+                Ok(source) => source,
+                Err(failure) => {
+                    let status_code = failure.status_code;
+                    return Ok(json_builder::build_json_response_from_response(failure.build_response(status_code)))
+                }
+            };
+
+            let (response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+            let ingest = IngestConnection::new(self.config.clone(), self.lobbies.clone(), self.missions.clone(), source);
+            tokio::spawn(async move {
+                if let Err(e) = ingest.serve(websocket).await {
+                    warn!(target: "ingest", "Error in ingest connection: {:?}", e);
+                }
+            });
+
+            Ok(response)
+        } else if hyper_tungstenite::is_upgrade_request(&request) {
+            let join = match self.validate_viewer(&request) {
+                Ok(viewer) => viewer,
+                Err(failure) if self.config.websocket.close_frame_errors => {
+                    // browsers cannot read the body of a failed upgrade, but can read a close frame
+                    let (response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+                    tokio::spawn(async move {
+                        if let Err(e) = viewer::reject(websocket, failure.close_code, failure.message).await {
+                            debug!("Error rejecting websocket connection: {:?}", e);
+                        }
+                    });
+                    return Ok(response)
+                },
+                Err(failure) => {
+                    let status_code = failure.status_code;
+                    return Ok(json_builder::build_json_response_from_response(failure.build_response(status_code)))
+                }
+            };
 
             let (response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
 
+            let viewer = ViewerConnection::new(self.config.clone(), self.lobbies.clone(), self.terrains.clone(), join);
             tokio::spawn(async move {
-                /*if let Err(e) = s.serve_websocket(websocket, request).await {
+                if let Err(e) = viewer.serve(websocket).await {
                     warn!(target: "view_session", "Error in websocket connection: {:?}", e);
-                }*/
+                }
             });
 
             Ok(response)
@@ -97,28 +207,14 @@ impl ViewSessionService {
         }
     }
 
-    async fn serve_websocket(&mut self, websocket: HyperWebsocket, request: Request<Body>) -> Result<(), Error> {
-        debug!("New websocket connection");
-        let mut websocket = websocket.await?;
-        let mut now = Instant::now();
-        loop {
-            if now.elapsed() > Duration::from_millis(500){
-                websocket.send(Message::text("Test")).await?;
-                now = Instant::now();
-                break;
-            }
-        }
-        Ok(())
-    }
-
     async fn serve_http(&mut self, request: Request<Body>) -> Result<Response<Body>, Error> {
         debug!("New request from path {:?}", request.uri().path());
-        let response = match request.method() {
-            &Method::GET => self.static_server.serve(request.uri().path()),
-            &Method::POST => {
+        let response = match *request.method() {
+            Method::GET => self.handle_http_get(request.uri()).await,
+            Method::POST => {
                 let uri = request.uri().clone();
                 let bytes = body::to_bytes(request.into_body()).await?.to_vec();
-                self.handle_http_post(&uri, bytes)
+                self.handle_http_post(&uri, bytes).await
             },
             _ => self.static_server.serve_404()
         };
@@ -126,33 +222,625 @@ impl ViewSessionService {
         Ok(response)
     }
 
-    fn handle_http_post(&mut self, uri: &hyper::Uri, bytes: Vec<u8>) -> Response<Body> {
-        let json_parse = serde_json::from_slice(&bytes);
-        if let Err(e) = json_parse {
-            warn!("Cannot parse request params: {:?}", e);
-            return Response::builder().status(hyper::StatusCode::BAD_REQUEST).body(Body::from("")).unwrap();
+    fn parse_params<T>(bytes: &[u8]) -> Option<T>
+            where T: serde::de::DeserializeOwned
+    {
+        match serde_json::from_slice(bytes) {
+            Ok(params) => Some(params),
+            Err(e) => {
+                warn!("Cannot parse request params: {:?}", e);
+                None
+            }
         }
+    }
+
+    fn bad_request() -> Response<Body> {
+        Response::builder().status(hyper::StatusCode::BAD_REQUEST).body(Body::from("")).unwrap()
+    }
 
+    /// Runs a mission store call on the blocking pool, as loading and importing read files
+    async fn with_missions<T, F>(&self, call: F) -> Result<T, Error>
+            where T: Send + 'static, F: FnOnce(&MissionStore) -> Result<T, Error> + Send + 'static
+    {
+        let missions = self.missions.clone();
+        tokio::task::spawn_blocking(move || call(&missions)).await?
+    }
+
+    /// Creates the lobby or finds it if it already exists
+    async fn create_lobby(&self, lobby_id: &str, mission_id: &str, password: Option<String>) -> Result<Uuid, Error> {
+        let id = mission_id.to_string();
+        let (recording, bookmarks) = self.with_missions(move |missions| Ok((missions.load(&id)?, missions.bookmarks(&id)?))).await?;
+
+        let mut lobbies = self.lobbies.write().unwrap();
+        Ok(lobbies.create_or_get_lobby_uuid(lobby_id, mission_id, recording, bookmarks, password))
+    }
+
+    /// Puts whoever opens a deep link into the link's lobby. The link's time and camera are applied
+    /// to that viewer when it joins, the lobby's clock is left to whoever is already watching
+    async fn open_link(&self, code: &str) -> Response<Body> {
+        let link = match self.links.get(code) {
+            Some(link) => link,
+            None => return self.static_server.serve_404()
+        };
+
+        let lobby_uuid = match self.create_lobby(&DeepLink::lobby_id(code), &link.mission_id, None).await {
+            Ok(lobby_uuid) => lobby_uuid,
+            Err(e) => {
+                warn!("Cannot open link {:?} to mission {:?}: {:?}", code, link.mission_id, e);
+                return self.static_server.serve_404()
+            }
+        };
+
+        info!("Opening link {:?} in lobby {}", code, lobby_uuid);
+        Response::builder()
+            .status(hyper::StatusCode::SEE_OTHER)
+            .header(hyper::header::LOCATION, DeepLink::viewer_url(code, &lobby_uuid))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn create_link(&self, params: requests::CreateLink) -> Result<String, Error> {
+        let mission_id = params.mission_id.clone();
+        let recording = self.with_missions(move |missions| missions.load(&mission_id)).await?;
+        if !(0.0..=recording.mission.duration_seconds()).contains(&params.mission_time) {
+            return Err("Link is outside of the mission".into())
+        }
+        if ![params.camera.x, params.camera.y, params.camera.zoom].iter().all(|value| value.is_finite()) {
+            return Err("Invalid camera".into())
+        }
+        if let Some(unit) = params.follow_unit {
+            if !recording.mission.entities.iter().any(|entity| entity.id == unit) {
+                return Err(format!("No unit {} exists in the mission", unit).into())
+            }
+        }
+
+        self.links.create(DeepLink {
+            mission_id: params.mission_id,
+            mission_time: params.mission_time,
+            camera: params.camera,
+            follow_unit: params.follow_unit
+        })
+    }
+
+    fn not_found(message: String) -> Response<Body> {
+        json_builder::build_json_response(hyper::StatusCode::NOT_FOUND, serde_json::json!({ "message": message }))
+    }
+
+    fn csv_response(file_name: &str, csv: Result<Vec<u8>, Error>) -> Response<Body> {
+        match csv {
+            Ok(csv) => Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header("Content-Type", "text/csv")
+                .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+                .body(Body::from(csv))
+                .unwrap(),
+            Err(e) => {
+                warn!("Could not write {}: {:?}", file_name, e);
+                Response::builder().status(hyper::StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap()
+            }
+        }
+    }
+
+    fn kill_filter(uri: &hyper::Uri) -> Option<KillFilter> {
+        let queries = utils::query_to_hash_map(uri);
+        Some(KillFilter {
+            side: match queries.get("side") {
+                Some(side) => Some(Side::from_game_name(side)?),
+                None => None
+            },
+            unit: match queries.get("unit") {
+                Some(unit) => Some(unit.parse().ok()?),
+                None => None
+            }
+        })
+    }
+
+    fn heatmap_query(mission_id: &str, mission: &Mission, uri: &hyper::Uri) -> Option<HeatmapQuery> {
+        let queries = utils::query_to_hash_map(uri);
+        let time = |name| queries.get(name).map(|time: &&str| time.parse::<f64>().ok().filter(|time| time.is_finite()));
+        let from = time("from").unwrap_or(Some(0.0))?;
+        let to = match time("to") {
+            Some(to) => Some(to.filter(|to| *to >= from)?),
+            None => None
+        };
+        let start_frame = mission.frame_at(from);
+        let end_frame = to.map_or(mission.frame_count, |to| mission.frame_at(to));
+
+        Some(HeatmapQuery {
+            mission_id: mission_id.to_string(),
+            kind: HeatmapKind::from_name(queries.get("kind").copied().unwrap_or("presence"))?,
+            side: match queries.get("side") {
+                Some(side) => Some(Side::from_game_name(side)?),
+                None => None
+            },
+            group: queries.get("group").map(|group| utils::percent_decode(group)),
+            start_frame,
+            end_frame,
+            cell_size: match queries.get("cell") {
+                Some(cell) => cell.parse().ok().filter(|cell| *cell >= 10)?,
+                None => 100
+            }
+        })
+    }
+
+    fn snapshot_options(mission: &Mission, uri: &hyper::Uri) -> Option<SnapshotOptions> {
+        let queries = utils::query_to_hash_map(uri);
+        let mission_time = queries.get("t")?.parse().ok()
+            .filter(|time| (0.0..=mission.duration_seconds()).contains(time))?;
+
+        Some(SnapshotOptions {
+            mission_time,
+            names: queries.get("names").is_some_and(|names| *names != "0" && *names != "false"),
+            trail_seconds: match queries.get("trail") {
+                Some(trail) => trail.parse().ok().filter(|trail| (0.0..=snapshot::MAX_TRAIL_SECONDS).contains(trail))?,
+                None => 0.0
+            },
+            bounds: match queries.get("bbox") {
+                Some(bounds) => Some(Bounds::parse(&utils::percent_decode(bounds))?),
+                None => None
+            },
+            width: match queries.get("width") {
+                Some(width) => width.parse().ok()?,
+                None => 1024
+            }
+        })
+    }
+
+    fn export_options(&self, mission: &Mission, uri: &hyper::Uri) -> Option<(ExportFilter, ExportPlacement)> {
+        let queries = utils::query_to_hash_map(uri);
+        let time = |name| queries.get(name).map(|time: &&str| time.parse::<f64>().ok().filter(|time| time.is_finite()).map(|time| mission.frame_at(time)));
+        let filter = ExportFilter {
+            units: match queries.get("unit") {
+                Some(units) => utils::percent_decode(units).split(',')
+                    .map(|unit| unit.parse().ok())
+                    .collect::<Option<_>>()?,
+                None => Vec::new()
+            },
+            side: match queries.get("side") {
+                Some(side) => Some(Side::from_game_name(side)?),
+                None => None
+            },
+            start_frame: time("from").unwrap_or(Some(0))?,
+            end_frame: time("to").unwrap_or(Some(mission.frame_count))?
+        };
+        let terrain = self.terrains.get(&mission.world_name);
+        let placement = ExportPlacement {
+            geo: self.config.geo_reference(&mission.world_name)
+                .or_else(|| terrain.as_ref().and_then(|terrain| terrain.geo_reference))
+                .unwrap_or_default(),
+            grid: terrain.map(|terrain| terrain.grid),
+            precision: match queries.get("grid") {
+                Some(digits) => GridPrecision::from_digits(digits.parse().ok()?)?,
+                None => GridPrecision::default()
+            },
+            epoch: match queries.get("epoch") {
+                Some(epoch) => epoch.parse().ok().filter(|epoch: &f64| epoch.is_finite())?,
+                None => 0.0
+            }
+        };
+
+        Some((filter, placement))
+    }
+
+    /// Runs work that walks a whole recording on the blocking pool, the runtime's threads have
+    /// every other connection to serve
+    async fn blocking_response<F>(work: F) -> Response<Body>
+            where F: FnOnce() -> Response<Body> + Send + 'static
+    {
+        match tokio::task::spawn_blocking(work).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Could not build response: {:?}", e);
+                Response::builder().status(hyper::StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap()
+            }
+        }
+    }
+
+    /// Data derived from a recording, at `/missions/<mission id>/<resource>`
+    async fn handle_mission_get(&self, mission_id: &str, resource: &str, uri: &hyper::Uri) -> Response<Body> {
+        let id = mission_id.to_string();
+        let recording = match self.with_missions(move |missions| missions.load(&id)).await {
+            Ok(recording) => recording,
+            Err(e) => return ViewSessionService::not_found(e.to_string())
+        };
+
+        match resource {
+            "info" => {
+                let terrain = self.terrains.get(&recording.mission.world_name);
+                json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({
+                    "mission_id": mission_id,
+                    "mission": responses::MissionInfo::new(&recording.mission, terrain.as_deref())
+                }))
+            },
+            "kills" => {
+                let filter = match ViewSessionService::kill_filter(uri) {
+                    Some(filter) => filter,
+                    None => return ViewSessionService::bad_request()
+                };
+                let kills: Vec<_> = kill_feed::kills(&recording.mission, self.terrains.grid(&recording.mission.world_name).as_ref()).into_iter()
+                    .filter(|kill| filter.matches(kill))
+                    .collect();
+                json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({
+                    "mission_id": mission_id,
+                    "kills": kills
+                }))
+            },
+            "heatmap" | "heatmap.png" => {
+                let query = match ViewSessionService::heatmap_query(mission_id, &recording.mission, uri) {
+                    Some(query) => query,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                let json = resource == "heatmap";
+                let scale = match utils::query_to_hash_map(uri).get("scale").filter(|_| !json) {
+                    Some(scale) => match scale.parse().ok().filter(|scale| (1..=32).contains(scale)) {
+                        Some(scale) => scale,
+                        None => return ViewSessionService::bad_request()
+                    },
+                    None => 4
+                };
+                let (heatmaps, mission_id) = (self.heatmaps.clone(), mission_id.to_string());
+                ViewSessionService::blocking_response(move || {
+                    if json {
+                        return match heatmaps.heatmap(&recording, &query) {
+                            Ok(heatmap) => json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({
+                                "mission_id": mission_id,
+                                "heatmap": *heatmap
+                            })),
+                            Err(e) => json_builder::build_json_response(hyper::StatusCode::BAD_REQUEST, serde_json::json!({ "message": e.to_string() }))
+                        }
+                    }
+
+                    match heatmaps.image(&recording, &query, scale) {
+                        Ok(image) => Response::builder()
+                            .status(hyper::StatusCode::OK)
+                            .header("Content-Type", "image/png")
+                            .body(Body::from(image))
+                            .unwrap(),
+                        Err(e) => json_builder::build_json_response(hyper::StatusCode::BAD_REQUEST, serde_json::json!({ "message": e.to_string() }))
+                    }
+                }).await
+            },
+            "snapshot.png" | "snapshot.svg" => {
+                let options = match ViewSessionService::snapshot_options(&recording.mission, uri) {
+                    Some(options) => options,
+                    None => return ViewSessionService::bad_request()
+                };
+                // drawing a large area takes a while, so keep it off the runtime's threads and
+                // only draw a few at a time
+                let permit = match self.renders.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => return json_builder::build_json_response(hyper::StatusCode::TOO_MANY_REQUESTS, serde_json::json!({
+                        "message": "Too many snapshots are being drawn, try again shortly"
+                    }))
+                };
+                let svg = resource == "snapshot.svg";
+                let rendered = tokio::task::spawn_blocking(move || -> Result<Result<_, Error>, Error> {
+                    let _permit = permit;
+                    let snapshot = Snapshot::new(&recording.mission, options)?;
+                    Ok(match svg {
+                        true => Ok(("image/svg+xml", snapshot.to_svg().into_bytes())),
+                        false => snapshot.to_png().map(|png| ("image/png", png))
+                    })
+                }).await;
+
+                let image: Result<_, Error> = match rendered {
+                    Ok(Ok(image)) => image,
+                    Ok(Err(e)) => return json_builder::build_json_response(hyper::StatusCode::BAD_REQUEST, serde_json::json!({ "message": e.to_string() })),
+                    Err(e) => Err(e.into())
+                };
+                match image {
+                    Ok((content_type, image)) => Response::builder()
+                        .status(hyper::StatusCode::OK)
+                        .header("Content-Type", content_type)
+                        .body(Body::from(image))
+                        .unwrap(),
+                    Err(e) => {
+                        warn!("Could not render snapshot of {:?}: {:?}", mission_id, e);
+                        Response::builder().status(hyper::StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap()
+                    }
+                }
+            },
+            "export/tracks.geojson" | "export/events.geojson" | "export/tracks.kml" | "export/positions.csv" => {
+                let (filter, placement) = match self.export_options(&recording.mission, uri) {
+                    Some(options) => options,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                let (mission_id, resource) = (mission_id.to_string(), resource.to_string());
+                ViewSessionService::blocking_response(move || {
+                    let export = Export::new(&recording.mission, filter, placement);
+                    let (content_type, body) = match resource.as_str() {
+                        "export/tracks.geojson" => ("application/geo+json", export.tracks_geojson().to_string()),
+                        "export/events.geojson" => ("application/geo+json", export.events_geojson().to_string()),
+                        "export/tracks.kml" => ("application/vnd.google-earth.kml+xml", export.tracks_kml()),
+                        _ => return ViewSessionService::csv_response(&format!("{}-positions.csv", mission_id), export.positions_csv())
+                    };
+                    let file_name = format!("{}-{}", mission_id, resource.trim_start_matches("export/"));
+                    Response::builder()
+                        .status(hyper::StatusCode::OK)
+                        .header("Content-Type", content_type)
+                        .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+                        .body(Body::from(body))
+                        .unwrap()
+                }).await
+            },
+            "stats" | "stats.csv" => {
+                let by = utils::query_to_hash_map(uri).get("by").map(|by| by.to_string());
+                let (mission_id, csv) = (mission_id.to_string(), resource == "stats.csv");
+                ViewSessionService::blocking_response(move || {
+                    let statistics = Statistics::new(&recording.mission);
+                    if !csv {
+                        return json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({
+                            "mission_id": mission_id,
+                            "statistics": statistics
+                        }))
+                    }
+                    match by.as_deref() {
+                        None | Some("unit") => ViewSessionService::csv_response(&format!("{}-units.csv", mission_id), statistics.units_csv()),
+                        Some("group") => ViewSessionService::csv_response(&format!("{}-groups.csv", mission_id), statistics.groups_csv()),
+                        Some(_) => ViewSessionService::bad_request()
+                    }
+                }).await
+            },
+            _ => self.static_server.serve_404()
+        }
+    }
+
+    /// Progress of a timelapse job at `/timelapse/<job id>`, and its result at `.../download`
+    fn handle_timelapse_get(&self, path: &str) -> Response<Body> {
+        let (job_id, download) = match path.strip_suffix("/download") {
+            Some(job_id) => (job_id, true),
+            None => (path, false)
+        };
+        let job_id = match Uuid::parse_str(job_id) {
+            Ok(job_id) => job_id,
+            Err(_) => return ViewSessionService::not_found("Unknown timelapse".to_string())
+        };
+
+        if !download {
+            return match self.timelapses.status(&job_id) {
+                Some(status) => json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!(status)),
+                None => ViewSessionService::not_found("Unknown timelapse".to_string())
+            }
+        }
+
+        match self.timelapses.result(&job_id) {
+            Some((format, timelapse)) => Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header("Content-Type", format.content_type())
+                .header("Content-Disposition", format!("attachment; filename=\"timelapse-{}.{}\"", job_id, format.extension()))
+                .body(Body::from(timelapse))
+                .unwrap(),
+            None => ViewSessionService::not_found("Timelapse is not ready".to_string())
+        }
+    }
+
+    async fn handle_http_get(&mut self, uri: &hyper::Uri) -> Response<Body> {
+        if let Some(code) = uri.path().strip_prefix("/l/") {
+            return self.open_link(code).await
+        }
+        if let Some((mission_id, resource)) = uri.path().strip_prefix("/missions/").and_then(|rest| rest.split_once('/')) {
+            return self.handle_mission_get(mission_id, resource, uri).await
+        }
+        if let Some(path) = uri.path().strip_prefix("/timelapse/") {
+            return self.handle_timelapse_get(path)
+        }
+        if uri.path() == "/terrains" {
+            return json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({ "terrains": self.terrains.all() }))
+        }
+        if let Some(world_name) = uri.path().strip_prefix("/terrains/") {
+            return match self.terrains.get(&utils::percent_decode(world_name)) {
+                Some(terrain) => json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!(*terrain)),
+                None => ViewSessionService::not_found(format!("Unknown terrain {:?}", world_name))
+            }
+        }
+
+        match uri.path() {
+            "/bookmarks" => {
+                let queries = utils::query_to_hash_map(uri);
+                let mission_id = match queries.get("mission-id") {
+                    Some(mission_id) => mission_id.to_string(),
+                    None => return ViewSessionService::bad_request()
+                };
+
+                let id = mission_id.clone();
+                match self.with_missions(move |missions| missions.bookmarks(&id)).await {
+                    Ok(bookmarks) => json_builder::build_json_response(hyper::StatusCode::OK, serde_json::json!({
+                        "mission_id": mission_id,
+                        "bookmarks": bookmarks
+                    })),
+                    Err(e) => {
+                        warn!("Cannot read bookmarks of {:?}: {:?}", mission_id, e);
+                        ViewSessionService::bad_request()
+                    }
+                }
+            },
+            path => self.static_server.serve(path)
+        }
+    }
+
+    /// Responds to a bookmark change and passes the new bookmarks on to lobbies watching the mission
+    fn bookmarks_updated(&mut self, mission_id: &str, result: Result<Vec<Bookmark>, Error>, success_code: hyper::StatusCode) -> Response<Body> {
+        let (status, status_code) = match result {
+            Ok(bookmarks) => {
+                self.lobbies.write().unwrap().update_bookmarks(mission_id, &bookmarks);
+                (
+                    responses::BookmarksUpdated {
+                        valid: true,
+                        mission_id: mission_id.to_string(),
+                        message: "".to_string(),
+                        bookmarks
+                    },
+                    success_code
+                )
+            },
+            Err(e) => (
+                responses::BookmarksUpdated {
+                    valid: false,
+                    mission_id: mission_id.to_string(),
+                    message: e.to_string(),
+                    bookmarks: Vec::new()
+                },
+                hyper::StatusCode::BAD_REQUEST
+            )
+        };
+
+        json_builder::build_json_response_from_response(status.build_response(status_code))
+    }
+
+    async fn handle_http_post(&mut self, uri: &hyper::Uri, bytes: Vec<u8>) -> Response<Body> {
         let response = match uri.path() {
             "/create_lobby" => {
-                let lobby_params: requests::CreateLobby = json_parse.unwrap();
+                let lobby_params: requests::CreateLobby = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
                 
                 let mut status = responses::LobbyCreated {
                     valid: false,
                     lobby_id: "".to_string()
                 };
 
-                if lobby_params.lobby_id.is_ascii() && lobby_params.mission_id.is_ascii() {
-                    status.valid = true;
-
-                    let lobby_id_str = &lobby_params.lobby_id[..];
-                    status.lobby_id = self.lobbies.write().unwrap().create_or_get_lobby_uuid(lobby_id_str).to_string();
+                let mut status_code = hyper::StatusCode::CREATED;
+                if lobby_params.lobby_id.starts_with(deep_link::LOBBY_PREFIX) {
+                    status_code = hyper::StatusCode::BAD_REQUEST;
+                } else if lobby_params.lobby_id.is_ascii() && lobby_params.mission_id.is_ascii() {
+                    match self.create_lobby(&lobby_params.lobby_id, &lobby_params.mission_id, lobby_params.password.clone()).await {
+                        Ok(lobby_uuid) => {
+                            status.valid = true;
+                            status.lobby_id = lobby_uuid.to_string();
+                        },
+                        Err(e) => {
+                            warn!("Cannot load mission {:?}: {:?}", lobby_params.mission_id, e);
+                            status_code = hyper::StatusCode::NOT_FOUND;
+                        }
+                    }
                 }
 
                 info!("New lobby request: {:?} || {:?}", lobby_params, status);
 
-                json_builder::build_json_response_from_response(status.build_response(hyper::StatusCode::CREATED))
-            }
+                json_builder::build_json_response_from_response(status.build_response(status_code))
+            },
+            "/import_ocap" => {
+                let import_params: requests::ImportOcap = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                let (mission_id, file_name) = (import_params.mission_id.clone(), import_params.file_name.clone());
+                let imported = self.with_missions(move |missions| missions.import_ocap(&mission_id, &file_name)).await;
+                let (status, status_code) = match imported {
+                    Ok(report) => (
+                        responses::MissionImported {
+                            valid: true,
+                            mission_id: import_params.mission_id.clone(),
+                            message: "".to_string(),
+                            report: Some(report)
+                        },
+                        hyper::StatusCode::CREATED
+                    ),
+                    Err(e) => (
+                        responses::MissionImported {
+                            valid: false,
+                            mission_id: import_params.mission_id.clone(),
+                            message: e.to_string(),
+                            report: None
+                        },
+                        hyper::StatusCode::BAD_REQUEST
+                    )
+                };
+
+                info!("OCAP import request: {:?} || valid: {}", import_params, status.valid);
+
+                json_builder::build_json_response_from_response(status.build_response(status_code))
+            },
+            "/create_link" => {
+                let link_params: requests::CreateLink = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                info!("New link request: {:?}", link_params);
+                let (status, status_code) = match self.create_link(link_params).await {
+                    Ok(code) => (
+                        responses::LinkCreated {
+                            valid: true,
+                            message: "".to_string(),
+                            url: format!("/l/{}", code),
+                            code
+                        },
+                        hyper::StatusCode::CREATED
+                    ),
+                    Err(e) => (
+                        responses::LinkCreated {
+                            valid: false,
+                            message: e.to_string(),
+                            code: "".to_string(),
+                            url: "".to_string()
+                        },
+                        hyper::StatusCode::BAD_REQUEST
+                    )
+                };
+
+                json_builder::build_json_response_from_response(status.build_response(status_code))
+            },
+            "/create_timelapse" => {
+                let timelapse_params: requests::CreateTimelapse = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                info!("New timelapse request: {:?}", timelapse_params);
+                let mission_id = timelapse_params.mission_id.clone();
+                let started = self.with_missions(move |missions| missions.load(&mission_id)).await
+                    .map_err(TimelapseRejection::Invalid)
+                    .and_then(|recording| self.timelapses.start(recording, &timelapse_params));
+                let (status, status_code) = match started {
+                    Ok(job_id) => (
+                        responses::TimelapseCreated {
+                            valid: true,
+                            message: "".to_string(),
+                            job_id: job_id.to_string(),
+                            status_url: format!("/timelapse/{}", job_id)
+                        },
+                        hyper::StatusCode::ACCEPTED
+                    ),
+                    Err(rejection) => (
+                        responses::TimelapseCreated {
+                            valid: false,
+                            message: rejection.message(),
+                            job_id: "".to_string(),
+                            status_url: "".to_string()
+                        },
+                        rejection.status_code()
+                    )
+                };
+
+                json_builder::build_json_response_from_response(status.build_response(status_code))
+            },
+            "/add_bookmark" => {
+                let bookmark_params: requests::AddBookmark = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                info!("Add bookmark request: {:?}", bookmark_params);
+                let (mission_id, name) = (bookmark_params.mission_id.clone(), bookmark_params.name.clone());
+                let result = self.with_missions(move |missions| missions.add_bookmark(&mission_id, &name, bookmark_params.mission_time)).await;
+                self.bookmarks_updated(&bookmark_params.mission_id, result, hyper::StatusCode::CREATED)
+            },
+            "/delete_bookmark" => {
+                let bookmark_params: requests::DeleteBookmark = match ViewSessionService::parse_params(&bytes) {
+                    Some(params) => params,
+                    None => return ViewSessionService::bad_request()
+                };
+
+                info!("Delete bookmark request: {:?}", bookmark_params);
+                let (mission_id, bookmark_id) = (bookmark_params.mission_id.clone(), bookmark_params.bookmark_id.clone());
+                let result = self.with_missions(move |missions| missions.delete_bookmark(&mission_id, &bookmark_id)).await;
+                self.bookmarks_updated(&bookmark_params.mission_id, result, hyper::StatusCode::OK)
+            },
             _ => {
                 Response::builder().status(hyper::StatusCode::NOT_IMPLEMENTED).body(Body::empty()).unwrap()
             }
@@ -172,26 +860,35 @@ impl Service<Request<Body>> for ViewSessionService {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let mut other = ViewSessionService::new(self.lobbies.clone(), self.static_server.clone());
+        let mut other = self.clone();
         Box::pin(async move { other.handle_request(req).await })
     }
 }
 
+/// Hands every new connection a service sharing the server's state
 pub struct MakeViewSessionService {
-    lobbies: Arc<RwLock<LobbyHandler>>,
-    static_server: Arc<StaticServer>
+    service: ViewSessionService
 }
 
 impl MakeViewSessionService {
-    pub fn new() -> MakeViewSessionService {
+    pub fn new(config: ServerConfig) -> Result<MakeViewSessionService, Error> {
         let mut static_server = StaticServer::new("www");
-        static_server.register("/", StaticFile::HTML(StaticFileStorage::Disk("index.html")));
+        static_server.register("/", StaticFile::Html(StaticFileStorage::Disk("index.html")));
         static_server.register("/test.js", StaticFile::JavaScript(StaticFileStorage::Disk("test.js")));
 
-        MakeViewSessionService {
-            lobbies: Arc::new(RwLock::new(LobbyHandler::new())),
-            static_server: Arc::new(static_server)
-        }
+        Ok(MakeViewSessionService {
+            service: ViewSessionService {
+                config: Arc::new(config),
+                lobbies: Arc::new(RwLock::new(LobbyHandler::new())),
+                missions: Arc::new(MissionStore::new("recordings")),
+                links: Arc::new(LinkStore::new("recordings/links.json")?),
+                heatmaps: Arc::new(HeatmapCache::new()),
+                timelapses: TimelapseJobs::new(),
+                renders: Arc::new(Semaphore::new(snapshot::MAX_CONCURRENT_RENDERS)),
+                terrains: Arc::new(TerrainRegistry::load("terrains")),
+                static_server: Arc::new(static_server)
+            }
+        })
     }
 }
 
@@ -206,8 +903,106 @@ impl<T> Service<T> for MakeViewSessionService {
 
     fn call(&mut self, _: T) -> Self::Future {
         debug!("New connection");
-        let lobbies = self.lobbies.clone();
-        let static_server = self.static_server.clone();
-        let fut = async move { Ok(ViewSessionService::new(lobbies, static_server)) };
-        Box::pin(fut)
-    }}
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn mission() -> Mission {
+        serde_json::from_value(serde_json::json!({
+            "name": "Query test",
+            "world_name": "VR",
+            "frame_interval": 2.0,
+            "frame_count": 50,
+            "entities": []
+        })).unwrap()
+    }
+
+    fn heatmap_query(query: &str) -> Option<HeatmapQuery> {
+        let uri: hyper::Uri = format!("/missions/test/heatmap?{}", query).parse().unwrap();
+        ViewSessionService::heatmap_query("test", &mission(), &uri)
+    }
+
+    fn viewer_service(max_viewers: usize) -> (ViewSessionService, Uuid) {
+        let mut config = ServerConfig::default();
+        config.websocket.max_viewers = max_viewers;
+        let mut lobbies = LobbyHandler::new();
+        let recording = Arc::new(crate::recording_index::Recording::new(mission()));
+        let lobby_uuid = lobbies.create_or_get_lobby_uuid("test", "test", recording, Vec::new(), Some("hunter2".to_string()));
+        let service = ViewSessionService {
+            config: Arc::new(config),
+            lobbies: Arc::new(RwLock::new(lobbies)),
+            missions: Arc::new(MissionStore::new("recordings")),
+            links: Arc::new(LinkStore::new(std::env::temp_dir().join(format!("links-{}.json", Uuid::new_v4()))).unwrap()),
+            heatmaps: Arc::new(HeatmapCache::new()),
+            timelapses: TimelapseJobs::new(),
+            renders: Arc::new(Semaphore::new(1)),
+            terrains: Arc::new(TerrainRegistry::load("terrains")),
+            static_server: Arc::new(StaticServer::new("www"))
+        };
+        (service, lobby_uuid)
+    }
+
+    fn rejection(service: &ViewSessionService, query: &str) -> Option<(u16, hyper::StatusCode)> {
+        let request = Request::get(format!("/connect{}", query)).body(Body::empty()).unwrap();
+        service.validate_viewer(&request).err().map(|rejection| (rejection.close_code, rejection.status_code))
+    }
+
+    #[test]
+    fn refused_viewers_are_told_why() {
+        let (service, lobby_uuid) = viewer_service(8);
+        assert_eq!(rejection(&service, ""), Some((4000, hyper::StatusCode::BAD_REQUEST)));
+        assert_eq!(rejection(&service, "?name=someone"), Some((4000, hyper::StatusCode::BAD_REQUEST)));
+        assert_eq!(rejection(&service, "?lobby-id=test&password=hunter2&encoding=xml"), Some((4000, hyper::StatusCode::BAD_REQUEST)));
+        assert_eq!(rejection(&service, "?lobby-id=test&password=hunter2&protocol=999"), Some((4002, hyper::StatusCode::BAD_REQUEST)));
+        assert_eq!(rejection(&service, "?lobby-id=elsewhere"), Some((4004, hyper::StatusCode::BAD_REQUEST)));
+        assert_eq!(rejection(&service, "?lobby-id=test"), Some((4001, hyper::StatusCode::UNAUTHORIZED)));
+        assert_eq!(rejection(&service, "?lobby-id=test&password=wrong"), Some((4001, hyper::StatusCode::UNAUTHORIZED)));
+        assert_eq!(rejection(&service, "?lobby-id=test&password=hunter2"), None);
+
+        let request = Request::get("/connect?lobby-id=test&password=hunter2").body(Body::empty()).unwrap();
+        assert_eq!(service.validate_viewer(&request).unwrap().lobby_uuid, lobby_uuid);
+
+        let (service, _) = viewer_service(0);
+        assert_eq!(rejection(&service, "?lobby-id=test&password=hunter2"), Some((4009, hyper::StatusCode::SERVICE_UNAVAILABLE)));
+    }
+
+    #[test]
+    fn resuming_viewers_skip_the_password() {
+        let (service, lobby_uuid) = viewer_service(8);
+        let session_token = Uuid::new_v4();
+        let preferences = viewer::ViewerPreferences {
+            name: "Resumed".to_string(),
+            tick_interval: Duration::from_millis(100),
+            filter: Default::default(),
+            kill_filter: Default::default(),
+            follow: None
+        };
+        service.lobbies.write().unwrap().lobby_mut(&lobby_uuid).unwrap()
+            .suspend_viewer(Uuid::new_v4(), session_token, preferences, Duration::from_secs(60));
+
+        assert_eq!(rejection(&service, &format!("?resume={}", session_token)), None);
+        assert_eq!(rejection(&service, &format!("?lobby-id=test&resume={}", session_token)), None);
+        // a token from nowhere is no substitute for the password
+        assert_eq!(rejection(&service, &format!("?lobby-id=test&resume={}", Uuid::new_v4())), Some((4001, hyper::StatusCode::UNAUTHORIZED)));
+        assert_eq!(rejection(&service, &format!("?resume={}", Uuid::new_v4())), Some((4004, hyper::StatusCode::BAD_REQUEST)));
+    }
+
+    #[test]
+    fn heatmap_time_ranges_have_to_run_forwards() {
+        let query = heatmap_query("from=10&to=20&side=east").unwrap();
+        assert_eq!((query.start_frame, query.end_frame, query.side), (5, 10, Some(Side::Opfor)));
+        assert_eq!(heatmap_query("").unwrap().end_frame, 50);
+        assert_eq!(heatmap_query("from=10&to=10").unwrap().end_frame, 5);
+
+        assert!(heatmap_query("from=20&to=10").is_none());
+        assert!(heatmap_query("from=inf").is_none());
+        assert!(heatmap_query("to=NaN").is_none());
+        assert!(heatmap_query("cell=5").is_none());
+    }
+}
@@ -0,0 +1,1194 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    time::{Duration, Instant},
+    sync::{Arc, RwLock, atomic::{AtomicBool, Ordering}}
+};
+use hyper::upgrade::Upgraded;
+use hyper_tungstenite::{tungstenite, HyperWebsocket, WebSocketStream};
+use tungstenite::{Message, protocol::CloseFrame, protocol::frame::coding::CloseCode};
+use tokio::sync::{mpsc, mpsc::error::TrySendError, oneshot};
+use futures::{SinkExt, StreamExt, stream::SplitSink};
+use uuid::Uuid;
+
+use log::{info, warn, debug};
+
+use crate::view_session::LobbyHandler;
+use crate::potato_types::Error;
+use crate::encoding::WireEncoding;
+use crate::config::ServerConfig;
+use crate::mission::{EntityId, FrameNumber, Mission};
+use crate::recording_index::{WorldState, DeltaThresholds, KEYFRAME_INTERVAL};
+use crate::requests::ViewerCommand;
+use crate::responses::{ViewerMessage, MissionInfo, EntityInfo, MarkerInfo, RosterEntry, ChatMessage};
+use crate::chat::{self, ChatRateLimiter};
+use crate::view_session::{Lobby, ViewSession};
+use crate::follow::{Follow, FollowTarget};
+use crate::visibility::{Visibility, VisibilityFilter};
+use crate::kill_feed::{Kill, KillFilter};
+use crate::terrain::{GridSystem, TerrainRegistry};
+use crate::deep_link::DeepLink;
+
+/// Bumped whenever messages change in a way older viewers cannot handle
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const DEFAULT_TICK_RATE: f64 = 10.0;
+const MIN_TICK_RATE: f64 = 0.5;
+/// Followed units get this many updates between two frames while playing, up to
+/// `MAX_FOLLOW_TICK_RATE` a second
+const FOLLOW_UPDATES_PER_TICK: u32 = 3;
+const MAX_FOLLOW_TICK_RATE: f64 = 30.0;
+/// Full snapshots are sent this often while playing so small movements held back by the delta
+/// thresholds, or anything the client lost, get corrected
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+const DELTA_THRESHOLDS: DeltaThresholds = DeltaThresholds {
+    position: 0.5,
+    direction: 2.0
+};
+/// Frames waiting to be written to a viewer before new frames are dropped
+const OUTBOUND_QUEUE_SIZE: usize = 32;
+/// How long a viewer may keep overflowing its queue before it is disconnected
+const STALL_LIMIT: Duration = Duration::from_secs(15);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Per viewer settings that survive a reconnect
+#[derive(Clone, Debug)]
+pub struct ViewerPreferences {
+    pub name: String,
+    pub tick_interval: Duration,
+    pub filter: VisibilityFilter,
+    pub kill_filter: KillFilter,
+    pub follow: Option<FollowTarget>
+}
+
+impl ViewerPreferences {
+    fn new(name: String) -> ViewerPreferences {
+        ViewerPreferences {
+            name,
+            tick_interval: Duration::from_secs_f64(1.0 / DEFAULT_TICK_RATE),
+            filter: VisibilityFilter::default(),
+            kill_filter: KillFilter::default(),
+            follow: None
+        }
+    }
+
+    /// Followed units are updated a few times per frame, so viewers that lowered their tick
+    /// rate get fewer follow updates too
+    fn follow_interval(&self) -> Duration {
+        (self.tick_interval / FOLLOW_UPDATES_PER_TICK).max(Duration::from_secs_f64(1.0 / MAX_FOLLOW_TICK_RATE))
+    }
+}
+
+/// Trims a display name to something that fits in a roster, `None` if nothing is left
+pub fn clean_name(name: &str) -> Option<String> {
+    let name: String = name.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        return None
+    }
+    Some(name.to_string())
+}
+
+/// Where a viewer's messages wait to be written. Frames may be dropped when the viewer falls
+/// behind, since a snapshot replaces them once it catches up. Everything else is small and has
+/// to arrive, so it is never dropped; a viewer that stops reading is disconnected by the stall
+/// check before that adds up
+#[derive(Clone)]
+struct Outbound {
+    frames: mpsc::Sender<Message>,
+    control: mpsc::UnboundedSender<Message>
+}
+
+/// Asks a connection to close and suspend its session. The sender is told once it has
+type TakeoverRequest = oneshot::Sender<()>;
+
+/// What a lobby keeps of each connected viewer so it can message them directly
+pub struct ViewerHandle {
+    pub name: String,
+    encoding: WireEncoding,
+    control: mpsc::UnboundedSender<Message>,
+    session_token: Uuid,
+    takeover: mpsc::Sender<TakeoverRequest>
+}
+
+impl ViewerHandle {
+    /// Queues a message for the viewer ahead of any frames it is waiting on
+    pub fn send(&self, message: &ViewerMessage) {
+        match self.encoding.encode(message) {
+            Ok(message) => { let _ = self.control.send(message); },
+            Err(e) => warn!(target: "viewer", "Cannot encode message: {:?}", e)
+        }
+    }
+
+    pub fn holds_session(&self, session_token: &Uuid) -> bool {
+        self.session_token == *session_token
+    }
+
+    /// Closes the connection so its session can be resumed elsewhere. The receiver completes
+    /// once the session is suspended
+    pub fn take_over(&self) -> Option<oneshot::Receiver<()>> {
+        let (request, released) = oneshot::channel();
+        self.takeover.try_send(request).ok()?;
+        Some(released)
+    }
+}
+
+/// Entities and markers a viewer has been told about. Filters keep everything else from the
+/// viewer until it becomes visible
+#[derive(Default)]
+struct Described {
+    entities: HashSet<EntityId>,
+    markers: HashSet<u32>
+}
+
+impl Described {
+    fn new(info: &MissionInfo) -> Described {
+        Described {
+            entities: info.entities.iter().map(|entity| entity.id).collect(),
+            markers: info.markers.iter().map(|marker| marker.id).collect()
+        }
+    }
+
+    /// Messages describing whatever in `state` the viewer has not been told about yet
+    fn reveal(&mut self, mission: &Mission, state: &WorldState) -> Vec<ViewerMessage> {
+        let entities: Vec<EntityInfo> = state.entities.keys()
+            .filter(|id| self.entities.insert(**id))
+            .filter_map(|id| mission.entity(*id))
+            .map(EntityInfo::new)
+            .collect();
+        let markers: Vec<MarkerInfo> = mission.markers.iter()
+            .filter(|marker| state.markers.contains_key(&marker.id) && self.markers.insert(marker.id))
+            .map(MarkerInfo::new)
+            .collect();
+
+        let mut messages = Vec::new();
+        if !entities.is_empty() {
+            messages.push(ViewerMessage::EntitiesAdded { entities });
+        }
+        if !markers.is_empty() {
+            messages.push(ViewerMessage::MarkersAdded { markers });
+        }
+        messages
+    }
+}
+
+/// What a websocket upgrade asked for, once it was checked against the lobby
+pub struct JoinRequest {
+    pub lobby_uuid: Uuid,
+    pub encoding: WireEncoding,
+    pub resume_token: Option<Uuid>,
+    pub name: Option<String>,
+    /// Deep link the viewer came from, its time, camera and unit are applied when the viewer joins
+    pub link: Option<DeepLink>
+}
+
+/// A single websocket viewer of a lobby. Follows the lobby clock and sends whatever the viewer
+/// needs to get from the last frame it saw to the current one
+pub struct ViewerConnection {
+    config: Arc<ServerConfig>,
+    lobbies: Arc<RwLock<LobbyHandler>>,
+    terrains: Arc<TerrainRegistry>,
+    /// Map grid of the lobby's terrain, for grid references in events
+    grid: Option<GridSystem>,
+    lobby_uuid: Uuid,
+    encoding: WireEncoding,
+    viewer_id: Uuid,
+    session_token: Uuid,
+    resume_token: Option<Uuid>,
+    preferences: ViewerPreferences,
+    last_frame: Option<FrameNumber>,
+    last_seek_count: u64,
+    last_revision: u64,
+    last_playback: Option<(bool, f64)>,
+    last_resync: Instant,
+    // the recording's state at `last_frame`, and the state the viewer was last told about
+    exact_state: WorldState,
+    sent_state: WorldState,
+    overflowed: Arc<AtomicBool>,
+    stalled_since: Option<Instant>,
+    chat_limiter: ChatRateLimiter,
+    follow: Option<Follow>,
+    last_follow_update: Option<f64>,
+    // the viewer's own clock while it is detached from the lobby's
+    personal_session: Option<ViewSession>,
+    visibility: Option<Visibility>,
+    described: Described,
+    link: Option<DeepLink>,
+    takeover: mpsc::Sender<TakeoverRequest>,
+    takeover_requests: Option<mpsc::Receiver<TakeoverRequest>>
+}
+
+impl ViewerConnection {
+    pub fn new(config: Arc<ServerConfig>, lobbies: Arc<RwLock<LobbyHandler>>, terrains: Arc<TerrainRegistry>, join: JoinRequest) -> ViewerConnection {
+        let viewer_id = Uuid::new_v4();
+        let name = join.name.unwrap_or_else(|| format!("Viewer {}", &viewer_id.simple().to_string()[..6]));
+        let chat_limiter = ChatRateLimiter::new(&config.chat);
+        let (takeover, takeover_requests) = mpsc::channel(1);
+        ViewerConnection {
+            config,
+            lobbies,
+            terrains,
+            grid: None,
+            lobby_uuid: join.lobby_uuid,
+            encoding: join.encoding,
+            viewer_id,
+            session_token: Uuid::new_v4(),
+            resume_token: join.resume_token,
+            preferences: ViewerPreferences::new(name),
+            last_frame: None,
+            last_seek_count: 0,
+            last_revision: 0,
+            last_playback: None,
+            last_resync: Instant::now(),
+            exact_state: WorldState::default(),
+            sent_state: WorldState::default(),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            stalled_since: None,
+            chat_limiter,
+            follow: None,
+            last_follow_update: None,
+            personal_session: None,
+            visibility: None,
+            described: Described::default(),
+            link: join.link,
+            takeover,
+            takeover_requests: Some(takeover_requests)
+        }
+    }
+
+    /// Picks up the preferences of a previous connection if the viewer sent a valid session
+    /// token, adds the viewer to the lobby roster and greets it with the token to use next time
+    fn join(&mut self, outbound: &Outbound) -> Option<Vec<ViewerMessage>> {
+        let lobbies = self.lobbies.clone();
+        let mut lobbies = lobbies.write().unwrap();
+        let lobby = lobbies.lobby_mut(&self.lobby_uuid)?;
+
+        let mut resumed = false;
+        if let Some(token) = self.resume_token {
+            if let Some((viewer_id, preferences)) = lobby.resume_viewer(&token) {
+                self.viewer_id = viewer_id;
+                self.session_token = token;
+                self.preferences = preferences;
+                self.set_filter(lobby, self.preferences.filter.clone());
+                resumed = true;
+            }
+        }
+        let resumed_follow = match resumed {
+            true => self.preferences.follow.clone(),
+            false => None
+        };
+
+        lobby.broadcast(&ViewerMessage::ViewerJoined {
+            viewer: RosterEntry {
+                viewer_id: self.viewer_id.to_string(),
+                name: self.preferences.name.clone()
+            }
+        });
+        lobby.add_viewer(self.viewer_id, ViewerHandle {
+            name: self.preferences.name.clone(),
+            encoding: self.encoding,
+            control: outbound.control.clone(),
+            session_token: self.session_token,
+            takeover: self.takeover.clone()
+        });
+
+        let terrain = self.terrains.get(&lobby.recording().mission.world_name);
+        self.grid = terrain.as_ref().map(|terrain| terrain.grid);
+
+        // a filtered viewer starts out knowing what it can see right now, the rest is described
+        // as it becomes visible
+        let mut info = MissionInfo::new(&lobby.recording().mission, terrain.as_deref());
+        if let Some(visibility) = &self.visibility {
+            let recording = lobby.recording();
+            let frame = recording.mission.frame_at(lobby.view_session().current_time());
+            let visible = visibility.apply(&recording.mission, &recording.index.snapshot(frame));
+            info.entities.retain(|entity| visible.entities.contains_key(&entity.id));
+            info.markers.retain(|marker| visible.markers.contains_key(&marker.id));
+        }
+        self.described = Described::new(&info);
+
+        let mut messages = vec![
+            ViewerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                viewer_id: self.viewer_id.to_string(),
+                session_token: self.session_token.to_string(),
+                resumed
+            },
+            ViewerMessage::MissionInfo(info),
+            ViewerMessage::Roster { viewers: lobby.roster() },
+            ViewerMessage::ChatHistory { messages: lobby.chat_history() },
+            ViewerMessage::Annotations { annotations: lobby.annotations() },
+            ViewerMessage::Bookmarks { bookmarks: lobby.bookmarks().to_vec() }
+        ];
+        if let Some(live) = lobby.live() {
+            messages.push(ViewerMessage::LiveProgress {
+                duration: lobby.recording().mission.duration_seconds(),
+                delay: live.delay().as_secs_f64(),
+                live: true
+            });
+        }
+        if let Some(target) = resumed_follow {
+            messages.push(self.start_following(lobby, &target));
+        }
+        if let Some(link) = self.link.take() {
+            // the linked moment is shown on a clock of the viewer's own, a resync returns it to
+            // the lobby's without anyone else having moved
+            let mut session = lobby.view_session().clone();
+            session.seek(link.mission_time);
+            self.personal_session = Some(session);
+            messages.push(ViewerMessage::Camera { camera: link.camera });
+            if let Some(id) = link.follow_unit {
+                messages.push(self.start_following(lobby, &FollowTarget::Unit { id }));
+            }
+        }
+        Some(messages)
+    }
+
+    /// Closes the connection still holding the session the viewer wants to resume, for a viewer
+    /// that came back before its old connection timed out
+    fn take_over(&self) -> Option<oneshot::Receiver<()>> {
+        let token = self.resume_token?;
+        let lobbies = self.lobbies.read().unwrap();
+        lobbies.lobby(&self.lobby_uuid)?.attached_session(&token)?.take_over()
+    }
+
+    fn leave(&self) {
+        let mut lobbies = self.lobbies.write().unwrap();
+        if let Some(lobby) = lobbies.lobby_mut(&self.lobby_uuid) {
+            lobby.suspend_viewer(self.viewer_id, self.session_token, self.preferences.clone(), self.config.websocket.resume_window());
+            lobby.broadcast(&ViewerMessage::ViewerLeft {
+                viewer_id: self.viewer_id.to_string()
+            });
+        }
+    }
+
+    /// Messages that bring the viewer up to the lobby's current frame. A snapshot is sent when
+    /// the viewer has nothing yet, the lobby seeked, the gap is larger than a keyframe interval
+    /// or a resync is due; otherwise only entities that changed noticeably are sent. `None` if
+    /// the lobby no longer exists
+    fn next_messages(&mut self) -> Option<Vec<ViewerMessage>> {
+        let lobbies = self.lobbies.read().unwrap();
+        let lobby = lobbies.lobby(&self.lobby_uuid)?;
+        let recording = lobby.recording();
+        if let Some(session) = &mut self.personal_session {
+            // a detached clock has to keep up with a live mission as the lobby's does
+            session.set_duration(recording.mission.duration_seconds());
+        }
+        let session = self.personal_session.as_ref().unwrap_or_else(|| lobby.view_session());
+
+        let mut messages = Vec::new();
+        if self.overflowed.swap(false, Ordering::Relaxed) {
+            // frames were dropped, so nothing the viewer has can be built upon
+            self.last_frame = None;
+            self.last_playback = None;
+        }
+
+        let mission_time = session.current_time();
+        // a live recording that rewrote frames leaves nothing to build upon either
+        let seeked = session.seek_count() != self.last_seek_count || recording.index.revision() != self.last_revision;
+
+        let playback = (session.is_playing(), session.speed());
+        if seeked || self.last_playback != Some(playback) {
+            messages.push(ViewerMessage::Playback {
+                playing: playback.0,
+                speed: playback.1,
+                mission_time,
+                detached: self.personal_session.is_some()
+            });
+            self.last_playback = Some(playback);
+        }
+
+        let frame = recording.mission.frame_at(mission_time);
+        let previous_frame = self.last_frame;
+        let follows_on = match self.last_frame {
+            Some(last_frame) if !seeked && last_frame <= frame && frame - last_frame <= KEYFRAME_INTERVAL => {
+                if frame == last_frame {
+                    self.last_seek_count = session.seek_count();
+                    return Some(messages)
+                }
+                self.exact_state.apply(&recording.index.delta_between(last_frame, frame));
+                true
+            },
+            _ => {
+                self.exact_state = recording.index.snapshot(frame);
+                false
+            }
+        };
+
+        // filters are applied here, so nothing the viewer should not see ever leaves the server
+        let visible_state = match &mut self.visibility {
+            Some(visibility) => {
+                visibility.refresh(&recording.mission);
+                Cow::Owned(visibility.apply(&recording.mission, &self.exact_state))
+            },
+            None => Cow::Borrowed(&self.exact_state)
+        };
+
+        messages.extend(self.described.reveal(&recording.mission, &visible_state));
+
+        let mut followed = Vec::new();
+        if let Some(follow) = &mut self.follow {
+            let from = if follows_on { previous_frame } else { None };
+            for (unit, event) in follow.events(&recording.mission, &visible_state, from) {
+                messages.push(ViewerMessage::FollowEvent { mission_time, unit, event });
+            }
+            followed = follow.units(&visible_state);
+        }
+
+        // kills are only announced as playback passes them, not when jumping around
+        if let (true, Some(previous_frame)) = (follows_on, previous_frame) {
+            // the victim may already be hidden, by `hide_dead` for one, so having seen it on the
+            // frame before is enough. Whoever the viewer cannot see made the kill anonymously
+            let seen = |id: &EntityId| self.visibility.is_none()
+                || visible_state.entities.contains_key(id)
+                || self.sent_state.entities.contains_key(id);
+            let kills = recording.mission.events_between(previous_frame, frame).iter()
+                .filter_map(|event| Kill::from_event(&recording.mission, event, self.grid.as_ref()))
+                .filter(|kill| seen(&kill.victim))
+                .map(|mut kill| {
+                    if !kill.killer.is_none_or(|killer| seen(&killer)) {
+                        kill.redact_killer();
+                    }
+                    kill
+                })
+                .filter(|kill| self.preferences.kill_filter.matches(kill));
+            messages.extend(kills.map(ViewerMessage::Kill));
+        }
+
+        if !follows_on || self.last_resync.elapsed() >= RESYNC_INTERVAL {
+            self.sent_state = visible_state.into_owned();
+            self.last_resync = Instant::now();
+            messages.push(ViewerMessage::Snapshot {
+                mission_time,
+                state: self.sent_state.clone(),
+                followed
+            });
+        } else {
+            let delta = self.sent_state.diff(&visible_state, &DELTA_THRESHOLDS);
+            self.sent_state.apply(&delta);
+            messages.push(ViewerMessage::Delta {
+                mission_time,
+                delta,
+                followed
+            });
+        }
+
+        self.last_frame = Some(frame);
+        self.last_seek_count = session.seek_count();
+        self.last_revision = recording.index.revision();
+        Some(messages)
+    }
+
+    /// Followed units between recorded frames. Nothing moves while paused so nothing is sent
+    fn follow_update(&mut self) -> Option<ViewerMessage> {
+        let lobbies = self.lobbies.read().unwrap();
+        let lobby = lobbies.lobby(&self.lobby_uuid)?;
+        let follow = self.follow.as_ref()?;
+        // the next frame resyncs the viewer, follow updates would only add to the backlog
+        if self.overflowed.load(Ordering::Relaxed) {
+            return None
+        }
+
+        let mission_time = self.personal_session.as_ref().unwrap_or_else(|| lobby.view_session()).current_time();
+        if self.last_follow_update == Some(mission_time) {
+            return None
+        }
+        self.last_follow_update = Some(mission_time);
+
+        let mut units = follow.interpolated(&lobby.recording().mission, mission_time);
+        units.retain(|unit| self.sent_state.entities.contains_key(&unit.id));
+        if units.is_empty() {
+            return None
+        }
+        Some(ViewerMessage::FollowUpdate { mission_time, units })
+    }
+
+    /// The clock playback commands apply to
+    fn playback_session<'a>(&'a mut self, lobby: &'a mut Lobby) -> &'a mut ViewSession {
+        match &mut self.personal_session {
+            Some(session) => session,
+            None => lobby.view_session_mut()
+        }
+    }
+
+    /// Starts the viewer over from a snapshot, for when the clock it follows changes
+    fn restart_stream(&mut self) {
+        self.last_frame = None;
+        self.last_playback = None;
+    }
+
+    fn set_filter(&mut self, lobby: &Lobby, filter: VisibilityFilter) {
+        self.visibility = match filter.is_empty() {
+            true => None,
+            false => Some(Visibility::new(filter.clone(), &lobby.recording().mission))
+        };
+        self.preferences.filter = filter;
+        self.restart_stream();
+    }
+
+    /// Starts following the target and returns the reply to send to the viewer
+    fn start_following(&mut self, lobby: &Lobby, target: &FollowTarget) -> ViewerMessage {
+        match Follow::new(target, &lobby.recording().mission) {
+            Ok(mut follow) => {
+                // units the viewer does not know about are followed without naming them
+                let units: Vec<EntityId> = follow.unit_ids().iter()
+                    .filter(|id| self.described.entities.contains(id))
+                    .copied()
+                    .collect();
+                if units.is_empty() {
+                    return ViewerMessage::FollowRejected { reason: "Nothing to follow".to_string() }
+                }
+                follow.events(&lobby.recording().mission, &self.exact_state, None);
+                self.follow = Some(follow);
+                self.last_follow_update = None;
+                self.preferences.follow = Some(target.clone());
+                ViewerMessage::Following { units }
+            },
+            Err(reason) => ViewerMessage::FollowRejected { reason: reason.to_string() }
+        }
+    }
+
+    fn handle_command(&mut self, message: &Message) {
+        let command: ViewerCommand = match self.encoding.decode(message) {
+            Ok(Some(command)) => command,
+            Ok(None) => return,
+            Err(e) => {
+                debug!(target: "viewer", "Ignoring malformed command {:?}: {:?}", message, e);
+                return
+            }
+        };
+        // checked before taking the lock, a panic while holding it would poison every lobby
+        if !command.is_valid() {
+            debug!(target: "viewer", "Ignoring command with unusable values {:?}", command);
+            return
+        }
+
+        let lobbies = self.lobbies.clone();
+        let mut lobbies = lobbies.write().unwrap();
+        let lobby = match lobbies.lobby_mut(&self.lobby_uuid) {
+            Some(lobby) => lobby,
+            None => return
+        };
+        if let Some(session) = &mut self.personal_session {
+            session.set_duration(lobby.recording().mission.duration_seconds());
+        }
+
+        match command {
+            ViewerCommand::Play => self.playback_session(lobby).play(),
+            ViewerCommand::Pause => self.playback_session(lobby).pause(),
+            ViewerCommand::Seek { mission_time } => self.playback_session(lobby).seek(mission_time),
+            ViewerCommand::SetSpeed { speed } => self.playback_session(lobby).set_speed(speed),
+            ViewerCommand::SeekToBookmark { bookmark_id } => {
+                let mission_time = lobby.bookmarks().iter()
+                    .find(|bookmark| bookmark.bookmark_id == bookmark_id)
+                    .map(|bookmark| bookmark.mission_time);
+                if let Some(mission_time) = mission_time {
+                    self.playback_session(lobby).seek(mission_time);
+                }
+            },
+            ViewerCommand::Detach => {
+                self.personal_session = Some(lobby.view_session().clone());
+                self.restart_stream();
+            },
+            ViewerCommand::Resync => {
+                self.personal_session = None;
+                self.restart_stream();
+            },
+            ViewerCommand::SetTickRate { ticks_per_second } => {
+                let ticks_per_second = ticks_per_second.clamp(MIN_TICK_RATE, DEFAULT_TICK_RATE);
+                self.preferences.tick_interval = Duration::from_secs_f64(1.0 / ticks_per_second);
+            },
+            ViewerCommand::Rename { name } => self.rename(lobby, &name),
+            ViewerCommand::Follow { target } => {
+                let reply = self.start_following(lobby, &target);
+                lobby.send_to(&self.viewer_id, &reply);
+            },
+            ViewerCommand::Unfollow => {
+                self.follow = None;
+                self.preferences.follow = None;
+            },
+            ViewerCommand::SetKillFilter { filter } => {
+                self.preferences.kill_filter = filter;
+                lobby.send_to(&self.viewer_id, &ViewerMessage::KillFilter { filter: self.preferences.kill_filter.clone() });
+            },
+            ViewerCommand::SetFilter { filter } => {
+                self.set_filter(lobby, filter);
+                lobby.send_to(&self.viewer_id, &ViewerMessage::Filter { filter: self.preferences.filter.clone() });
+            },
+            ViewerCommand::Chat { text, mission_time } => self.chat(lobby, &text, mission_time),
+            ViewerCommand::AddAnnotation { annotation } => {
+                let result = lobby.add_annotation(&self.viewer_id, annotation);
+                self.reject_annotation(lobby, result);
+            },
+            ViewerCommand::EditAnnotation { annotation_id, annotation } => {
+                let result = lobby.edit_annotation(&self.viewer_id, &annotation_id, annotation);
+                self.reject_annotation(lobby, result);
+            },
+            ViewerCommand::DeleteAnnotation { annotation_id } => {
+                let result = lobby.delete_annotation(&self.viewer_id, &annotation_id);
+                self.reject_annotation(lobby, result);
+            }
+        }
+    }
+
+    fn reject_annotation(&self, lobby: &Lobby, result: Result<(), &'static str>) {
+        if let Err(reason) = result {
+            lobby.send_to(&self.viewer_id, &ViewerMessage::AnnotationRejected { reason: reason.to_string() });
+        }
+    }
+
+    fn rename(&mut self, lobby: &mut Lobby, name: &str) {
+        if let Some(name) = clean_name(name) {
+            self.preferences.name = name.clone();
+            lobby.rename_viewer(&self.viewer_id, &name);
+            lobby.broadcast(&ViewerMessage::ViewerRenamed {
+                viewer_id: self.viewer_id.to_string(),
+                name
+            });
+        }
+    }
+
+    fn chat(&mut self, lobby: &mut Lobby, text: &str, mission_time: Option<f64>) {
+        let text = match chat::clean_message(text, self.config.chat.max_length) {
+            Ok(text) => text,
+            Err(reason) => {
+                lobby.send_to(&self.viewer_id, &ViewerMessage::ChatRejected { reason: reason.to_string() });
+                return
+            }
+        };
+
+        if !self.chat_limiter.try_acquire() {
+            lobby.send_to(&self.viewer_id, &ViewerMessage::ChatRejected { reason: "Sending messages too quickly".to_string() });
+            return
+        }
+
+        let mission_time = mission_time
+            .unwrap_or_else(|| self.personal_session.as_ref().unwrap_or_else(|| lobby.view_session()).current_time())
+            .clamp(0.0, lobby.recording().mission.duration_seconds());
+        lobby.post_chat(ChatMessage {
+            viewer_id: self.viewer_id.to_string(),
+            name: self.preferences.name.clone(),
+            text,
+            mission_time
+        }, self.config.chat.history_size);
+    }
+
+    /// Queues messages without waiting on the socket. Frames that do not fit are dropped and
+    /// the viewer is resynced with a snapshot once the queue drains. Returns false once the
+    /// writer has gone away
+    fn queue(&mut self, outbound: &Outbound, messages: &[ViewerMessage]) -> Result<bool, Error> {
+        let mut overflowed = false;
+        for message in messages {
+            let encoded = self.encoding.encode(message)?;
+            if !message.is_replaceable() {
+                if outbound.control.send(encoded).is_err() {
+                    return Ok(false)
+                }
+                continue
+            }
+            match outbound.frames.try_send(encoded) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) => overflowed = true,
+                Err(TrySendError::Closed(_)) => return Ok(false)
+            }
+        }
+
+        if overflowed {
+            self.overflowed.store(true, Ordering::Relaxed);
+            self.stalled_since.get_or_insert_with(Instant::now);
+        } else {
+            self.stalled_since = None;
+        }
+        Ok(true)
+    }
+
+    async fn write_messages(mut sink: SplitSink<WebSocketStream<Upgraded>, Message>, mut frames: mpsc::Receiver<Message>, mut control: mpsc::UnboundedReceiver<Message>, mut close: oneshot::Receiver<CloseFrame<'static>>) -> Result<(), Error> {
+        loop {
+            tokio::select! {
+                biased;
+                frame = &mut close => {
+                    if let Ok(frame) = frame {
+                        sink.send(Message::Close(Some(frame))).await?;
+                    }
+                    break;
+                },
+                Some(message) = control.recv() => sink.send(message).await?,
+                message = frames.recv() => {
+                    match message {
+                        Some(message) => sink.send(message).await?,
+                        None => break
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn serve(mut self, websocket: HyperWebsocket) -> Result<(), Error> {
+        let (sink, mut stream) = websocket.await?.split();
+        let (frames, frame_receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let outbound = Outbound { frames, control };
+        let (close, close_receiver) = oneshot::channel();
+        let mut writer = tokio::spawn(ViewerConnection::write_messages(sink, frame_receiver, control_receiver, close_receiver));
+        info!(target: "viewer", "Viewer joined lobby {}", self.lobby_uuid);
+
+        if let Some(released) = self.take_over() {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, released).await;
+        }
+        match self.join(&outbound) {
+            Some(messages) => { self.queue(&outbound, &messages)?; },
+            None => return Ok(())
+        }
+
+        let mut close_frame = None;
+        let mut taken_over = None;
+        let mut takeover_requests = self.takeover_requests.take().ok_or("Connection is already served")?;
+        let mut last_heard = Instant::now();
+        let mut heartbeat = tokio::time::interval(self.config.websocket.ping_interval());
+        let mut ticker = tokio::time::interval(self.preferences.tick_interval);
+        let mut follow_ticker = tokio::time::interval(self.preferences.follow_interval());
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > self.config.websocket.ping_timeout() {
+                        info!(target: "viewer", "Viewer in lobby {} timed out", self.lobby_uuid);
+                        close_frame = Some(CloseFrame {
+                            code: CloseCode::Away,
+                            reason: "Heartbeat timed out".into()
+                        });
+                        break;
+                    }
+                    // a full queue already means the viewer is behind, the stall check handles that
+                    let _ = outbound.frames.try_send(Message::Ping(Vec::new()));
+                },
+                _ = ticker.tick() => {
+                    let messages = match self.next_messages() {
+                        Some(messages) => messages,
+                        None => break
+                    };
+                    if !self.queue(&outbound, &messages)? {
+                        break;
+                    }
+                    if self.stalled_since.is_some_and(|since| since.elapsed() > STALL_LIMIT) {
+                        warn!(target: "viewer", "Disconnecting stalled viewer from lobby {}", self.lobby_uuid);
+                        close_frame = Some(CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: "Viewer could not keep up with the replay".into()
+                        });
+                        break;
+                    }
+                },
+                Some(released) = takeover_requests.recv() => {
+                    info!(target: "viewer", "Viewer in lobby {} resumed on another connection", self.lobby_uuid);
+                    close_frame = Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "Session resumed on another connection".into()
+                    });
+                    taken_over = Some(released);
+                    break;
+                },
+                _ = follow_ticker.tick(), if self.follow.is_some() => {
+                    if let Some(message) = self.follow_update() {
+                        if !self.queue(&outbound, &[message])? {
+                            break;
+                        }
+                    }
+                },
+                message = stream.next() => {
+                    last_heard = Instant::now();
+                    match message {
+                        Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                            self.handle_command(&message);
+                            if ticker.period() != self.preferences.tick_interval {
+                                ticker = tokio::time::interval(self.preferences.tick_interval);
+                                follow_ticker = tokio::time::interval(self.preferences.follow_interval());
+                            }
+                        },
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => {},
+                        Some(Err(e)) => {
+                            // most often a dropped connection, which the viewer may resume
+                            debug!(target: "viewer", "Websocket error in lobby {}: {:?}", self.lobby_uuid, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(target: "viewer", "Viewer left lobby {}", self.lobby_uuid);
+        self.leave();
+        if let Some(released) = taken_over {
+            let _ = released.send(());
+        }
+        if let Some(frame) = close_frame {
+            let _ = close.send(frame);
+        }
+        drop(outbound);
+
+        // a stalled writer may never get to the close frame, so don't wait on it forever
+        match tokio::time::timeout(CLOSE_TIMEOUT, &mut writer).await {
+            Ok(result) => result?,
+            Err(_) => {
+                writer.abort();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Completes the upgrade only to close the connection straight away, so browser viewers can
+/// read why they were refused
+pub async fn reject(websocket: HyperWebsocket, close_code: u16, reason: String) -> Result<(), Error> {
+    let mut websocket = websocket.await?;
+    websocket.close(Some(CloseFrame {
+        code: CloseCode::from(close_code),
+        reason: reason.into()
+    })).await?;
+
+    // wait for the viewer to acknowledge the close before dropping the connection
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Some(Ok(_)) = websocket.next().await {}
+    }).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::recording_index::Recording;
+    use crate::mission_store::Bookmark;
+    use crate::annotation::AnnotationDraft;
+
+    /// A minute long mission where unit 1 (blufor, Alpha) walks east, unit 2 (opfor, Bravo)
+    /// stands still until unit 1 kills it at frame 20, and unit 3 (blufor, Charlie) rides in
+    /// vehicle 10
+    fn mission() -> Mission {
+        let state = |x: f32, life: &str, vehicle: Option<u32>, crew: Vec<u32>| json!({
+            "position": { "x": x, "y": 0.0, "z": 0.0 }, "direction": 90.0, "life": life, "vehicle": vehicle, "crew": crew
+        });
+        let unit = |id: u32, side: &str, group: &str, states: Vec<Value>| json!({
+            "id": id, "kind": "unit", "name": format!("Unit {}", id), "side": side, "group": group,
+            "start_frame": 0, "states": states
+        });
+        serde_json::from_value(json!({
+            "name": "Viewer test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 60,
+            "entities": [
+                unit(1, "blufor", "Alpha", (0..60).map(|frame| state(frame as f32 * 10.0, "alive", None, Vec::new())).collect()),
+                unit(2, "opfor", "Bravo", (0..60).map(|frame| state(300.0, if frame < 20 { "alive" } else { "dead" }, None, Vec::new())).collect()),
+                unit(3, "blufor", "Charlie", (0..60).map(|_| state(500.0, "alive", Some(10), Vec::new())).collect()),
+                {
+                    "id": 10, "kind": "vehicle", "name": "Hunter", "side": "unknown", "group": "",
+                    "start_frame": 0, "states": (0..60).map(|_| state(500.0, "alive", None, vec![3])).collect::<Vec<_>>()
+                }
+            ],
+            "events": [
+                { "frame": 20, "type": "killed", "victim": 2, "killer": 1, "weapon": "Rifle", "distance": 100.0 }
+            ]
+        })).unwrap()
+    }
+
+    fn lobbies() -> (Arc<RwLock<LobbyHandler>>, Uuid) {
+        let mut lobbies = LobbyHandler::new();
+        let lobby_uuid = lobbies.create_or_get_lobby_uuid("test", "test", Arc::new(Recording::new(mission())), Vec::new(), None);
+        (Arc::new(RwLock::new(lobbies)), lobby_uuid)
+    }
+
+    /// A connection with its queues, without a socket behind it
+    struct TestViewer {
+        connection: ViewerConnection,
+        outbound: Outbound,
+        frames: mpsc::Receiver<Message>,
+        control: mpsc::UnboundedReceiver<Message>
+    }
+
+    impl TestViewer {
+        fn new(lobbies: &Arc<RwLock<LobbyHandler>>, lobby_uuid: Uuid, resume_token: Option<Uuid>) -> TestViewer {
+            let (frames, frame_receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+            let (control, control_receiver) = mpsc::unbounded_channel();
+            let join = JoinRequest { lobby_uuid, encoding: WireEncoding::Json, resume_token, name: None, link: None };
+            TestViewer {
+                connection: ViewerConnection::new(Arc::new(ServerConfig::default()), lobbies.clone(), Arc::new(TerrainRegistry::load("terrains")), join),
+                outbound: Outbound { frames, control },
+                frames: frame_receiver,
+                control: control_receiver
+            }
+        }
+
+        fn join(&mut self) {
+            let messages = self.connection.join(&self.outbound).unwrap();
+            self.connection.queue(&self.outbound, &messages).unwrap();
+        }
+
+        fn tick(&mut self) {
+            let messages = self.connection.next_messages().unwrap();
+            self.connection.queue(&self.outbound, &messages).unwrap();
+        }
+
+        fn command(&mut self, command: Value) {
+            self.connection.handle_command(&Message::text(command.to_string()));
+        }
+
+        /// Everything queued so far, both frames and the rest
+        fn received(&mut self) -> Vec<Value> {
+            let mut messages = Vec::new();
+            while let Ok(message) = self.control.try_recv() {
+                messages.push(serde_json::from_str(message.to_text().unwrap()).unwrap());
+            }
+            while let Ok(message) = self.frames.try_recv() {
+                messages.push(serde_json::from_str(message.to_text().unwrap()).unwrap());
+            }
+            messages
+        }
+
+        fn received_of(&mut self, kind: &str) -> Vec<Value> {
+            self.received().into_iter().filter(|message| message["type"] == kind).collect()
+        }
+    }
+
+    fn seek(lobbies: &RwLock<LobbyHandler>, lobby_uuid: &Uuid, mission_time: f64) {
+        lobbies.write().unwrap().lobby_mut(lobby_uuid).unwrap().view_session_mut().seek(mission_time);
+    }
+
+    #[test]
+    fn overflowing_drops_frames_but_not_chat_or_bookmarks() {
+        let (lobbies, lobby_uuid) = lobbies();
+        let mut viewer = TestViewer::new(&lobbies, lobby_uuid, None);
+        viewer.join();
+        viewer.received();
+
+        // the viewer reads nothing while frames keep coming
+        for tick in 0..OUTBOUND_QUEUE_SIZE + 8 {
+            seek(&lobbies, &lobby_uuid, tick as f64);
+            viewer.tick();
+        }
+        assert!(viewer.connection.stalled_since.is_some());
+        {
+            let mut lobbies = lobbies.write().unwrap();
+            let chat = ChatMessage { viewer_id: String::new(), name: "Someone".to_string(), text: "Still there?".to_string(), mission_time: 0.0 };
+            lobbies.lobby_mut(&lobby_uuid).unwrap().post_chat(chat, 10);
+            let bookmark = Bookmark { bookmark_id: "b".to_string(), name: "Contact".to_string(), mission_time: 5.0 };
+            lobbies.update_bookmarks("test", &[bookmark]);
+        }
+
+        let received = viewer.received();
+        let frames = received.iter().filter(|message| message["type"] == "snapshot" || message["type"] == "playback");
+        assert_eq!(frames.count(), OUTBOUND_QUEUE_SIZE);
+        assert!(received.iter().any(|message| message["type"] == "chat"));
+        assert!(received.iter().any(|message| message["type"] == "bookmarks"));
+
+        // once it catches up it starts over from a snapshot
+        viewer.tick();
+        assert_eq!(viewer.received_of("snapshot").len(), 1);
+        assert!(viewer.connection.stalled_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconnecting_takes_over_a_session_still_connected() {
+        let (lobbies, lobby_uuid) = lobbies();
+        let mut first = TestViewer::new(&lobbies, lobby_uuid, None);
+        first.join();
+        first.command(json!({ "type": "rename", "name": "Scout" }));
+        let token = first.connection.session_token;
+
+        // the viewer comes back long before the old connection's heartbeat times out
+        let mut second = TestViewer::new(&lobbies, lobby_uuid, Some(token));
+        let released = second.connection.take_over().expect("session is still attached");
+        // what the old connection does when asked, in place of its socket loop
+        let request = first.connection.takeover_requests.as_mut().unwrap().try_recv().unwrap();
+        first.connection.leave();
+        request.send(()).unwrap();
+        released.await.unwrap();
+
+        second.join();
+        let welcome = second.received_of("welcome").remove(0);
+        assert_eq!(welcome["resumed"], true);
+        assert_eq!(welcome["viewer_id"], first.connection.viewer_id.to_string());
+        let lobbies = lobbies.read().unwrap();
+        let roster = lobbies.lobby(&lobby_uuid).unwrap().roster();
+        assert_eq!(roster.len(), 1);
+        assert_eq!(roster[0].name, "Scout");
+    }
+
+    #[test]
+    fn chat_is_relayed_and_replayed_to_late_joiners() {
+        let (lobbies, lobby_uuid) = lobbies();
+        let mut speaker = TestViewer::new(&lobbies, lobby_uuid, None);
+        speaker.join();
+        speaker.received();
+
+        for text in ["one", "two", "three", "four", "five", "six"] {
+            speaker.command(json!({ "type": "chat", "text": text }));
+        }
+        let received = speaker.received();
+        let relayed: Vec<&Value> = received.iter().filter(|message| message["type"] == "chat").collect();
+        assert_eq!(relayed.len(), 5);
+        assert_eq!(relayed[0]["text"], "one");
+        assert!(received.iter().any(|message| message["type"] == "chat_rejected"));
+
+        let mut late = TestViewer::new(&lobbies, lobby_uuid, None);
+        late.join();
+        let history = late.received_of("chat_history").remove(0);
+        assert_eq!(history["messages"].as_array().unwrap().len(), 5);
+        assert_eq!(history["messages"][4]["text"], "five");
+    }
+
+    #[test]
+    fn roster_follows_joins_renames_and_leaves() {
+        let (lobbies, lobby_uuid) = lobbies();
+        let mut first = TestViewer::new(&lobbies, lobby_uuid, None);
+        first.join();
+        first.command(json!({ "type": "rename", "name": "  Zulu\u{7} " }));
+        first.received();
+
+        let mut second = TestViewer::new(&lobbies, lobby_uuid, None);
+        second.join();
+        second.command(json!({ "type": "rename", "name": "Alpha" }));
+        // names with nothing printable left are ignored
+        second.command(json!({ "type": "rename", "name": " \u{7} " }));
+        let roster = second.received_of("roster").remove(0);
+        assert_eq!(roster["viewers"].as_array().unwrap().len(), 2);
+
+        let received = first.received();
+        assert!(received.iter().any(|message| message["type"] == "viewer_joined"));
+        let renames: Vec<&Value> = received.iter().filter(|message| message["type"] == "viewer_renamed").collect();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0]["name"], "Alpha");
+        let names: Vec<String> = lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().roster().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["Alpha", "Zulu"]);
+
+        second.connection.leave();
+        let left = first.received_of("viewer_left").remove(0);
+        assert_eq!(left["viewer_id"], second.connection.viewer_id.to_string());
+        assert_eq!(lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().roster().len(), 1);
+    }
+
+    #[test]
+    fn names_are_trimmed_to_fit() {
+        assert_eq!(clean_name(" Scout\n"), Some("Scout".to_string()));
+        assert_eq!(clean_name(&"x".repeat(50)).map(|name| name.len()), Some(MAX_NAME_LENGTH));
+        assert_eq!(clean_name("\t \r"), None);
+    }
+
+    #[test]
+    fn only_authors_change_their_annotations() {
+        let (lobbies, lobby_uuid) = lobbies();
+        let mut author = TestViewer::new(&lobbies, lobby_uuid, None);
+        author.join();
+        let mut other = TestViewer::new(&lobbies, lobby_uuid, None);
+        other.join();
+        author.received();
+        other.received();
+
+        let arrow = json!({ "shape": "arrow", "from": { "x": 0.0, "y": 0.0 }, "to": { "x": 10.0, "y": 10.0 }, "color": "red", "start_time": 0.0 });
+        author.command(json!({ "type": "add_annotation", "annotation": arrow }));
+        let added = other.received_of("annotation_added").remove(0);
+        assert_eq!(added["annotation"]["author_id"], author.connection.viewer_id.to_string());
+        let annotation_id = added["annotation"]["annotation_id"].clone();
+
+        other.command(json!({ "type": "delete_annotation", "annotation_id": annotation_id }));
+        let rejected = other.received_of("annotation_rejected").remove(0);
+        assert_eq!(rejected["reason"], "Only the author can change an annotation");
+        assert_eq!(lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().annotations().len(), 1);
+
+        author.command(json!({ "type": "add_annotation", "annotation": { "shape": "circle", "center": { "x": 0.0, "y": 0.0 }, "radius": -1.0, "color": "red", "start_time": 0.0 } }));
+        assert_eq!(author.received_of("annotation_rejected").remove(0)["reason"], "Invalid radius");
+
+        author.command(json!({ "type": "delete_annotation", "annotation_id": annotation_id }));
+        assert_eq!(other.received_of("annotation_deleted").remove(0)["annotation_id"], annotation_id);
+        assert!(lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().annotations().is_empty());
+
+        // every lobby has a cap so one viewer cannot grow it without bound
+        let mut lobbies = lobbies.write().unwrap();
+        let lobby = lobbies.lobby_mut(&lobby_uuid).unwrap();
+        let draft: AnnotationDraft = serde_json::from_value(arrow).unwrap();
+        let rejection = (0..1000).find_map(|_| lobby.add_annotation(&author.connection.viewer_id, draft.clone()).err());
+        assert_eq!(rejection, Some("Lobby has too many annotations"));
+    }
+
+    #[test]
+    fn links_move_only_the_viewer_that_opened_them() {
+        let (lobbies, lobby_uuid) = lobbies();
+        seek(&lobbies, &lobby_uuid, 5.0);
+        let mut watching = TestViewer::new(&lobbies, lobby_uuid, None);
+        watching.join();
+        watching.tick();
+        watching.received();
+
+        let mut linked = TestViewer::new(&lobbies, lobby_uuid, None);
+        linked.connection.link = Some(DeepLink {
+            mission_id: "test".to_string(),
+            mission_time: 42.0,
+            camera: crate::deep_link::Camera { x: 100.0, y: 200.0, zoom: 2.0 },
+            follow_unit: Some(1)
+        });
+        linked.join();
+        linked.tick();
+        let received = linked.received();
+        let camera = received.iter().find(|message| message["type"] == "camera").unwrap();
+        assert_eq!(camera["camera"]["zoom"], 2.0);
+        assert!(received.iter().any(|message| message["type"] == "following"));
+        let playback = received.iter().find(|message| message["type"] == "playback").unwrap();
+        assert_eq!((playback["mission_time"].as_f64(), playback["detached"].as_bool()), (Some(42.0), Some(true)));
+
+        assert_eq!(lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().view_session().current_time(), 5.0);
+        watching.tick();
+        assert!(watching.received_of("playback").is_empty());
+
+        linked.command(json!({ "type": "resync" }));
+        linked.tick();
+        let playback = linked.received_of("playback").remove(0);
+        assert_eq!((playback["mission_time"].as_f64(), playback["detached"].as_bool()), (Some(5.0), Some(false)));
+    }
+
+    #[test]
+    fn detached_viewers_keep_their_own_clock_until_they_resync() {
+        let (lobbies, lobby_uuid) = lobbies();
+        seek(&lobbies, &lobby_uuid, 5.0);
+        let mut viewer = TestViewer::new(&lobbies, lobby_uuid, None);
+        viewer.join();
+        viewer.command(json!({ "type": "detach" }));
+        viewer.command(json!({ "type": "seek", "mission_time": 30.0 }));
+        viewer.tick();
+        let playback = viewer.received_of("playback").remove(0);
+        assert_eq!((playback["mission_time"].as_f64(), playback["detached"].as_bool()), (Some(30.0), Some(true)));
+        assert_eq!(lobbies.read().unwrap().lobby(&lobby_uuid).unwrap().view_session().current_time(), 5.0);
+
+        // detached while a live mission was shorter, the clock still reaches what arrived since
+        viewer.connection.personal_session.as_mut().unwrap().set_duration(10.0);
+        viewer.command(json!({ "type": "seek", "mission_time": 50.0 }));
+        viewer.tick();
+        assert_eq!(viewer.received_of("playback").remove(0)["mission_time"], 50.0);
+
+        viewer.command(json!({ "type": "resync" }));
+        viewer.tick();
+        let playback = viewer.received_of("playback").remove(0);
+        assert_eq!((playback["mission_time"].as_f64(), playback["detached"].as_bool()), (Some(5.0), Some(false)));
+    }
+}
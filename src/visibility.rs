@@ -0,0 +1,206 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::{HashMap, HashSet};
+
+use serde::{Serialize, Deserialize};
+
+use crate::mission::{Entity, EntityId, EntityState, FrameNumber, LifeState, Mission, Side};
+use crate::recording_index::WorldState;
+
+/// What a viewer wants to see. Empty lists let everything through. It shapes what the viewer's
+/// websocket streams and nothing more: the HTTP kill feed, stats, heatmaps and exports describe
+/// the whole mission to anyone who can open it, so a filter is no way to keep a side's secrets
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct VisibilityFilter {
+    pub sides: Vec<Side>,
+    pub groups: Vec<String>,
+    pub hide_dead: bool,
+    /// Only show what this side knew about at the time, from the recorded knowledge data
+    pub seen_by: Option<Side>
+}
+
+impl VisibilityFilter {
+    pub fn is_empty(&self) -> bool {
+        self.sides.is_empty() && self.groups.is_empty() && !self.hide_dead && self.seen_by.is_none()
+    }
+}
+
+/// A filter prepared against one mission so it can be applied every frame
+pub struct Visibility {
+    filter: VisibilityFilter,
+    known: HashMap<EntityId, Vec<(FrameNumber, FrameNumber)>>,
+    hidden_markers: HashSet<u32>,
+    // sizes of the mission data this was prepared from, live missions keep adding to them
+    prepared_from: (usize, usize)
+}
+
+impl Visibility {
+    pub fn new(filter: VisibilityFilter, mission: &Mission) -> Visibility {
+        let mut known: HashMap<EntityId, Vec<(FrameNumber, FrameNumber)>> = HashMap::new();
+        if let Some(side) = filter.seen_by {
+            for knowledge in mission.knowledge.iter().filter(|knowledge| knowledge.side == side) {
+                known.entry(knowledge.entity).or_default().push((knowledge.start_frame, knowledge.end_frame));
+            }
+        }
+
+        // side channel markers belong to their side, markers without one are for everybody
+        let hidden_markers = mission.markers.iter()
+            .filter(|marker| marker.side.is_some_and(|side| {
+                filter.seen_by.is_some_and(|seen_by| seen_by != side)
+                    || (!filter.sides.is_empty() && !filter.sides.contains(&side))
+            }))
+            .map(|marker| marker.id)
+            .collect();
+
+        Visibility {
+            filter,
+            known,
+            hidden_markers,
+            prepared_from: (mission.knowledge.len(), mission.markers.len())
+        }
+    }
+
+    /// Prepares the filter again if a live mission gained knowledge or markers since
+    pub fn refresh(&mut self, mission: &Mission) {
+        if self.prepared_from != (mission.knowledge.len(), mission.markers.len()) {
+            *self = Visibility::new(self.filter.clone(), mission);
+        }
+    }
+
+    fn matches(&self, entity: &Entity) -> bool {
+        (self.filter.sides.is_empty() || self.filter.sides.contains(&entity.side))
+            && (self.filter.groups.is_empty() || self.filter.groups.contains(&entity.group))
+    }
+
+    fn is_known(&self, mission: &Mission, entity: &Entity, state: &EntityState, frame: FrameNumber) -> bool {
+        let side = match self.filter.seen_by {
+            Some(side) => side,
+            None => return true
+        };
+        let own_side = |id: &EntityId| mission.entity(*id).is_some_and(|crew| crew.side == side);
+
+        entity.side == side
+            || state.crew.iter().any(own_side)
+            || self.known.get(&entity.id).is_some_and(|ranges| ranges.iter().any(|(start, end)| (*start..*end).contains(&frame)))
+    }
+
+    pub fn is_visible(&self, mission: &Mission, id: EntityId, state: &EntityState, frame: FrameNumber) -> bool {
+        let entity = match mission.entity(id) {
+            Some(entity) => entity,
+            None => return false
+        };
+        if self.filter.hide_dead && state.life == LifeState::Dead {
+            return false
+        }
+
+        // vehicles have no group of their own, so they show up with the units inside them
+        let matches = self.matches(entity) || state.crew.iter()
+            .filter_map(|crew| mission.entity(*crew))
+            .any(|crew| self.matches(crew));
+        matches && self.is_known(mission, entity, state, frame)
+    }
+
+    /// The part of `state` the viewer is allowed to see. Crews and vehicles name each other, so
+    /// those references are cut wherever they point at something hidden
+    pub fn apply(&self, mission: &Mission, state: &WorldState) -> WorldState {
+        let visible: HashSet<EntityId> = state.entities.iter()
+            .filter(|(id, entity_state)| self.is_visible(mission, **id, entity_state, state.frame))
+            .map(|(id, _)| *id)
+            .collect();
+
+        WorldState {
+            frame: state.frame,
+            entities: visible.iter()
+                .map(|id| {
+                    let mut entity_state = state.entities[id].clone();
+                    entity_state.crew.retain(|crew| visible.contains(crew));
+                    entity_state.vehicle = entity_state.vehicle.filter(|vehicle| visible.contains(vehicle));
+                    (*id, entity_state)
+                })
+                .collect(),
+            markers: state.markers.iter()
+                .filter(|(id, _)| !self.hidden_markers.contains(id))
+                .map(|(id, marker_state)| (*id, marker_state.clone()))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::recording_index::RecordingIndex;
+
+    /// Unit 1 (blufor, Alpha) and unit 2 (opfor, Bravo) ride in vehicle 10, which is destroyed
+    /// on frame 1 with unit 1 still inside. Marker 1 belongs to opfor
+    fn mission() -> Mission {
+        let state = |vehicle: Option<u32>, crew: Vec<u32>, life: &str| json!({
+            "position": { "x": 0.0, "y": 0.0 }, "direction": 0.0, "life": life, "vehicle": vehicle, "crew": crew
+        });
+        serde_json::from_value(json!({
+            "name": "Visibility test",
+            "world_name": "VR",
+            "frame_interval": 1.0,
+            "frame_count": 2,
+            "entities": [
+                { "id": 1, "kind": "unit", "name": "Blue", "side": "blufor", "group": "Alpha", "start_frame": 0,
+                    "states": [state(Some(10), vec![], "alive"), state(Some(10), vec![], "alive")] },
+                { "id": 2, "kind": "unit", "name": "Red", "side": "opfor", "group": "Bravo", "start_frame": 0,
+                    "states": [state(Some(10), vec![], "alive"), state(None, vec![], "alive")] },
+                { "id": 10, "kind": "vehicle", "name": "Truck", "side": "unknown", "group": "", "start_frame": 0,
+                    "states": [state(None, vec![1, 2], "alive"), state(None, vec![1], "dead")] }
+            ],
+            "markers": [
+                { "id": 1, "text": "Objective", "icon": "mil_objective", "color": "ColorRed", "side": "opfor", "start_frame": 0,
+                    "positions": [{ "frame": 0, "position": { "x": 0.0, "y": 0.0 } }] }
+            ]
+        })).unwrap()
+    }
+
+    fn visible(filter: serde_json::Value, frame: FrameNumber) -> WorldState {
+        let mission = mission();
+        let state = RecordingIndex::build(&mission).snapshot(frame);
+        Visibility::new(serde_json::from_value(filter).unwrap(), &mission).apply(&mission, &state)
+    }
+
+    #[test]
+    fn hidden_entities_are_not_named_by_the_ones_shown() {
+        let opfor = visible(json!({ "sides": ["opfor"] }), 0);
+        assert_eq!(opfor.entities.keys().copied().collect::<Vec<_>>(), [2, 10]);
+        assert_eq!(opfor.entities[&10].crew, [2]);
+        assert!(opfor.markers.contains_key(&1));
+
+        let blufor = visible(json!({ "sides": ["blufor"] }), 0);
+        assert_eq!(blufor.entities[&10].crew, [1]);
+        assert_eq!(blufor.entities[&1].vehicle, Some(10));
+        assert!(blufor.markers.is_empty());
+
+        // the wreck is hidden, so its passenger no longer points at it
+        let living = visible(json!({ "hide_dead": true }), 1);
+        assert!(!living.entities.contains_key(&10));
+        assert_eq!(living.entities[&1].vehicle, None);
+    }
+
+    #[test]
+    fn empty_filters_show_everything() {
+        assert!(VisibilityFilter::default().is_empty());
+        assert_eq!(visible(json!({}), 0).entities.len(), 3);
+    }
+}
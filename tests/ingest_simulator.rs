@@ -0,0 +1,199 @@
+/*
+    potato_plant_replay - controls replaying a given mission. clients connect to a websocket and recieve data
+    Copyright (C) 2022  Bailey Danyluk
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Runs the ingest simulator against a real server and checks the recording the server saves
+//! matches the one that was replayed
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant}
+};
+use serde_json::{json, Value};
+
+const FRAME_COUNT: u64 = 40;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server running in its own directory, killed when dropped
+struct Server {
+    process: Child,
+    directory: PathBuf,
+    address: SocketAddr
+}
+
+impl Server {
+    fn start(name: &str) -> Server {
+        let directory = std::env::temp_dir().join(format!("potato-ingest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("recordings")).unwrap();
+
+        // the port is free once the probe is dropped, which is good enough for a test
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = json!({
+            "listen": address.to_string(),
+            "ingest": { "sources": { "test": { "token": "secret" } } }
+        });
+        fs::write(directory.join("config.json"), config.to_string()).unwrap();
+        fs::write(directory.join("recording.json"), recording().to_string()).unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_potato_plant_replay"))
+            .current_dir(&directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { process, directory, address };
+
+        let started = Instant::now();
+        while TcpStream::connect(server.address).is_err() {
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "server never started listening");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    fn simulate(&self, mission_id: &str, options: &[&str]) {
+        let status = Command::new(env!("CARGO_BIN_EXE_ingest_simulator"))
+            .arg(self.directory.join("recording.json"))
+            .arg(format!("ws://{}/ingest?source=test", self.address))
+            .args(["--token", "secret", "--speed", "100", "--mission-id", mission_id])
+            .args(options)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "simulator failed with {}", status);
+    }
+
+    fn saved(&self, mission_id: &str) -> Value {
+        let path = self.directory.join("recordings").join(format!("{}.json", mission_id));
+        serde_json::from_slice(&fs::read(&path).unwrap()).unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Two units walking east, one of them killing the other half way through
+fn recording() -> Value {
+    let states = |y: f64, dies_at: Option<u64>| (0..FRAME_COUNT)
+        .map(|frame| json!({
+            "position": { "x": frame as f64 * 10.0, "y": y, "z": 0.0 },
+            "direction": 90.0,
+            "life": if dies_at.is_some_and(|dies_at| frame >= dies_at) { "dead" } else { "alive" }
+        }))
+        .collect::<Vec<_>>();
+    let unit = |id: u64, side: &str, y: f64, dies_at: Option<u64>| json!({
+        "id": id,
+        "kind": "unit",
+        "name": format!("Unit {}", id),
+        "side": side,
+        "group": "Alpha",
+        "start_frame": 0,
+        "states": states(y, dies_at)
+    });
+
+    json!({
+        "name": "Simulated",
+        "world_name": "VR",
+        "frame_interval": 1.0,
+        "frame_count": FRAME_COUNT,
+        "entities": [unit(1, "blufor", 100.0, None), unit(2, "opfor", 200.0, Some(FRAME_COUNT / 2))],
+        "events": [{
+            "frame": FRAME_COUNT / 2,
+            "type": "killed",
+            "victim": 2,
+            "killer": 1,
+            "weapon": "Rifle",
+            "distance": 100.0
+        }]
+    })
+}
+
+fn x_positions(mission: &Value, id: u64) -> Vec<f64> {
+    let entity = mission["entities"].as_array().unwrap().iter()
+        .find(|entity| entity["id"] == id)
+        .unwrap_or_else(|| panic!("entity {} is missing", id));
+    entity["states"].as_array().unwrap().iter()
+        .map(|state| state["position"]["x"].as_f64().unwrap())
+        .collect()
+}
+
+fn assert_complete(saved: &Value) {
+    assert_eq!(saved["frame_count"], FRAME_COUNT);
+    for id in [1, 2] {
+        assert_eq!(x_positions(saved, id).len(), FRAME_COUNT as usize, "states of entity {}", id);
+    }
+}
+
+#[test]
+fn replays_a_recording_unchanged() {
+    let server = Server::start("clean");
+    server.simulate("clean", &[]);
+
+    let saved = server.saved("clean");
+    assert_complete(&saved);
+    assert_eq!(saved["name"], "Simulated");
+    assert_eq!(saved["events"].as_array().unwrap().len(), 1);
+    assert_eq!(saved["events"][0]["frame"], FRAME_COUNT / 2);
+    let expected: Vec<f64> = (0..FRAME_COUNT).map(|frame| frame as f64 * 10.0).collect();
+    assert_eq!(x_positions(&saved, 1), expected);
+    assert_eq!(x_positions(&saved, 2), expected);
+}
+
+#[test]
+fn fills_in_lost_and_reordered_frames() {
+    let server = Server::start("lossy");
+    server.simulate("lossy", &["--drop", "0.2", "--reorder", "0.2", "--seed", "7"]);
+
+    // frames lost at the very end are never known about, every other lost frame repeats the
+    // one before it
+    let saved = server.saved("lossy");
+    let frame_count = saved["frame_count"].as_u64().unwrap();
+    assert!((FRAME_COUNT / 2..=FRAME_COUNT).contains(&frame_count), "{} frames", frame_count);
+    let positions = x_positions(&saved, 1);
+    assert_eq!(positions.len(), frame_count as usize);
+    for (frame, x) in positions.iter().enumerate() {
+        assert!(*x == frame as f64 * 10.0 || (frame > 0 && *x == positions[frame - 1]), "{:?}", positions);
+    }
+}
+
+#[test]
+fn resumes_after_disconnecting() {
+    let server = Server::start("resume");
+    server.simulate("resume", &["--disconnect", "0.15"]);
+    assert_complete(&server.saved("resume"));
+}
+
+#[test]
+fn refuses_bad_options() {
+    for options in [&["--speed", "NaN"][..], &["--speed", "-1"], &["--speed", "inf"], &["--disconnect", "-1"], &["--disconnect", "0"]] {
+        let status = Command::new(env!("CARGO_BIN_EXE_ingest_simulator"))
+            .args(["recording.json", "ws://127.0.0.1:1/ingest"])
+            .args(options)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(2), "{:?}", options);
+    }
+}
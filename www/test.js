@@ -15,9 +15,22 @@ async function testPost() {
     return response.json()
 }
 
+// deep links redirect here with the lobby and the link to apply once connected
+const page_params = new URLSearchParams(window.location.search);
+window.addEventListener("DOMContentLoaded", () => {
+    if (page_params.has("lobby-id")) {
+        document.getElementById("lobby_id").value = page_params.get("lobby-id");
+    }
+});
+
 async function testWebSocket() {
     let lobby_id = document.getElementById("lobby_id").value;
-	const socket = new WebSocket("ws://localhost:3000?lobby-id=" + lobby_id);
+    let query = "?lobby-id=" + encodeURIComponent(lobby_id);
+    if (page_params.has("link")) {
+        query += "&link=" + encodeURIComponent(page_params.get("link"));
+    }
+	const socket = new WebSocket("ws://localhost:3000" + query);
 	socket.onopen = () => { console.log("foo"); };
 	socket.onmessage = (ev) => { console.log(ev.data); }
+	socket.onclose = (ev) => { console.log("closed", ev.code, ev.reason); }
 }